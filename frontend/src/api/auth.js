@@ -11,14 +11,36 @@ const authApi = axios.create({
   },
 });
 
-// 请求拦截器 - 添加token
-authApi.interceptors.request.use((config) => {
-  const token = localStorage.getItem('token');
-  if (token) {
-    config.headers.Authorization = `Bearer ${token}`;
-  }
-  return config;
-});
+// access token 过期前提前刷新的秒数
+const REFRESH_MARGIN_SECS = 60;
+
+let refreshPromise = null;
+let refreshTimer = null;
+
+// 为 axios 实例添加 token，并在 401 时用 refresh token 换取新 token 后重试一次
+export const withAuth = (client) => {
+  client.interceptors.request.use((config) => {
+    const token = localStorage.getItem('token');
+    if (token) {
+      config.headers.Authorization = `Bearer ${token}`;
+    }
+    return config;
+  });
+
+  client.interceptors.response.use(undefined, async (error) => {
+    const config = error.config;
+    if (error.response?.status !== 401 || !config || config._retried || !localStorage.getItem('refreshToken')) {
+      throw error;
+    }
+    config._retried = true;
+    await refreshSession();
+    return client(config);
+  });
+
+  return client;
+};
+
+withAuth(authApi);
 
 export const register = async (userData) => {
   const response = await authApi.post('/register', userData);
@@ -30,12 +52,183 @@ export const login = async (credentials) => {
   return response.data;
 };
 
+export const changePassword = async (currentPassword, newPassword) => {
+  await authApi.post('/password', { current_password: currentPassword, new_password: newPassword });
+};
+
+export const forgotPassword = async (email) => {
+  await authApi.post('/password/forgot', { email });
+};
+
+export const resetPassword = async (token, newPassword) => {
+  await authApi.post('/password/reset', { token, new_password: newPassword });
+};
+
+export const verifyEmail = async (token) => {
+  await authApi.post('/verify', { token });
+};
+
+export const resendVerification = async (email) => {
+  await authApi.post('/verify/resend', { email });
+};
+
+// 单点登录需要浏览器整页跳转，因此直接打开这个地址而不是用 axios 请求
+export const oidcLoginUrl = `${apiConfig.baseURL}/auth/oidc/login`;
+
+export const getOidcProvider = async () => {
+  const response = await authApi.get('/oidc');
+  return response.data;
+};
+
+// 用单点登录回调带回的一次性 code 换取 token
+export const exchangeOidcCode = async (code) => {
+  const response = await authApi.post('/oidc/token', { code });
+  return response.data;
+};
+
+// 两步登录的第二步，code 可以是验证器中的验证码或恢复码
+export const loginWithCode = async (challengeToken, code) => {
+  const response = await authApi.post('/2fa/verify', { challenge_token: challengeToken, code });
+  return response.data;
+};
+
+export const getTwoFactorStatus = async () => {
+  const response = await authApi.get('/2fa');
+  return response.data;
+};
+
+export const setupTwoFactor = async () => {
+  const response = await authApi.post('/2fa/setup');
+  return response.data;
+};
+
+export const enableTwoFactor = async (code) => {
+  const response = await authApi.post('/2fa/enable', { code });
+  return response.data.recovery_codes;
+};
+
+export const disableTwoFactor = async (password, code) => {
+  await authApi.post('/2fa/disable', { password, code });
+};
+
+export const regenerateRecoveryCodes = async (code) => {
+  const response = await authApi.post('/2fa/recovery-codes', { code });
+  return response.data.recovery_codes;
+};
+
 export const getCurrentUser = async () => {
   const response = await authApi.get('/user');
   return response.data;
 };
 
-export const logout = () => {
+// 图片通过 <img> 加载时无法携带 Authorization 头，同时写入 cookie 供后端鉴权
+export const setTokenCookie = (token) => {
+  document.cookie = `token=${token}; path=/; SameSite=Lax`;
+};
+
+export const saveSession = (authData) => {
+  localStorage.setItem('token', authData.token);
+  localStorage.setItem('refreshToken', authData.refresh_token);
+  localStorage.setItem('tokenExpiresAt', String(Date.now() + authData.expires_in * 1000));
+  localStorage.setItem('user', JSON.stringify(authData.user));
+  setTokenCookie(authData.token);
+  scheduleRefresh();
+};
+
+// 同一时间只发起一次刷新；refresh token 每次使用后都会更换
+export const refreshSession = () => {
+  if (!refreshPromise) {
+    const refreshToken = localStorage.getItem('refreshToken');
+    refreshPromise = axios
+      .post(`${apiConfig.baseURL}/auth/refresh`, { refresh_token: refreshToken })
+      .then((response) => {
+        saveSession(response.data);
+        return response.data.token;
+      })
+      .catch((error) => {
+        // 其他标签页可能刚刚完成刷新，直接使用它保存的新 token
+        const current = localStorage.getItem('refreshToken');
+        if (current && current !== refreshToken) {
+          setTokenCookie(localStorage.getItem('token'));
+          scheduleRefresh();
+          return localStorage.getItem('token');
+        }
+        clearSession();
+        throw error;
+      })
+      .finally(() => {
+        refreshPromise = null;
+      });
+  }
+  return refreshPromise;
+};
+
+// 在 access token 过期前刷新，保证 cookie 中用于加载图片的 token 始终有效
+export const scheduleRefresh = () => {
+  clearTimeout(refreshTimer);
+  const expiresAt = Number(localStorage.getItem('tokenExpiresAt'));
+  if (!expiresAt || !localStorage.getItem('refreshToken')) {
+    return;
+  }
+  const delay = Math.max(expiresAt - Date.now() - REFRESH_MARGIN_SECS * 1000, 0);
+  refreshTimer = setTimeout(() => {
+    refreshSession().catch((error) => console.error('刷新登录状态失败:', error));
+  }, delay);
+};
+
+const clearSession = () => {
+  clearTimeout(refreshTimer);
   localStorage.removeItem('token');
+  localStorage.removeItem('refreshToken');
+  localStorage.removeItem('tokenExpiresAt');
   localStorage.removeItem('user');
-};
\ No newline at end of file
+  document.cookie = 'token=; path=/; max-age=0';
+};
+
+// 通知服务器注销当前会话，失败时也清除本地登录状态
+export const logout = async () => {
+  const refreshToken = localStorage.getItem('refreshToken');
+  try {
+    if (refreshToken) {
+      await axios.post(`${apiConfig.baseURL}/auth/logout`, { refresh_token: refreshToken });
+    }
+  } catch (error) {
+    console.error('注销会话失败:', error);
+  } finally {
+    clearSession();
+  }
+};
+
+export const listSessions = async () => {
+  const response = await authApi.get('/sessions');
+  return response.data;
+};
+
+export const revokeSession = async (sessionId) => {
+  await authApi.delete(`/sessions/${sessionId}`);
+};
+
+// 注销除当前设备以外的所有会话，返回注销的数量
+export const revokeOtherSessions = async () => {
+  const response = await authApi.delete('/sessions');
+  return response.data.revoked;
+};
+
+export const listApiTokens = async () => {
+  const response = await authApi.get('/tokens');
+  return response.data;
+};
+
+// 返回的 token 字段只有这一次能看到
+export const createApiToken = async (name, scopes, expiresInSecs) => {
+  const response = await authApi.post('/tokens', {
+    name,
+    scopes,
+    expires_in_secs: expiresInSecs,
+  });
+  return response.data;
+};
+
+export const revokeApiToken = async (tokenId) => {
+  await authApi.delete(`/tokens/${tokenId}`);
+};
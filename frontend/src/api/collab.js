@@ -0,0 +1,78 @@
+import getApiConfig from '../config/api.js';
+
+const apiConfig = getApiConfig();
+
+const RECONNECT_DELAY_MS = 1000;
+const MAX_RECONNECT_DELAY_MS = 30000;
+
+const socketUrl = (projectId) => {
+  const url = new URL(`${apiConfig.baseURL}/projects/${projectId}/ws`, window.location.href);
+  url.protocol = url.protocol === 'https:' ? 'wss:' : 'ws:';
+  return url.toString();
+};
+
+// 浏览器的 WebSocket 无法设置 Authorization 头，令牌放在子协议里（放在 URL 里会被写进访问日志）
+const socketProtocols = () => {
+  const token = localStorage.getItem('token');
+  return token ? ['venus', `venus.token.${token}`] : ['venus'];
+};
+
+// 连接项目的实时协作房间。handlers 接收服务器消息（init、joined、left、elements、pointer、
+// selection、saved），以及连接状态变化 onStatus(connected)。断线后自动重连。
+export const connectProject = (projectId, handlers) => {
+  let socket = null;
+  let closed = false;
+  let delay = RECONNECT_DELAY_MS;
+  let reconnectTimer = null;
+
+  const open = () => {
+    socket = new WebSocket(socketUrl(projectId), socketProtocols());
+
+    socket.onopen = () => {
+      delay = RECONNECT_DELAY_MS;
+    };
+
+    socket.onmessage = (event) => {
+      let message;
+      try {
+        message = JSON.parse(event.data);
+      } catch (error) {
+        return;
+      }
+      if (message.type === 'init') {
+        handlers.onStatus?.(true);
+      }
+      handlers[message.type]?.(message);
+    };
+
+    socket.onclose = () => {
+      handlers.onStatus?.(false);
+      if (closed) {
+        return;
+      }
+      reconnectTimer = setTimeout(open, delay);
+      delay = Math.min(delay * 2, MAX_RECONNECT_DELAY_MS);
+    };
+  };
+
+  const send = (message) => {
+    if (socket && socket.readyState === WebSocket.OPEN) {
+      socket.send(JSON.stringify(message));
+      return true;
+    }
+    return false;
+  };
+
+  open();
+
+  return {
+    sendElements: (elements, files) => send({ type: 'elements', elements, files }),
+    sendPointer: (pointer, button) => send({ type: 'pointer', pointer, button }),
+    sendSelection: (selectedElementIds) => send({ type: 'selection', selectedElementIds }),
+    close: () => {
+      closed = true;
+      clearTimeout(reconnectTimer);
+      socket?.close();
+    },
+  };
+};
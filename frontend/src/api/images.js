@@ -1,5 +1,6 @@
 import axios from 'axios';
 import getApiConfig from '../config/api.js';
+import { withAuth } from './auth.js';
 
 const apiConfig = getApiConfig();
 
@@ -10,14 +11,8 @@ const apiClient = axios.create({
   },
 });
 
-// 请求拦截器 - 添加token
-apiClient.interceptors.request.use((config) => {
-  const token = localStorage.getItem('token');
-  if (token) {
-    config.headers.Authorization = `Bearer ${token}`;
-  }
-  return config;
-});
+// 添加token，过期时自动刷新
+withAuth(apiClient);
 
 export const uploadImage = async (file, projectId = null) => {
   const formData = new FormData();
@@ -45,4 +40,10 @@ export const listImages = async () => {
 
 export const deleteImage = async (imageId) => {
   await apiClient.delete(`/images/${imageId}`);
-};
\ No newline at end of file
+};
+// 用于无法携带 token 的场景（如外部嵌入），返回带有效期的签名地址
+export const getSignedImageUrl = async (imageId, ttlSecs = null) => {
+  const params = ttlSecs ? { ttl_secs: ttlSecs } : {};
+  const response = await apiClient.get(`/images/${imageId}/signed-url`, { params });
+  return `${apiConfig.imageBaseURL}${response.data.url}`;
+};
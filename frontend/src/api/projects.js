@@ -1,6 +1,7 @@
 
 import axios from 'axios';
 import getApiConfig from '../config/api.js';
+import { withAuth } from './auth.js';
 
 const apiConfig = getApiConfig();
 
@@ -11,14 +12,8 @@ const apiClient = axios.create({
   },
 });
 
-// 请求拦截器 - 添加token
-apiClient.interceptors.request.use((config) => {
-  const token = localStorage.getItem('token');
-  if (token) {
-    config.headers.Authorization = `Bearer ${token}`;
-  }
-  return config;
-});
+// 添加token，过期时自动刷新
+withAuth(apiClient);
 
 export const getProjects = async () => {
   const response = await apiClient.get('/projects');
@@ -35,8 +30,16 @@ export const createProject = async (project) => {
   return response.data;
 };
 
-export const updateProject = async (id, data) => {
-  const response = await apiClient.put(`/projects/${id}`, data);
+// version 来自上一次读取/保存，服务器版本不一致时返回 412
+export const updateProject = async (id, data, version = null) => {
+  const headers = version != null ? { 'If-Match': `"${version}"` } : {};
+  const response = await apiClient.put(`/projects/${id}`, data, { headers });
+  return response.data;
+};
+
+// 与服务器上的场景按元素合并后保存，返回合并结果和新版本
+export const mergeProject = async (id, data) => {
+  const response = await apiClient.patch(`/projects/${id}`, data);
   return response.data;
 };
 
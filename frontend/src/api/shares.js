@@ -0,0 +1,47 @@
+import axios from 'axios';
+import getApiConfig from '../config/api.js';
+import { withAuth } from './auth.js';
+
+const apiConfig = getApiConfig();
+
+const apiClient = axios.create({
+  baseURL: apiConfig.baseURL,
+  headers: {
+    'Content-Type': 'application/json',
+  },
+});
+
+// 添加token，过期时自动刷新
+withAuth(apiClient);
+
+export const createShare = async (projectId, options = {}) => {
+  const response = await apiClient.post(`/projects/${projectId}/shares`, options);
+  return response.data;
+};
+
+export const listShares = async (projectId) => {
+  const response = await apiClient.get(`/projects/${projectId}/shares`);
+  return response.data;
+};
+
+export const revokeShare = async (projectId, shareId) => {
+  await apiClient.delete(`/projects/${projectId}/shares/${shareId}`);
+};
+
+// 受密码保护的分享先用密码换取短期授权，之后的请求只携带授权
+export const unlockShare = async (token, password) => {
+  const response = await axios.post(`${apiConfig.baseURL}/shared/${token}/access`, { password });
+  return response.data.access;
+};
+
+// 无需登录
+export const getShared = async (token, access = null) => {
+  const headers = access ? { 'X-Share-Access': access } : {};
+  const response = await axios.get(`${apiConfig.baseURL}/shared/${token}`, { headers });
+  return response.data;
+};
+
+export const getSharedImageUrl = (url, access = null) => {
+  const absolute = `${apiConfig.imageBaseURL}${url}`;
+  return access ? `${absolute}?access=${encodeURIComponent(access)}` : absolute;
+};
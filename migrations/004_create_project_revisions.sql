@@ -0,0 +1,14 @@
+-- Create project revisions table for version history
+CREATE TABLE IF NOT EXISTS project_revisions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    project_id TEXT NOT NULL,
+    rev INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    content_hash TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    author_uid INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    UNIQUE (project_id, rev)
+);
+
+CREATE INDEX IF NOT EXISTS idx_project_revisions_project_id ON project_revisions(project_id);
\ No newline at end of file
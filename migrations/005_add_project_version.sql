@@ -0,0 +1,2 @@
+-- Per-project version counter used for optimistic concurrency (ETag / If-Match)
+ALTER TABLE projects ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
\ No newline at end of file
@@ -0,0 +1,14 @@
+-- Create project members table for sharing projects with per-user roles
+CREATE TABLE IF NOT EXISTS project_members (
+    project_id TEXT NOT NULL,
+    uid INTEGER NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('viewer', 'editor', 'owner')),
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    PRIMARY KEY (project_id, uid)
+);
+
+CREATE INDEX IF NOT EXISTS idx_project_members_uid ON project_members(uid);
+
+-- Existing projects are owned by their creator
+INSERT OR IGNORE INTO project_members (project_id, uid, role, created_at)
+SELECT id, uid, 'owner', created_at FROM projects;
\ No newline at end of file
@@ -0,0 +1,12 @@
+-- Create project shares table for public read-only links
+CREATE TABLE IF NOT EXISTS project_shares (
+    id TEXT PRIMARY KEY,
+    project_id TEXT NOT NULL,
+    token TEXT NOT NULL UNIQUE,
+    password_hash TEXT,
+    expires_at TEXT,
+    created_by INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+CREATE INDEX IF NOT EXISTS idx_project_shares_project_id ON project_shares(project_id);
\ No newline at end of file
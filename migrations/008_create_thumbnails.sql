@@ -0,0 +1,13 @@
+-- Resized variants of uploaded images (e.g. 256px and 1024px previews)
+CREATE TABLE IF NOT EXISTS image_variants (
+    image_id TEXT NOT NULL,
+    size TEXT NOT NULL CHECK (size IN ('thumb', 'medium')),
+    filename TEXT NOT NULL,
+    mime_type TEXT NOT NULL,
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    PRIMARY KEY (image_id, size)
+);
+
+-- SVG preview rendered from the scene on every save
+ALTER TABLE projects ADD COLUMN thumbnail TEXT;
@@ -0,0 +1,13 @@
+-- Content-addressed image blobs shared by every images row with the same bytes
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    key TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    refcount INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+-- NULL for images uploaded before deduplication, which own their file
+ALTER TABLE images ADD COLUMN blob_hash TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_images_blob_hash ON images(blob_hash);
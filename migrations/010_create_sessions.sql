@@ -0,0 +1,17 @@
+-- One row per login. Access tokens carry the session id and stop working once it is revoked;
+-- the refresh token is rotated on every use and only its SHA-256 is stored.
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    refresh_token_hash TEXT NOT NULL,
+    -- The token replaced by the last rotation, to detect a stolen refresh token being replayed
+    previous_token_hash TEXT,
+    rotated_at TEXT,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL,
+    revoked_at TEXT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_sessions_refresh_token_hash ON sessions(refresh_token_hash);
+CREATE INDEX IF NOT EXISTS idx_sessions_previous_token_hash ON sessions(previous_token_hash);
+CREATE INDEX IF NOT EXISTS idx_sessions_uid ON sessions(uid);
@@ -0,0 +1,6 @@
+-- Shown in the session list so users can tell their logins apart
+ALTER TABLE sessions ADD COLUMN last_seen_at TEXT;
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip TEXT;
+
+UPDATE sessions SET last_seen_at = COALESCE(rotated_at, created_at);
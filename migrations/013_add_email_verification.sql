@@ -0,0 +1,16 @@
+-- NULL until the user opens the link emailed at registration. Accounts created before email
+-- verification existed are treated as verified.
+ALTER TABLE users ADD COLUMN verified_at TEXT;
+UPDATE users SET verified_at = created_at;
+
+-- Outstanding verification links; only the SHA-256 of the token is stored
+CREATE TABLE IF NOT EXISTS email_verifications (
+    id TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    token_hash TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_email_verifications_token_hash ON email_verifications(token_hash);
+CREATE INDEX IF NOT EXISTS idx_email_verifications_uid ON email_verifications(uid);
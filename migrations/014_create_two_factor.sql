@@ -0,0 +1,32 @@
+-- TOTP authenticator of a user. enabled_at stays NULL until the user confirms the setup with a
+-- first code; last_used_step stops the same code from being accepted twice.
+CREATE TABLE IF NOT EXISTS user_totp (
+    uid INTEGER PRIMARY KEY,
+    secret TEXT NOT NULL,
+    enabled_at TEXT,
+    last_used_step INTEGER,
+    created_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
+
+-- Single-use codes for signing in without the authenticator; only their SHA-256 is stored
+CREATE TABLE IF NOT EXISTS recovery_codes (
+    id TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    code_hash TEXT NOT NULL,
+    used_at TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_recovery_codes_uid ON recovery_codes(uid);
+
+-- Issued after a correct password when a second factor is required; exchanged together with a
+-- code for the real tokens
+CREATE TABLE IF NOT EXISTS login_challenges (
+    id TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    token_hash TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_login_challenges_token_hash ON login_challenges(token_hash);
@@ -0,0 +1,33 @@
+-- Accounts at the OpenID Connect provider, identified by issuer and subject, and the venus user
+-- each one signs in as
+CREATE TABLE IF NOT EXISTS user_identities (
+    id TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    email TEXT,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    last_login_at TEXT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS idx_user_identities_issuer_subject ON user_identities(issuer, subject);
+CREATE INDEX IF NOT EXISTS idx_user_identities_uid ON user_identities(uid);
+
+-- Logins sent to the provider and not back yet: the PKCE verifier and nonce belonging to each
+-- state parameter, stored as its SHA-256
+CREATE TABLE IF NOT EXISTS oidc_auth_requests (
+    state_hash TEXT PRIMARY KEY,
+    code_verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL
+);
+
+-- Single-use codes the callback redirects the browser back to the app with; the app exchanges
+-- one for tokens like a password login
+CREATE TABLE IF NOT EXISTS oidc_handoffs (
+    token_hash TEXT PRIMARY KEY,
+    uid INTEGER NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    expires_at TEXT NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Set while a blob's file is being written or deleted; the blob may only be referenced once it is
+-- NULL again
+ALTER TABLE blobs ADD COLUMN pending_since TEXT;
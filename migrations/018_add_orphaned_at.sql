@@ -0,0 +1,4 @@
+-- When the garbage collector first found the image or blob unreferenced; cleared once something
+-- references it again. The grace period counts from here.
+ALTER TABLE images ADD COLUMN orphaned_at TEXT;
+ALTER TABLE blobs ADD COLUMN orphaned_at TEXT;
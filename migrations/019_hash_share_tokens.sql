@@ -0,0 +1,3 @@
+-- Share tokens are stored as their SHA-256 hash like every other token; rows still holding the
+-- plain token are hashed by the server after migrating
+ALTER TABLE project_shares RENAME COLUMN token TO token_hash;
@@ -0,0 +1,10 @@
+-- 013 marked the accounts that already existed as verified without anyone proving they own their
+-- address. Remember them, so single sign-on doesn't link to them by email until they do.
+ALTER TABLE users ADD COLUMN legacy_verified INTEGER NOT NULL DEFAULT 0;
+UPDATE users SET legacy_verified = 1
+WHERE verified_at = created_at AND id NOT IN (SELECT uid FROM user_identities);
+
+-- Addresses are stored lowercase and compared without case. This fails when two accounts use the
+-- same address in different case; change one of them before upgrading.
+UPDATE users SET email = lower(email);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_email_nocase ON users(email COLLATE NOCASE);
@@ -0,0 +1,4 @@
+-- Wrong second factors in a row, counted across login challenges and the 2FA settings. Too many
+-- lock the second factor until locked_until, so new challenges can't be used to keep guessing.
+ALTER TABLE user_totp ADD COLUMN failed_attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE user_totp ADD COLUMN locked_until TEXT;
@@ -0,0 +1,80 @@
+use axum::http::StatusCode;
+use sqlx::SqlitePool;
+
+use crate::models::{Image, Role};
+
+pub async fn project_role(pool: &SqlitePool, project_id: &str, uid: i64) -> Result<Option<Role>, sqlx::Error> {
+    sqlx::query_scalar("SELECT role FROM project_members WHERE project_id = ? AND uid = ?")
+        .bind(project_id)
+        .bind(uid)
+        .fetch_optional(pool)
+        .await
+}
+
+// Non-members get 404 so project ids cannot be probed; members without enough rights get 403
+pub async fn require_role(pool: &SqlitePool, project_id: &str, uid: i64, required: Role) -> Result<Role, StatusCode> {
+    let role = project_role(pool, project_id, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    if role < required {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(role)
+}
+
+// Images are visible to their uploader and to members of the project they belong to
+pub async fn can_view_image(pool: &SqlitePool, image: &Image, uid: i64) -> Result<bool, sqlx::Error> {
+    if image.uploaded_by == uid {
+        return Ok(true);
+    }
+
+    match &image.project_id {
+        Some(project_id) => Ok(project_role(pool, project_id, uid).await?.is_some()),
+        None => Ok(false),
+    }
+}
+
+// Deleting additionally requires being the uploader or an owner of the project
+pub async fn can_delete_image(pool: &SqlitePool, image: &Image, uid: i64) -> Result<bool, sqlx::Error> {
+    if image.uploaded_by == uid {
+        return Ok(true);
+    }
+
+    match &image.project_id {
+        Some(project_id) => Ok(project_role(pool, project_id, uid).await? == Some(Role::Owner)),
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{test_project, test_state, test_user};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn roles_are_ordered() {
+        let state = test_state().await;
+        let (owner, _) = test_user(&state, "alice").await;
+        let (viewer, _) = test_user(&state, "bob").await;
+        let (stranger, _) = test_user(&state, "carol").await;
+        let project = test_project(&state, owner, &json!({})).await;
+        sqlx::query("INSERT INTO project_members (project_id, uid, role) VALUES (?, ?, 'viewer')")
+            .bind(&project)
+            .bind(viewer)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let pool = &state.pool;
+        assert_eq!(require_role(pool, &project, viewer, Role::Viewer).await, Ok(Role::Viewer));
+        assert_eq!(require_role(pool, &project, viewer, Role::Editor).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(require_role(pool, &project, owner, Role::Owner).await, Ok(Role::Owner));
+        // 非成员看不到项目是否存在
+        assert_eq!(require_role(pool, &project, stranger, Role::Viewer).await, Err(StatusCode::NOT_FOUND));
+        assert_eq!(require_role(pool, "missing", owner, Role::Viewer).await, Err(StatusCode::NOT_FOUND));
+    }
+}
@@ -0,0 +1,88 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use chrono::{TimeDelta, Utc};
+
+use crate::{
+    api_tokens,
+    auth::extract_uid_from_headers,
+    models::{ApiTokenResponse, CreateApiTokenRequest, CreatedApiToken, TokenScope},
+    state::AppState,
+};
+
+const MAX_NAME_LEN: usize = 100;
+const MAX_EXPIRES_IN_SECS: i64 = 5 * 365 * 24 * 60 * 60;
+
+pub async fn list_api_tokens(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ApiTokenResponse>>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    let rows = api_tokens::list_tokens(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(rows.iter().map(|row| row.to_response()).collect()))
+}
+
+// 令牌明文只在创建时返回一次
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateApiTokenRequest>,
+) -> Result<Json<CreatedApiToken>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    let name = req.name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let scopes: Vec<TokenScope> = TokenScope::ALL
+        .into_iter()
+        .filter(|scope| req.scopes.contains(scope))
+        .collect();
+    if scopes.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // 超出范围的过期时间直接拒绝，避免时间计算溢出
+    let expires_at = match req.expires_in_secs {
+        Some(secs) if !(1..=MAX_EXPIRES_IN_SECS).contains(&secs) => return Err(StatusCode::BAD_REQUEST),
+        Some(secs) => Some(
+            TimeDelta::try_seconds(secs)
+                .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                .ok_or(StatusCode::BAD_REQUEST)?,
+        ),
+        None => None,
+    };
+
+    let (row, token) = api_tokens::create_token(&state.pool, uid, name, &scopes, expires_at)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::info!("User {} created API token {} ({})", uid, row.id, row.name);
+
+    Ok(Json(CreatedApiToken {
+        token,
+        api_token: row.to_response(),
+    }))
+}
+
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    // 只能撤销自己的令牌
+    let revoked = api_tokens::revoke_token(&state.pool, &id, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,254 @@
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{FromRow, SqlitePool};
+use uuid::Uuid;
+
+use crate::{
+    auth::{authenticate, extract_token, random_token, token_hash, Claims, NO_SESSION_ID},
+    models::{ApiTokenRow, TokenScope},
+    state::AppState,
+};
+
+// Makes API tokens recognizable, both here and to secret scanners
+const TOKEN_PREFIX: &str = "venus_pat_";
+const TOKEN_LEN: usize = 40;
+// Don't write last_used_at on every request of a busy CI job
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+#[derive(FromRow)]
+struct TokenOwner {
+    id: String,
+    uid: i64,
+    username: String,
+    scopes: String,
+    expires_at: Option<DateTime<Utc>>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(TOKEN_PREFIX)
+}
+
+// Returns the row and the token itself, which is not stored
+pub async fn create_token(
+    pool: &SqlitePool,
+    uid: i64,
+    name: &str,
+    scopes: &[TokenScope],
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<(ApiTokenRow, String)> {
+    let token = format!("{}{}", TOKEN_PREFIX, random_token(TOKEN_LEN));
+    let scopes = scopes.iter().map(|scope| scope.as_str()).collect::<Vec<_>>().join(" ");
+
+    let row = sqlx::query_as::<_, ApiTokenRow>(
+        r#"
+        INSERT INTO api_tokens (id, uid, name, token_hash, scopes, created_at, expires_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        RETURNING id, name, scopes, created_at, expires_at, last_used_at
+        "#
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(uid)
+    .bind(name)
+    .bind(token_hash(&token))
+    .bind(scopes)
+    .bind(Utc::now())
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await?;
+
+    Ok((row, token))
+}
+
+pub async fn list_tokens(pool: &SqlitePool, uid: i64) -> Result<Vec<ApiTokenRow>, sqlx::Error> {
+    sqlx::query_as::<_, ApiTokenRow>(
+        r#"
+        SELECT id, name, scopes, created_at, expires_at, last_used_at FROM api_tokens
+        WHERE uid = ? ORDER BY created_at DESC
+        "#
+    )
+    .bind(uid)
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn revoke_token(pool: &SqlitePool, id: &str, uid: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("DELETE FROM api_tokens WHERE id = ? AND uid = ?")
+        .bind(id)
+        .bind(uid)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// The user an API token acts for, or None when it is unknown or expired
+pub async fn authenticate_token(pool: &SqlitePool, token: &str) -> Result<Option<Claims>, sqlx::Error> {
+    let now = Utc::now();
+    let owner = sqlx::query_as::<_, TokenOwner>(
+        r#"
+        SELECT t.id, t.uid, u.username, t.scopes, t.expires_at, t.last_used_at
+        FROM api_tokens t JOIN users u ON u.id = t.uid
+        WHERE t.token_hash = ? AND (t.expires_at IS NULL OR t.expires_at > ?)
+        "#
+    )
+    .bind(token_hash(token))
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(owner) = owner else {
+        return Ok(None);
+    };
+    if owner
+        .last_used_at
+        .is_none_or(|used| now - used >= Duration::seconds(LAST_USED_RESOLUTION_SECS))
+    {
+        sqlx::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(&owner.id)
+            .execute(pool)
+            .await?;
+    }
+
+    Ok(Some(Claims {
+        sub: owner.uid.to_string(),
+        uid: owner.uid,
+        username: owner.username,
+        sid: NO_SESSION_ID.to_string(),
+        exp: owner.expires_at.map_or(usize::MAX, |expires| expires.timestamp() as usize),
+        scopes: Some(owner.scopes.split_whitespace().filter_map(TokenScope::parse).collect()),
+    }))
+}
+
+enum Access {
+    // Any token, e.g. to look up who it belongs to
+    Any,
+    Scope(TokenScope),
+    // Only with a login session: account settings, and managing API tokens themselves
+    Denied,
+}
+
+fn required_access(method: &Method, path: &str) -> Access {
+    let read = method == Method::GET || method == Method::HEAD;
+    if let Some(path) = path.strip_prefix("/api/auth/") {
+        return match path {
+            "user" | "user/usage" if read => Access::Any,
+            _ => Access::Denied,
+        };
+    }
+    if path.starts_with("/api/images") {
+        return if read {
+            Access::Scope(TokenScope::ProjectsRead)
+        } else {
+            Access::Scope(TokenScope::ImagesWrite)
+        };
+    }
+    // 协作连接虽然是 GET 请求，但可以修改项目
+    if read && !path.ends_with("/ws") {
+        Access::Scope(TokenScope::ProjectsRead)
+    } else {
+        Access::Scope(TokenScope::ProjectsWrite)
+    }
+}
+
+// Writing projects needs reading them, e.g. to update a diagram from CI
+fn grants(granted: TokenScope, required: TokenScope) -> bool {
+    granted == required || (granted == TokenScope::ProjectsWrite && required == TokenScope::ProjectsRead)
+}
+
+// Runs before every API handler and rejects requests made with an API token that lacks the scope
+// the endpoint needs. Requests that fail to authenticate are left to the handler.
+pub async fn restrict_api_tokens(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let uses_api_token = extract_token(request.headers()).is_some_and(|token| is_api_token(&token));
+    if !uses_api_token || !request.uri().path().starts_with("/api/") {
+        return next.run(request).await;
+    }
+
+    if let Ok(Claims { scopes: Some(scopes), .. }) = authenticate(&state, request.headers()).await {
+        let allowed = match required_access(request.method(), request.uri().path()) {
+            Access::Any => true,
+            Access::Scope(scope) => scopes.iter().any(|&granted| grants(granted, scope)),
+            Access::Denied => false,
+        };
+        if !allowed {
+            return StatusCode::FORBIDDEN.into_response();
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(method: Method, path: &str) -> Option<TokenScope> {
+        match required_access(&method, path) {
+            Access::Scope(scope) => Some(scope),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn account_endpoints_need_a_login_session() {
+        assert!(matches!(required_access(&Method::GET, "/api/auth/user"), Access::Any));
+        assert!(matches!(required_access(&Method::HEAD, "/api/auth/user/usage"), Access::Any));
+        for (method, path) in [
+            (Method::GET, "/api/auth/tokens"),
+            (Method::POST, "/api/auth/tokens"),
+            (Method::DELETE, "/api/auth/tokens/abc"),
+            (Method::GET, "/api/auth/sessions"),
+            (Method::POST, "/api/auth/user"),
+            (Method::POST, "/api/auth/change-password"),
+        ] {
+            assert!(matches!(required_access(&method, path), Access::Denied), "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn endpoints_map_to_scopes() {
+        let cases = [
+            (Method::GET, "/api/projects", TokenScope::ProjectsRead),
+            (Method::HEAD, "/api/projects/p", TokenScope::ProjectsRead),
+            (Method::GET, "/api/projects/p/revisions", TokenScope::ProjectsRead),
+            (Method::POST, "/api/projects", TokenScope::ProjectsWrite),
+            (Method::PUT, "/api/projects/p", TokenScope::ProjectsWrite),
+            (Method::PATCH, "/api/projects/p", TokenScope::ProjectsWrite),
+            (Method::DELETE, "/api/projects/p", TokenScope::ProjectsWrite),
+            (Method::GET, "/api/projects/p/ws", TokenScope::ProjectsWrite),
+            (Method::GET, "/api/images", TokenScope::ProjectsRead),
+            (Method::GET, "/api/images/i", TokenScope::ProjectsRead),
+            (Method::POST, "/api/images", TokenScope::ImagesWrite),
+            (Method::DELETE, "/api/images/i", TokenScope::ImagesWrite),
+        ];
+        for (method, path, expected) in cases {
+            assert_eq!(scope(method.clone(), path), Some(expected), "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn write_scope_implies_read_only_for_projects() {
+        use TokenScope::*;
+
+        let cases = [
+            (ProjectsRead, ProjectsRead, true),
+            (ProjectsWrite, ProjectsWrite, true),
+            (ImagesWrite, ImagesWrite, true),
+            (ProjectsWrite, ProjectsRead, true),
+            (ProjectsRead, ProjectsWrite, false),
+            (ImagesWrite, ProjectsRead, false),
+            (ImagesWrite, ProjectsWrite, false),
+            (ProjectsWrite, ImagesWrite, false),
+            (ProjectsRead, ImagesWrite, false),
+        ];
+        for (granted, required, expected) in cases {
+            assert_eq!(grants(granted, required), expected, "{:?} grants {:?}", granted, required);
+        }
+    }
+}
@@ -1,26 +1,43 @@
 use axum::{
-    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    http::{header::{AUTHORIZATION, COOKIE}, HeaderMap, StatusCode},
 };
 use bcrypt::{hash, verify, DEFAULT_COST};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation, Algorithm};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{api_tokens, config::AuthConfig, models::TokenScope, sessions::touch_session, state::AppState};
+
+// Requests authenticated by a reverse proxy or an API token have no venus login session
+pub const NO_SESSION_ID: &str = "";
+
+// Cookie the frontend keeps the access token in for <img> requests; only image GETs accept it
+pub const TOKEN_COOKIE: &str = "token";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub uid: i64,
     pub username: String,
+    // Login session the token belongs to; revoking it invalidates the token
+    pub sid: String,
     pub exp: usize,
+    // Set only for API tokens, which may do nothing beyond these
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<TokenScope>>,
 }
 
 impl Claims {
-    pub fn new(uid: i64, username: String) -> Self {
+    pub fn new(uid: i64, username: String, session_id: &str, ttl_secs: i64) -> Self {
         Self {
             sub: uid.to_string(),
             uid,
             username,
-            exp: (Utc::now() + Duration::days(7)).timestamp() as usize, // Token expires in 7 days
+            sid: session_id.to_string(),
+            exp: (Utc::now() + Duration::seconds(ttl_secs)).timestamp() as usize,
+            scopes: None,
         }
     }
 }
@@ -30,65 +47,184 @@ pub fn hash_password(password: &str) -> Result<String, bcrypt::BcryptError> {
 }
 
 pub fn verify_password(password: &str, hash: &str) -> Result<bool, bcrypt::BcryptError> {
+    // 通过单点登录创建的账号没有密码
+    if hash.is_empty() {
+        return Ok(false);
+    }
     verify(password, hash)
 }
 
-pub fn generate_jwt_token(uid: i64, username: String) -> Result<String, jsonwebtoken::errors::Error> {
-    let claims = Claims::new(uid, username);
-    let secret = "your-secret-key-change-this-in-production";
-    encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref()))
+// Random URL-safe token for links and secrets
+pub fn random_token(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+// Emailed and refresh tokens are stored only as this hash
+pub fn token_hash(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
-pub fn extract_uid_from_headers(headers: &HeaderMap) -> Result<i64, StatusCode> {
+pub fn generate_jwt_token(
+    state: &AppState,
+    uid: i64,
+    username: String,
+    session_id: &str,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let claims = Claims::new(uid, username, session_id, state.config.auth.token_ttl_secs);
+    state.jwt_keys.sign(&claims)
+}
+
+pub async fn extract_uid_from_headers(state: &AppState, headers: &HeaderMap) -> Result<i64, StatusCode> {
+    authenticate(state, headers).await.map(|claims| claims.uid)
+}
+
+pub async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<Claims, StatusCode> {
+    // 反向代理或认证服务已经确认过的用户
+    if let Some(claims) = state.proxy.authenticate(&state.pool, headers).await? {
+        return Ok(claims);
+    }
+
     // Extract JWT token from Authorization header
     let token = extract_token(headers)
         .ok_or(StatusCode::UNAUTHORIZED)?;
 
+    // 脚本和 CI 使用的个人访问令牌
+    if api_tokens::is_api_token(&token) {
+        return api_tokens::authenticate_token(&state.pool, &token)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED);
+    }
+
+    claims_from_token(state, &token).await
+}
+
+pub async fn claims_from_token(state: &AppState, token: &str) -> Result<Claims, StatusCode> {
     // Validate JWT token
-    validate_jwt_token(&token)
-        .map_err(|_| StatusCode::UNAUTHORIZED)
+    let claims: Claims = state.jwt_keys.verify(token)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    // 退出登录或被注销的会话，其 access token 即使未过期也立即失效
+    let active = touch_session(&state.pool, &claims.sid, claims.uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !active {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(claims)
 }
 
-fn extract_token(headers: &HeaderMap) -> Option<String> {
+pub fn extract_token(headers: &HeaderMap) -> Option<String> {
     headers
         .get(AUTHORIZATION)
         .and_then(|header| header.to_str().ok())
-        .and_then(|header| {
-            if header.starts_with("Bearer ") {
-                Some(header[7..].to_string())
-            } else {
-                None
-            }
-        })
-        .or_else(|| {
-            // Try to get token from cookie
-            headers
-                .get("cookie")
-                .and_then(|header| header.to_str().ok())
-                .and_then(|cookies| {
-                    cookies
-                        .split(';')
-                        .find_map(|cookie| {
-                            let parts: Vec<&str> = cookie.trim().splitn(2, '=').collect();
-                            if parts.len() == 2 && parts[0] == "token" {
-                                Some(parts[1].to_string())
-                            } else {
-                                None
-                            }
-                        })
-                })
+        .and_then(|header| header.strip_prefix("Bearer ").map(str::to_string))
+}
+
+fn cookie_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == TOKEN_COOKIE).then(|| value.to_string())
         })
 }
 
-fn validate_jwt_token(token: &str) -> Result<i64, Box<dyn std::error::Error>> {
-    let secret = "your-secret-key-change-this-in-production";
-    
-    let validation = Validation::new(Algorithm::HS256);
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(secret.as_ref()),
-        &validation,
-    )?;
+// Like authenticate, but also accepts the access token from the `token` cookie. Only for GETs of
+// images and thumbnails, which browsers load with <img> and can't add a header to; any endpoint
+// taking the cookie can be triggered by other pages the browser has open.
+pub async fn authenticate_with_cookie(state: &AppState, headers: &HeaderMap) -> Result<Claims, StatusCode> {
+    match authenticate(state, headers).await {
+        Err(StatusCode::UNAUTHORIZED) if extract_token(headers).is_none() => {
+            let token = cookie_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+            claims_from_token(state, &token).await
+        }
+        result => result,
+    }
+}
+
+// HMAC signature for time-limited URLs, e.g. images embedded where no bearer token can be sent
+pub fn sign_url(config: &AuthConfig, path: &str, expires: i64) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.url_signing_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}:{}", path, expires).as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+pub fn verify_url_signature(config: &AuthConfig, path: &str, expires: i64, signature: &str) -> bool {
+    if expires < Utc::now().timestamp() {
+        return false;
+    }
+    let Ok(signature) = hex::decode(signature) else {
+        return false;
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(config.url_signing_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(format!("{}:{}", path, expires).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        models::User,
+        sessions::{create_session, ClientInfo},
+        state::test_state,
+    };
+    use axum::http::HeaderValue;
 
-    Ok(token_data.claims.uid)
-}
\ No newline at end of file
+    async fn access_token(state: &AppState) -> String {
+        let now = Utc::now();
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (username, email, password_hash, created_at, updated_at) VALUES ('alice', 'alice@example.com', 'x', ?, ?)
+            RETURNING id, username, email, password_hash, verified_at, created_at, updated_at
+            "#
+        )
+        .bind(now)
+        .bind(now)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+        let client = ClientInfo { user_agent: None, ip: None };
+        create_session(state, &user, &client).await.unwrap().access_token
+    }
+
+    fn headers(pairs: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn cookie_only_counts_where_allowed() {
+        let state = test_state().await;
+        let token = access_token(&state).await;
+        let cookie = headers(&[("cookie", format!("theme=dark; {}={}", TOKEN_COOKIE, token))]);
+
+        assert_eq!(authenticate(&state, &cookie).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(authenticate_with_cookie(&state, &cookie).await.unwrap().username, "alice");
+
+        // 同时带有 Authorization 头时只看请求头
+        let both = headers(&[
+            ("authorization", "Bearer forged".to_string()),
+            ("cookie", format!("{}={}", TOKEN_COOKIE, token)),
+        ]);
+        assert_eq!(authenticate_with_cookie(&state, &both).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let other_cookie = headers(&[("cookie", format!("x{}={}", TOKEN_COOKIE, token))]);
+        assert_eq!(authenticate_with_cookie(&state, &other_cookie).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,27 +1,46 @@
 use axum::{
-    extract::State,
-    http::{HeaderMap, StatusCode},
-    response::Json,
+    extract::{ConnectInfo, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
 };
 use chrono::Utc;
-use sqlx::SqlitePool;
+use jsonwebtoken::jwk::JwkSet;
+use std::net::SocketAddr;
 
 use crate::{
-    auth::{generate_jwt_token, hash_password, verify_password},
-    models::{AuthResponse, LoginRequest, RegisterRequest, User, UserResponse},
+    auth::{authenticate, hash_password, verify_password},
+    config::UnverifiedAccess,
+    models::{
+        AuthResponse, ChangePasswordRequest, ForgotPasswordRequest, LoginChallenge, LoginRequest, LogoutRequest,
+        RefreshRequest, RegisterRequest, ResendVerificationRequest, ResetPasswordRequest, StorageUsage,
+        TwoFactorLoginRequest, User, UserResponse, VerifyEmailRequest,
+    },
+    password_reset::{self, request_reset},
+    quota::storage_used,
+    sessions::{
+        create_session, refresh_session, revoke_other_sessions, revoke_refresh_token, revoke_session, ClientInfo,
+        IssuedTokens,
+    },
+    state::AppState,
+    two_factor::{self, ChallengeOutcome},
+    verification::{self, normalize_email, send_verification},
 };
 
 pub async fn register(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(req): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
+) -> Result<Response, StatusCode> {
+    let email = normalize_email(&req.email).ok_or(StatusCode::BAD_REQUEST)?;
+
     // 检查用户名是否已存在
     let existing_user = sqlx::query_as::<_, User>(
-        "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE username = ? OR email = ?"
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE username = ? OR email = ? COLLATE NOCASE"
     )
     .bind(&req.username)
-    .bind(&req.email)
-    .fetch_optional(&pool)
+    .bind(&email)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -40,40 +59,49 @@ pub async fn register(
         r#"
         INSERT INTO users (username, email, password_hash, created_at, updated_at)
         VALUES (?, ?, ?, ?, ?)
-        RETURNING id, username, email, password_hash, created_at, updated_at
+        RETURNING id, username, email, password_hash, verified_at, created_at, updated_at
         "#
     )
     .bind(&req.username)
-    .bind(&req.email)
+    .bind(&email)
     .bind(&password_hash)
     .bind(now)
     .bind(now)
-    .fetch_one(&pool)
+    .fetch_one(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // 生成JWT token
-    let token = generate_jwt_token(user.id, user.username.clone())
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // 账号已经创建，验证邮件发送失败时用户可以稍后重新发送
+    if let Err(e) = send_verification(&state, &user).await {
+        tracing::error!("Failed to start email verification for user {}: {}", user.id, e);
+    }
 
-    let response = AuthResponse {
-        user: UserResponse::from(user),
-        token,
-    };
+    // 未验证邮箱不能登录时，注册后不签发 token
+    if state.config.auth.unverified_access == UnverifiedAccess::None {
+        return Ok((StatusCode::ACCEPTED, Json(UserResponse::from(user))).into_response());
+    }
+
+    // 每次登录创建一个会话，返回 access token 和 refresh token
+    let tokens = create_session(&state, &user, &ClientInfo::new(&headers, peer)).await.map_err(|e| {
+        tracing::error!("Failed to create session for user {}: {}", user.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
-    Ok(Json(response))
+    Ok(Json(auth_response(user, tokens)).into_response())
 }
 
 pub async fn login(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(req): Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
+) -> Result<Response, StatusCode> {
     // 查找用户
     let user = sqlx::query_as::<_, User>(
-        "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE username = ?"
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE username = ?"
     )
     .bind(&req.username)
-    .fetch_optional(&pool)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -87,33 +115,267 @@ pub async fn login(
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    // 生成JWT token
-    let token = generate_jwt_token(user.id, user.username.clone())
+    finish_login(&state, user, &ClientInfo::new(&headers, peer)).await
+}
+
+// Everything after the user proved who they are, whether by password or single sign-on: ask for
+// the second factor when needed, otherwise start the session
+pub async fn finish_login(state: &AppState, user: User, client: &ClientInfo) -> Result<Response, StatusCode> {
+    if user.verified_at.is_none() && state.config.auth.unverified_access == UnverifiedAccess::None {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // 启用了两步验证时先返回 challenge，验证码通过后才签发 token
+    let two_factor_enabled = two_factor::is_enabled(&state.pool, user.id)
+        .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if two_factor_enabled {
+        let challenge_token = two_factor::create_challenge(&state.pool, user.id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(Json(LoginChallenge {
+            two_factor_required: true,
+            challenge_token,
+            expires_in: two_factor::CHALLENGE_TTL_SECS,
+        })
+        .into_response());
+    }
 
-    let response = AuthResponse {
-        user: UserResponse::from(user),
-        token,
+    // 每次登录创建一个会话，返回 access token 和 refresh token
+    let tokens = create_session(state, &user, client).await.map_err(|e| {
+        tracing::error!("Failed to create session for user {}: {}", user.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(auth_response(user, tokens)).into_response())
+}
+
+// 两步登录的第二步：用 challenge token 和验证码（或恢复码）换取 token
+pub async fn login_two_factor(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<TwoFactorLoginRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    let outcome = two_factor::complete_challenge(&state.pool, &req.challenge_token, &req.code)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to check two-factor login: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let user = match outcome {
+        ChallengeOutcome::SignedIn(user) => user,
+        ChallengeOutcome::Refused => return Err(StatusCode::UNAUTHORIZED),
+        ChallengeOutcome::LockedOut => return Err(StatusCode::TOO_MANY_REQUESTS),
     };
 
-    Ok(Json(response))
+    let tokens = create_session(&state, &user, &ClientInfo::new(&headers, peer)).await.map_err(|e| {
+        tracing::error!("Failed to create session for user {}: {}", user.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Json(auth_response(user, tokens)))
+}
+
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    let (user, tokens) = refresh_session(&state, &req.refresh_token)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to refresh session: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    Ok(Json(auth_response(user, tokens)))
+}
+
+// 注销当前会话：优先使用 access token，过期时也可以只提交 refresh token
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Option<Json<LogoutRequest>>,
+) -> Result<StatusCode, StatusCode> {
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+
+    if let Ok(claims) = authenticate(&state, &headers).await {
+        revoke_session(&state.pool, &claims.sid, claims.uid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    let refresh_token = req.refresh_token.ok_or(StatusCode::UNAUTHORIZED)?;
+    let revoked = revoke_refresh_token(&state.pool, &refresh_token)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !revoked {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 修改密码需要提供当前密码；成功后注销其他设备上的会话
+pub async fn change_password(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = authenticate(&state, &headers).await?;
+    if req.new_password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+    )
+    .bind(claims.uid)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // 当前密码错误返回 403，401 会让前端误以为 token 过期
+    let password_valid = verify_password(&req.current_password, &user.password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !password_valid {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let password_hash = hash_password(&req.new_password)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sqlx::query("UPDATE users SET password_hash = ?, updated_at = ? WHERE id = ?")
+        .bind(&password_hash)
+        .bind(Utc::now())
+        .bind(user.id)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    revoke_other_sessions(&state.pool, user.id, &claims.sid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 无论邮箱是否存在都返回 202，避免泄露哪些邮箱注册过
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(req): Json<ForgotPasswordRequest>,
+) -> Result<StatusCode, StatusCode> {
+    request_reset(&state, &req.email).await.map_err(|e| {
+        tracing::error!("Failed to start password reset: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(req): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if req.new_password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let reset = password_reset::reset_password(&state.pool, &req.token, &req.new_password)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to reset password: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if !reset {
+        // 链接无效、已使用或已过期
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(req): Json<VerifyEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let verified = verification::verify_email(&state, &req.token).await.map_err(|e| {
+        tracing::error!("Failed to verify email: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !verified {
+        // 链接无效或已过期
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 与忘记密码相同，无论邮箱是否存在、是否已验证都返回 202
+pub async fn resend_verification(
+    State(state): State<AppState>,
+    Json(req): Json<ResendVerificationRequest>,
+) -> Result<StatusCode, StatusCode> {
+    verification::resend_verification(&state, &req.email).await.map_err(|e| {
+        tracing::error!("Failed to resend verification email: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+fn auth_response(user: User, tokens: IssuedTokens) -> AuthResponse {
+    AuthResponse {
+        user: UserResponse::from(user),
+        token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
+    }
+}
+
+// Public keys of the access tokens, for services that verify them; cached briefly so that a rotated
+// key shows up soon
+pub async fn get_jwks(State(state): State<AppState>) -> impl IntoResponse {
+    let jwks: &JwkSet = state.jwt_keys.jwks();
+    ([(header::CACHE_CONTROL, "public, max-age=300")], Json(jwks.clone()))
 }
 
 pub async fn get_current_user(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<UserResponse>, StatusCode> {
-    let uid = crate::auth::extract_uid_from_headers(&headers)?;
+    let uid = crate::auth::extract_uid_from_headers(&state, &headers).await?;
     
     let user = sqlx::query_as::<_, User>(
-        "SELECT id, username, email, password_hash, created_at, updated_at FROM users WHERE id = ?"
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
     )
     .bind(uid)
-    .fetch_optional(&pool)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let user = user.ok_or(StatusCode::NOT_FOUND)?;
 
     Ok(Json(UserResponse::from(user)))
-}
\ No newline at end of file
+}
+
+pub async fn get_storage_usage(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<StorageUsage>, StatusCode> {
+    let uid = crate::auth::extract_uid_from_headers(&state, &headers).await?;
+
+    let (used_bytes, images) = storage_used(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let quota = state.config.storage.user_quota_bytes;
+
+    Ok(Json(StorageUsage {
+        used_bytes,
+        images,
+        quota_bytes: (quota > 0).then_some(quota),
+        max_upload_bytes: state.config.storage.max_upload_bytes,
+    }))
+}
@@ -0,0 +1,250 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{Sqlite, Transaction};
+use std::time::Instant;
+
+use crate::{image_probe::ImageKind, models::Image, spool::SpooledFile, state::AppState};
+
+// A blob row with pending_since set is having its file written or deleted. Uploads of the same
+// bytes wait for that to finish, and take over when it has been pending this long because the
+// process doing it died.
+const STALE_PENDING_SECS: i64 = 5 * 60;
+const PENDING_WAIT: std::time::Duration = std::time::Duration::from_secs(30);
+const PENDING_POLL: std::time::Duration = std::time::Duration::from_millis(200);
+
+pub struct StoredBlob {
+    pub hash: String,
+    pub key: String,
+    // False when identical bytes were already stored
+    pub created: bool,
+}
+
+pub fn blob_hash(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+// Store image bytes under their SHA-256 hash, reusing an existing blob with the same content.
+// This takes no reference; reference_blob does, together with the images row.
+pub async fn store_blob(state: &AppState, data: &[u8], kind: ImageKind) -> Result<StoredBlob> {
+    let blob = claim_blob(state, blob_hash(data), data.len() as u64, kind).await?;
+    if blob.created {
+        let written = state.blobs.put(&blob.key, data.to_vec(), kind.mime_type()).await;
+        finish_blob(state, &blob, written).await?;
+    }
+    Ok(blob)
+}
+
+// Same as store_blob for an upload spooled to disk; the file is streamed to storage.
+pub async fn store_blob_file(state: &AppState, file: &SpooledFile, kind: ImageKind) -> Result<StoredBlob> {
+    let blob = claim_blob(state, file.sha256.clone(), file.size, kind).await?;
+    if blob.created {
+        let written = state.blobs.put_file(&blob.key, file.path(), kind.mime_type()).await;
+        finish_blob(state, &blob, written).await?;
+    }
+    Ok(blob)
+}
+
+// Find the stored blob with this hash, or register it as pending when the caller has to write it
+// (`created`). Waits while another upload writes, or a release deletes, the same blob.
+async fn claim_blob(state: &AppState, hash: String, size: u64, kind: ImageKind) -> Result<StoredBlob> {
+    let key = format!("{}.{}", hash, kind.extension());
+    let deadline = Instant::now() + PENDING_WAIT;
+
+    loop {
+        let now = Utc::now();
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO blobs (hash, key, size, refcount, created_at, pending_since) VALUES (?, ?, ?, 0, ?, ?)
+            ON CONFLICT(hash) DO NOTHING
+            "#
+        )
+        .bind(&hash)
+        .bind(&key)
+        .bind(size as i64)
+        .bind(now)
+        .bind(now)
+        .execute(&state.pool)
+        .await?
+        .rows_affected();
+        if inserted > 0 {
+            return Ok(StoredBlob { hash, key, created: true });
+        }
+
+        // 写入或删除到一半时进程退出留下的记录，由这次上传接手重新写入
+        let taken_over: Option<String> = sqlx::query_scalar(
+            "UPDATE blobs SET pending_since = ? WHERE hash = ? AND pending_since < ? RETURNING key"
+        )
+        .bind(now)
+        .bind(&hash)
+        .bind(now - Duration::seconds(STALE_PENDING_SECS))
+        .fetch_optional(&state.pool)
+        .await?;
+        if let Some(key) = taken_over {
+            return Ok(StoredBlob { hash, key, created: true });
+        }
+
+        let existing: Option<(String, Option<DateTime<Utc>>)> =
+            sqlx::query_as("SELECT key, pending_since FROM blobs WHERE hash = ?")
+                .bind(&hash)
+                .fetch_optional(&state.pool)
+                .await?;
+        match existing {
+            Some((key, None)) => return Ok(StoredBlob { hash, key, created: false }),
+            // 刚被删除，重新登记
+            None => continue,
+            Some((_, Some(_))) if Instant::now() < deadline => tokio::time::sleep(PENDING_POLL).await,
+            Some((_, Some(_))) => bail!("blob {} is still being written or deleted", hash),
+        }
+    }
+}
+
+// Mark a blob written by this process as stored, or forget it when writing failed so that the
+// next upload of the same bytes tries again
+async fn finish_blob(state: &AppState, blob: &StoredBlob, written: Result<()>) -> Result<()> {
+    match written {
+        Ok(()) => {
+            sqlx::query("UPDATE blobs SET pending_since = NULL WHERE hash = ?")
+                .bind(&blob.hash)
+                .execute(&state.pool)
+                .await?;
+            Ok(())
+        }
+        Err(e) => {
+            sqlx::query("DELETE FROM blobs WHERE hash = ? AND refcount = 0 AND pending_since IS NOT NULL")
+                .bind(&blob.hash)
+                .execute(&state.pool)
+                .await?;
+            Err(e)
+        }
+    }
+}
+
+// Take a reference on a stored blob for an images row inserted in the same transaction, so the
+// reference count always equals the number of images using the blob
+pub async fn reference_blob(tx: &mut Transaction<'_, Sqlite>, hash: &str) -> Result<()> {
+    let referenced = sqlx::query("UPDATE blobs SET refcount = refcount + 1, orphaned_at = NULL WHERE hash = ? AND pending_since IS NULL")
+        .bind(hash)
+        .execute(&mut **tx)
+        .await?
+        .rows_affected();
+    if referenced == 0 {
+        bail!("blob {} was deleted before it could be used", hash);
+    }
+    Ok(())
+}
+
+// Delete an images row and drop its reference to the file; returns true when the file itself was
+// removed.
+pub async fn remove_image(state: &AppState, image: &Image) -> Result<bool> {
+    let mut tx = state.pool.begin().await?;
+    sqlx::query("DELETE FROM images WHERE id = ?")
+        .bind(&image.id)
+        .execute(&mut *tx)
+        .await?;
+
+    let Some(hash) = &image.blob_hash else {
+        tx.commit().await?;
+        // 去重之前上传的图片独占自己的文件
+        state.blobs.delete(&image.filename).await?;
+        return Ok(true);
+    };
+
+    let released: Option<(String, i64)> =
+        sqlx::query_as("UPDATE blobs SET refcount = refcount - 1 WHERE hash = ? RETURNING key, refcount")
+            .bind(hash)
+            .fetch_optional(&mut *tx)
+            .await?;
+    let key = match released {
+        Some((key, 0)) => {
+            // 先标记为删除中，防止删除文件期间有相同内容的上传复用它
+            sqlx::query("UPDATE blobs SET pending_since = ? WHERE hash = ?")
+                .bind(Utc::now())
+                .bind(hash)
+                .execute(&mut *tx)
+                .await?;
+            key
+        }
+        _ => {
+            tx.commit().await?;
+            return Ok(false);
+        }
+    };
+    tx.commit().await?;
+
+    delete_blob(state, hash, &key).await?;
+    Ok(true)
+}
+
+// Remove the file of a blob marked as pending with no references, then its row
+pub async fn delete_blob(state: &AppState, hash: &str, key: &str) -> Result<()> {
+    state.blobs.delete(key).await?;
+    sqlx::query("DELETE FROM blobs WHERE hash = ? AND refcount = 0 AND pending_since IS NOT NULL")
+        .bind(hash)
+        .execute(&state.pool)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{test_image, test_state, test_user};
+
+    async fn refcount(state: &AppState, hash: &str) -> Option<i64> {
+        sqlx::query_scalar("SELECT refcount FROM blobs WHERE hash = ?")
+            .bind(hash)
+            .fetch_optional(&state.pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn identical_uploads_share_a_blob() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+
+        let first = test_image(&state, uid, None, 10).await;
+        let second = test_image(&state, uid, None, 10).await;
+        let hash = first.blob_hash.clone().unwrap();
+        assert_ne!(first.id, second.id);
+        assert_eq!(second.blob_hash.as_deref(), Some(hash.as_str()));
+        assert_eq!(first.filename, second.filename);
+        assert_eq!(refcount(&state, &hash).await, Some(2));
+
+        // 还有其他图片在用，文件保留
+        assert!(!remove_image(&state, &first).await.unwrap());
+        assert_eq!(refcount(&state, &hash).await, Some(1));
+        assert!(state.blobs.get(&second.filename).await.unwrap().is_some());
+
+        assert!(remove_image(&state, &second).await.unwrap());
+        assert_eq!(refcount(&state, &hash).await, None);
+        assert!(state.blobs.get(&second.filename).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn claims_wait_for_pending_blobs() {
+        let state = test_state().await;
+        let data = b"not really a png".to_vec();
+        let hash = blob_hash(&data);
+
+        let writer = claim_blob(&state, hash.clone(), data.len() as u64, ImageKind::Png).await.unwrap();
+        assert!(writer.created);
+
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            let hash = hash.clone();
+            async move { claim_blob(&state, hash, 16, ImageKind::Png).await }
+        });
+        tokio::time::sleep(PENDING_POLL * 3).await;
+        // 文件还没写完，不能复用
+        assert!(!waiting.is_finished());
+
+        let written = state.blobs.put(&writer.key, data, "image/png").await;
+        finish_blob(&state, &writer, written).await.unwrap();
+
+        let reader = waiting.await.unwrap().unwrap();
+        assert!(!reader.created);
+        assert_eq!(reader.key, writer.key);
+    }
+}
@@ -0,0 +1,515 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, Mutex as AsyncMutex};
+
+use crate::{
+    handlers::{prune_history, update_content},
+    revisions::record_revision,
+    offload::offload_inline_files,
+    models::Role,
+    scene::{merge_scenes, normalize_content},
+    state::AppState,
+};
+
+const ROOM_CHANNEL_CAPACITY: usize = 256;
+const ACCESS_CHANNEL_CAPACITY: usize = 16;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Collaborator {
+    pub client_id: String,
+    pub uid: i64,
+    pub username: String,
+    pub role: Role,
+}
+
+// Messages sent by a browser over the project socket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Elements {
+        elements: Vec<Value>,
+        #[serde(default)]
+        files: Option<Value>,
+    },
+    Pointer {
+        pointer: Value,
+        #[serde(default)]
+        button: Option<String>,
+    },
+    Selection {
+        #[serde(rename = "selectedElementIds")]
+        selected_element_ids: Value,
+    },
+}
+
+// Messages fanned out to every client in a room
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Init {
+        client_id: String,
+        scene: Value,
+        collaborators: Vec<Collaborator>,
+    },
+    Joined {
+        collaborator: Collaborator,
+    },
+    Left {
+        client_id: String,
+    },
+    Elements {
+        from: String,
+        elements: Vec<Value>,
+        files: Option<Value>,
+    },
+    Pointer {
+        from: String,
+        uid: i64,
+        username: String,
+        pointer: Value,
+        button: Option<String>,
+    },
+    Selection {
+        from: String,
+        uid: i64,
+        #[serde(rename = "selectedElementIds")]
+        selected_element_ids: Value,
+    },
+    Saved {
+        version: i64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    // Sender's client id, so a connection can skip its own messages
+    pub from: Option<String>,
+    pub message: ServerMessage,
+}
+
+struct RoomState {
+    scene: Value,
+    dirty: bool,
+    // Saved since the last revision was recorded
+    unrevisioned: bool,
+    last_revision: Instant,
+    last_editor: i64,
+    collaborators: HashMap<String, Collaborator>,
+    // Saved and about to be dropped; joining has to load a fresh room
+    closed: bool,
+}
+
+pub struct Room {
+    project_id: String,
+    tx: broadcast::Sender<Envelope>,
+    // Users whose membership of the project changed; their connections check their access again
+    access_changed: broadcast::Sender<i64>,
+    state: AsyncMutex<RoomState>,
+    // Held while saving so periodic and final saves of a room don't interleave
+    persisting: AsyncMutex<()>,
+}
+
+impl Room {
+    fn new(project_id: &str, scene: Value) -> Self {
+        let (tx, _) = broadcast::channel(ROOM_CHANNEL_CAPACITY);
+        let (access_changed, _) = broadcast::channel(ACCESS_CHANNEL_CAPACITY);
+        Self {
+            project_id: project_id.to_string(),
+            tx,
+            access_changed,
+            state: AsyncMutex::new(RoomState {
+                scene,
+                dirty: false,
+                unrevisioned: false,
+                last_revision: Instant::now(),
+                last_editor: 0,
+                collaborators: HashMap::new(),
+                closed: false,
+            }),
+            persisting: AsyncMutex::new(()),
+        }
+    }
+
+    fn broadcast(&self, from: Option<&str>, message: ServerMessage) {
+        // 没有订阅者时发送失败，可以忽略
+        let _ = self.tx.send(Envelope {
+            from: from.map(str::to_string),
+            message,
+        });
+    }
+
+    // Add a collaborator and return its subscription with the Init message, or None when the room
+    // has already been closed
+    async fn join(&self, collaborator: Collaborator) -> Option<(broadcast::Receiver<Envelope>, ServerMessage)> {
+        let mut state = self.state.lock().await;
+        if state.closed {
+            return None;
+        }
+        // 在锁内订阅，保证 Init 中的场景之后的修改都能收到
+        let rx = self.tx.subscribe();
+        state
+            .collaborators
+            .insert(collaborator.client_id.clone(), collaborator.clone());
+
+        self.broadcast(
+            Some(&collaborator.client_id),
+            ServerMessage::Joined {
+                collaborator: collaborator.clone(),
+            },
+        );
+
+        Some((
+            rx,
+            ServerMessage::Init {
+                client_id: collaborator.client_id,
+                scene: state.scene.clone(),
+                collaborators: state.collaborators.values().cloned().collect(),
+            },
+        ))
+    }
+
+    pub fn watch_access(&self) -> broadcast::Receiver<i64> {
+        self.access_changed.subscribe()
+    }
+
+    pub async fn handle(&self, sender: &Collaborator, message: ClientMessage) {
+        let from = Some(sender.client_id.as_str());
+
+        match message {
+            ClientMessage::Elements { .. } if sender.role < Role::Editor => {
+                tracing::debug!("Ignoring scene update from viewer {}", sender.uid);
+            }
+            ClientMessage::Elements { elements, files } => {
+                let mut state = self.state.lock().await;
+                let mut update = json!({ "elements": elements });
+                if let Some(files) = &files {
+                    update["files"] = files.clone();
+                }
+                // 保留房间场景里的 appState，只合并元素和文件
+                if let Some(app_state) = state.scene.get("appState") {
+                    update["appState"] = app_state.clone();
+                }
+                state.scene = merge_scenes(&state.scene, &update);
+                state.dirty = true;
+                state.last_editor = sender.uid;
+
+                self.broadcast(from, ServerMessage::Elements { from: sender.client_id.clone(), elements, files });
+            }
+            ClientMessage::Pointer { pointer, button } => {
+                self.broadcast(
+                    from,
+                    ServerMessage::Pointer {
+                        from: sender.client_id.clone(),
+                        uid: sender.uid,
+                        username: sender.username.clone(),
+                        pointer,
+                        button,
+                    },
+                );
+            }
+            ClientMessage::Selection { selected_element_ids } => {
+                self.broadcast(
+                    from,
+                    ServerMessage::Selection {
+                        from: sender.client_id.clone(),
+                        uid: sender.uid,
+                        selected_element_ids,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Rooms {
+    inner: Arc<Mutex<HashMap<String, Arc<Room>>>>,
+}
+
+impl Rooms {
+    // Join the project's room, loading it from the database when nobody is collaborating yet
+    pub async fn join(
+        &self,
+        state: &AppState,
+        project_id: &str,
+        collaborator: &Collaborator,
+    ) -> Result<(Arc<Room>, broadcast::Receiver<Envelope>, ServerMessage), sqlx::Error> {
+        loop {
+            let room = self.get_or_load(state, project_id).await?;
+            if let Some((rx, init)) = room.join(collaborator.clone()).await {
+                return Ok((room, rx, init));
+            }
+            // 房间刚保存完正在关闭，移除后重新加载
+            self.remove(&room);
+        }
+    }
+
+    async fn get_or_load(&self, state: &AppState, project_id: &str) -> Result<Arc<Room>, sqlx::Error> {
+        if let Some(room) = self.inner.lock().unwrap().get(project_id) {
+            return Ok(room.clone());
+        }
+
+        let content: String = sqlx::query_scalar("SELECT content FROM projects WHERE id = ?")
+            .bind(project_id)
+            .fetch_one(&state.pool)
+            .await?;
+        let scene = normalize_content(&serde_json::from_str(&content).unwrap_or(json!({})));
+        let room = Arc::new(Room::new(project_id, scene));
+
+        // 加载期间可能已有其他连接创建了同一个房间
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .entry(project_id.to_string())
+            .or_insert(room)
+            .clone())
+    }
+
+    // Tell the user's open connections to the project that their role changed or was taken away
+    pub fn access_changed(&self, project_id: &str, uid: i64) {
+        if let Some(room) = self.inner.lock().unwrap().get(project_id) {
+            let _ = room.access_changed.send(uid);
+        }
+    }
+
+    pub async fn leave(&self, state: &AppState, room: &Arc<Room>, client_id: &str) {
+        let empty = {
+            let mut room_state = room.state.lock().await;
+            room_state.collaborators.remove(client_id);
+            room_state.collaborators.is_empty()
+        };
+        room.broadcast(Some(client_id), ServerMessage::Left { client_id: client_id.to_string() });
+
+        // 保存失败时房间保留在内存里，由 persist_loop 重试
+        if empty && persist_room(state, room).await {
+            self.remove(room);
+        }
+    }
+
+    fn remove(&self, room: &Arc<Room>) {
+        let mut rooms = self.inner.lock().unwrap();
+        if rooms.get(&room.project_id).is_some_and(|current| Arc::ptr_eq(current, room)) {
+            rooms.remove(&room.project_id);
+        }
+    }
+
+    fn snapshot(&self) -> Vec<Arc<Room>> {
+        self.inner.lock().unwrap().values().cloned().collect()
+    }
+}
+
+// Write the room's merged scene back to the projects table if anything changed since the last
+// save. Returns true when nobody is left in the room and everything is saved; the room is then
+// closed and should be dropped.
+pub async fn persist_room(state: &AppState, room: &Room) -> bool {
+    let _persisting = room.persisting.lock().await;
+    let revision_interval = Duration::from_secs(state.config.collab.revision_interval_secs);
+
+    let (scene, author, revision) = {
+        let mut room_state = room.state.lock().await;
+        let idle = room_state.collaborators.is_empty();
+        // 最后一个人离开时保存的内容一定要进入历史记录
+        let needs_save = room_state.dirty || (idle && room_state.unrevisioned);
+        if !needs_save {
+            room_state.closed |= idle;
+            return room_state.closed;
+        }
+        room_state.dirty = false;
+        let revision = idle || room_state.last_revision.elapsed() >= revision_interval;
+        (room_state.scene.clone(), room_state.last_editor, revision)
+    };
+
+    match save_scene(state, &room.project_id, author, &scene, revision).await {
+        Ok(Some((version, merged))) => {
+            // 保存期间房间里可能又有新的修改，合并而不是覆盖
+            let mut room_state = room.state.lock().await;
+            room_state.scene = merge_scenes(&merged, &room_state.scene);
+            if revision {
+                room_state.unrevisioned = false;
+                room_state.last_revision = Instant::now();
+            } else {
+                room_state.unrevisioned = true;
+            }
+            room_state.closed = room_state.collaborators.is_empty() && !room_state.dirty;
+            let closed = room_state.closed;
+            drop(room_state);
+            room.broadcast(None, ServerMessage::Saved { version });
+            closed
+        }
+        Ok(None) => {
+            // 项目已被删除，没有可保存的地方
+            tracing::warn!("Project {} disappeared while collaborating", room.project_id);
+            let mut room_state = room.state.lock().await;
+            room_state.closed = room_state.collaborators.is_empty();
+            room_state.closed
+        }
+        Err(e) => {
+            tracing::warn!("Failed to persist collaborative scene for project {}: {:#}", room.project_id, e);
+            room.state.lock().await.dirty = true;
+            false
+        }
+    }
+}
+
+async fn save_scene(
+    state: &AppState,
+    project_id: &str,
+    author: i64,
+    scene: &Value,
+    revision: bool,
+) -> anyhow::Result<Option<(i64, Value)>> {
+    // 在事务外转存内嵌图片，房间里的场景稍后合并时会换成引用
+    // 转存失败时和 PUT 一样不保存，房间保留修改，下次再试
+    let offloaded = offload_inline_files(state, project_id, author, scene).await?;
+    let scene = offloaded.as_ref().map_or(scene, |offloaded| &offloaded.content);
+
+    let mut tx = state.pool.begin().await?;
+
+    let Some((stored, current)): Option<(String, i64)> =
+        sqlx::query_as("SELECT content, version FROM projects WHERE id = ?")
+            .bind(project_id)
+            .fetch_optional(&mut *tx)
+            .await?
+    else {
+        return Ok(None);
+    };
+
+    // 合并数据库中的内容，避免覆盖通过 PUT/PATCH 保存的修改
+    let stored: Value = serde_json::from_str(&stored).unwrap_or(json!({}));
+    let merged = merge_scenes(&stored, scene);
+    let content = merged.to_string();
+    let now = Utc::now();
+
+    let Some(version) = update_content(&mut tx, project_id, current, &content, now).await? else {
+        anyhow::bail!("project {} changed while saving", project_id);
+    };
+    if revision {
+        record_revision(&mut tx, project_id, author, &content, now).await?;
+    }
+    tx.commit().await?;
+    if revision {
+        prune_history(state, project_id).await;
+    }
+
+    Ok(Some((version, merged)))
+}
+
+pub async fn persist_loop(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.collab.persist_interval_secs));
+    loop {
+        interval.tick().await;
+        // 同时关闭之前保存失败而留下的空房间
+        for room in state.rooms.snapshot() {
+            if persist_room(&state, &room).await {
+                state.rooms.remove(&room);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collaborator(client_id: &str, role: Role) -> Collaborator {
+        Collaborator {
+            client_id: client_id.to_string(),
+            uid: 1,
+            username: "alice".to_string(),
+            role,
+        }
+    }
+
+    fn element(id: &str, version: i64) -> Value {
+        json!({"id": id, "version": version, "versionNonce": 1, "isDeleted": false})
+    }
+
+    async fn scene_versions(room: &Room) -> Vec<i64> {
+        let state = room.state.lock().await;
+        state.scene["elements"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|element| element["version"].as_i64().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn init_carries_scene_and_collaborators() {
+        let room = Room::new("p", json!({"elements": [element("a", 1)]}));
+        room.join(collaborator("one", Role::Editor)).await.unwrap();
+
+        let (_, init) = room.join(collaborator("two", Role::Viewer)).await.unwrap();
+        let ServerMessage::Init { client_id, scene, collaborators } = init else {
+            panic!("expected Init");
+        };
+        assert_eq!(client_id, "two");
+        assert_eq!(scene["elements"][0]["id"], "a");
+        assert_eq!(collaborators.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn editor_changes_are_merged_and_fanned_out() {
+        let room = Room::new("p", json!({"elements": [element("a", 1)]}));
+        let editor = collaborator("editor", Role::Editor);
+        let (mut own, _) = room.join(editor.clone()).await.unwrap();
+        let (mut other, _) = room.join(collaborator("other", Role::Viewer)).await.unwrap();
+        // 自己收到的是 other 加入的通知
+        assert!(matches!(own.recv().await.unwrap().message, ServerMessage::Joined { .. }));
+
+        room.handle(&editor, ClientMessage::Elements { elements: vec![element("a", 2)], files: None })
+            .await;
+
+        assert_eq!(scene_versions(&room).await, [2]);
+        assert!(room.state.lock().await.dirty);
+        // 连接会跳过自己发出的加入通知
+        assert_eq!(other.recv().await.unwrap().from.as_deref(), Some("other"));
+        let envelope = other.recv().await.unwrap();
+        assert_eq!(envelope.from.as_deref(), Some("editor"));
+        assert!(matches!(envelope.message, ServerMessage::Elements { .. }));
+    }
+
+    #[tokio::test]
+    async fn viewer_changes_are_ignored() {
+        let room = Room::new("p", json!({"elements": [element("a", 1)]}));
+        let viewer = collaborator("viewer", Role::Viewer);
+        room.join(viewer.clone()).await.unwrap();
+
+        room.handle(&viewer, ClientMessage::Elements { elements: vec![element("a", 5)], files: None })
+            .await;
+
+        assert_eq!(scene_versions(&room).await, [1]);
+        assert!(!room.state.lock().await.dirty);
+    }
+
+    #[tokio::test]
+    async fn closed_room_cannot_be_joined() {
+        let room = Room::new("p", json!({}));
+        room.state.lock().await.closed = true;
+        assert!(room.join(collaborator("late", Role::Editor)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn access_changes_reach_open_rooms() {
+        let state = crate::state::test_state().await;
+        sqlx::query("INSERT INTO projects (id, name, content, uid) VALUES ('p', 'p', '{}', 1)")
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let (room, _rx, _) = state.rooms.join(&state, "p", &collaborator("one", Role::Editor)).await.unwrap();
+        let mut changes = room.watch_access();
+
+        state.rooms.access_changed("p", 1);
+        // 没有人协作的项目没有房间，不需要通知
+        state.rooms.access_changed("other", 2);
+        assert_eq!(changes.recv().await.unwrap(), 1);
+        assert!(changes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use futures::{SinkExt, StreamExt};
+use std::time::Duration;
+use tokio::{
+    sync::{broadcast::error::RecvError, oneshot},
+    time::Instant,
+};
+use uuid::Uuid;
+
+use crate::{
+    access::require_role,
+    auth::{authenticate, claims_from_token, NO_SESSION_ID},
+    collab::{ClientMessage, Collaborator},
+    config::UnverifiedAccess,
+    models::Role,
+    sessions::touch_session,
+    state::AppState,
+    verification::is_verified,
+};
+
+// Browsers can't set the Authorization header on a WebSocket, so they offer the access token as
+// a subprotocol "venus.token.<token>" next to "venus"; a query parameter would end up in the
+// access logs of reverse proxies
+const PROTOCOL: &str = "venus";
+const TOKEN_PROTOCOL_PREFIX: &str = "venus.token.";
+
+// How often an open connection checks that its login and project role are still valid
+const RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+// Close code telling the client that its login or role changed; reconnecting picks up the new role
+const ACCESS_CHANGED: u16 = 4403;
+
+// How the connection was authenticated, checked again while it stays open
+enum Credential {
+    // An access token: the socket outlives the token itself, but not its login session
+    Session(String),
+    // An API token or a user signed in at a reverse proxy, authenticated again with the same headers
+    Headers(HeaderMap),
+}
+
+fn protocol_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .find_map(|protocol| protocol.trim().strip_prefix(TOKEN_PROTOCOL_PREFIX))
+        .map(str::to_string)
+}
+
+pub async fn project_ws(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, StatusCode> {
+    let (uid, credential) = match protocol_token(&headers) {
+        Some(token) => {
+            let claims = claims_from_token(&state, &token).await?;
+            (claims.uid, Credential::Session(claims.sid))
+        }
+        None => {
+            let claims = authenticate(&state, &headers).await?;
+            let credential = if claims.sid == NO_SESSION_ID {
+                Credential::Headers(headers)
+            } else {
+                Credential::Session(claims.sid)
+            };
+            (claims.uid, credential)
+        }
+    };
+
+    let role = collaborator_role(&state, &id, uid).await?;
+
+    let username: String = sqlx::query_scalar("SELECT username FROM users WHERE id = ?")
+        .bind(uid)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    // 项目不存在时在升级之前返回错误
+    sqlx::query_scalar::<_, String>("SELECT id FROM projects WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let collaborator = Collaborator {
+        client_id: Uuid::new_v4().to_string(),
+        uid,
+        username,
+        role,
+    };
+
+    Ok(ws
+        .protocols([PROTOCOL])
+        .on_upgrade(move |socket| handle_socket(state, id, collaborator, credential, socket)))
+}
+
+// The role the user collaborates with; unverified accounts with read-only access can only watch
+async fn collaborator_role(state: &AppState, project_id: &str, uid: i64) -> Result<Role, StatusCode> {
+    let role = require_role(&state.pool, project_id, uid, Role::Viewer).await?;
+    if state.config.auth.unverified_access == UnverifiedAccess::ReadOnly
+        && !is_verified(&state.pool, uid).await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    {
+        return Ok(Role::Viewer);
+    }
+    Ok(role)
+}
+
+// Whether the connection may stay open: the login is still valid and the user still has the role
+// the connection joined with. A changed role closes it too, so that the client rejoins with the new one.
+async fn still_allowed(
+    state: &AppState,
+    project_id: &str,
+    collaborator: &Collaborator,
+    credential: &Credential,
+) -> Result<bool, StatusCode> {
+    let signed_in = match credential {
+        Credential::Session(session_id) => touch_session(&state.pool, session_id, collaborator.uid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        Credential::Headers(headers) => match authenticate(state, headers).await {
+            Ok(claims) => claims.uid == collaborator.uid,
+            Err(StatusCode::UNAUTHORIZED) => false,
+            Err(status) => return Err(status),
+        },
+    };
+    if !signed_in {
+        return Ok(false);
+    }
+
+    match collaborator_role(state, project_id, collaborator.uid).await {
+        Ok(role) => Ok(role == collaborator.role),
+        Err(StatusCode::NOT_FOUND | StatusCode::FORBIDDEN) => Ok(false),
+        Err(status) => Err(status),
+    }
+}
+
+async fn handle_socket(
+    state: AppState,
+    project_id: String,
+    collaborator: Collaborator,
+    credential: Credential,
+    socket: WebSocket,
+) {
+    let (mut sink, mut stream) = socket.split();
+
+    let (room, mut rx, init) = match state.rooms.join(&state, &project_id, &collaborator).await {
+        Ok(joined) => joined,
+        Err(e) => {
+            tracing::warn!("Failed to open collaboration room for project {}: {}", project_id, e);
+            return;
+        }
+    };
+    let Ok(init) = serde_json::to_string(&init) else {
+        state.rooms.leave(&state, &room, &collaborator.client_id).await;
+        return;
+    };
+    if sink.send(Message::Text(init)).await.is_err() {
+        state.rooms.leave(&state, &room, &collaborator.client_id).await;
+        return;
+    }
+
+    // 将房间广播转发给当前连接（跳过自己发出的消息）
+    let client_id = collaborator.client_id.clone();
+    let (close_tx, mut close_rx) = oneshot::channel::<()>();
+    let mut forward = tokio::spawn(async move {
+        loop {
+            let received = tokio::select! {
+                received = rx.recv() => received,
+                Ok(()) = &mut close_rx => {
+                    let frame = CloseFrame { code: ACCESS_CHANGED, reason: "access changed".into() };
+                    let _ = sink.send(Message::Close(Some(frame))).await;
+                    break;
+                }
+            };
+            let envelope = match received {
+                Ok(envelope) => envelope,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Collaboration client {} lagged by {} messages", client_id, skipped);
+                    continue;
+                }
+                Err(_) => break,
+            };
+            if envelope.from.as_deref() == Some(client_id.as_str()) {
+                continue;
+            }
+            let Ok(text) = serde_json::to_string(&envelope.message) else {
+                continue;
+            };
+            if sink.send(Message::Text(text)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut access_changed = room.watch_access();
+    let mut recheck = tokio::time::interval_at(Instant::now() + RECHECK_INTERVAL, RECHECK_INTERVAL);
+    let mut close_tx = Some(close_tx);
+    let mut forward_done = false;
+    loop {
+        tokio::select! {
+            incoming = stream.next() => {
+                match incoming {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientMessage>(&text) {
+                        Ok(message) => room.handle(&collaborator, message).await,
+                        Err(e) => tracing::debug!("Ignoring malformed collaboration message: {}", e),
+                    },
+                    Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+            changed = access_changed.recv() => match changed {
+                // 成员被移除或角色变化时立即重新检查
+                Ok(uid) if uid != collaborator.uid => {}
+                Ok(_) | Err(RecvError::Lagged(_)) => recheck.reset_immediately(),
+                Err(RecvError::Closed) => {}
+            },
+            _ = recheck.tick() => match still_allowed(&state, &project_id, &collaborator, &credential).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    // 登录失效或角色变化，通知客户端后断开
+                    if let Some(close_tx) = close_tx.take() {
+                        let _ = close_tx.send(());
+                    }
+                    forward_done = tokio::time::timeout(CLOSE_TIMEOUT, &mut forward).await.is_ok();
+                    break;
+                }
+                // 数据库暂时不可用时保持连接，下次再检查
+                Err(status) => tracing::warn!(
+                    "Failed to recheck access of collaboration client {}: {}",
+                    collaborator.client_id,
+                    status
+                ),
+            },
+            _ = &mut forward => {
+                forward_done = true;
+                break;
+            }
+        }
+    }
+
+    if !forward_done {
+        forward.abort();
+        let _ = forward.await;
+    }
+    state.rooms.leave(&state, &room, &collaborator.client_id).await;
+}
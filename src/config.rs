@@ -0,0 +1,986 @@
+use anyhow::{bail, Context, Result};
+use axum::http::{HeaderName, HeaderValue};
+use clap::{Parser, Subcommand};
+use lettre::message::Mailbox;
+use reqwest::Url;
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use crate::{auth::random_token, proxy::Cidr};
+
+const DEFAULT_CONFIG_PATH: &str = "venus.toml";
+const MIN_SECRET_LEN: usize = 32;
+// Placeholder secrets of venus.example.toml, which must not end up signing real tokens
+const PLACEHOLDER_SECRET_PREFIX: &str = "change-me";
+
+#[derive(Debug, Parser)]
+#[command(name = "venus", version, about = "Excalidraw drawing project manager")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the TOML config file (defaults to ./venus.toml when present)
+    #[arg(short, long, global = true, env = "VENUS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:8085
+    #[arg(long, global = true, env = "VENUS_BIND")]
+    pub bind: Option<SocketAddr>,
+
+    /// SQLite database URL, e.g. sqlite:./venus.db
+    #[arg(long, global = true, env = "VENUS_DATABASE_URL")]
+    pub database_url: Option<String>,
+
+    /// Maximum number of open database connections
+    #[arg(long, global = true, env = "VENUS_DATABASE_MAX_CONNECTIONS")]
+    pub database_max_connections: Option<u32>,
+
+    /// Where uploaded images are stored: "filesystem" or "s3"
+    #[arg(long, global = true, env = "VENUS_STORAGE_BACKEND")]
+    pub storage_backend: Option<StorageBackend>,
+
+    /// Directory uploaded images are stored in (filesystem backend)
+    #[arg(long, global = true, env = "VENUS_UPLOAD_DIR")]
+    pub upload_dir: Option<PathBuf>,
+
+    /// S3-compatible endpoint, e.g. https://s3.us-east-1.amazonaws.com or http://127.0.0.1:9000
+    #[arg(long, global = true, env = "VENUS_S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    /// Bucket uploaded images are stored in (s3 backend)
+    #[arg(long, global = true, env = "VENUS_S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    /// Access key id for the s3 backend
+    #[arg(long, global = true, env = "VENUS_S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+
+    /// Secret access key for the s3 backend
+    #[arg(long, global = true, env = "VENUS_S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+
+    /// Comma separated list of allowed CORS origins, or "*"
+    #[arg(long, global = true, env = "VENUS_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+
+    /// Lifetime of issued access tokens in seconds
+    #[arg(long, global = true, env = "VENUS_TOKEN_TTL")]
+    pub token_ttl: Option<i64>,
+
+    /// Secret used to sign tokens with --jwt-algorithm hs256
+    #[arg(long, global = true, env = "VENUS_JWT_SECRET", hide_env_values = true)]
+    pub jwt_secret: Option<String>,
+
+    /// Algorithm access tokens are signed with: "eddsa", "rs256" or "hs256"
+    #[arg(long, global = true, env = "VENUS_JWT_ALGORITHM")]
+    pub jwt_algorithm: Option<JwtAlgorithm>,
+
+    /// PEM private key access tokens are signed with; generated when the file does not exist
+    #[arg(long, global = true, env = "VENUS_SIGNING_KEY_FILE")]
+    pub signing_key_file: Option<PathBuf>,
+
+    /// Comma separated PEM keys of earlier rotations whose tokens are still accepted
+    #[arg(long, global = true, env = "VENUS_VERIFICATION_KEY_FILES", value_delimiter = ',')]
+    pub verification_key_files: Option<Vec<PathBuf>>,
+
+    /// Secret used to sign time-limited image URLs (defaults to the JWT secret)
+    #[arg(long, global = true, env = "VENUS_URL_SIGNING_SECRET", hide_env_values = true)]
+    pub url_signing_secret: Option<String>,
+
+    /// Address users reach the app at, used for links in emails, e.g. https://draw.example.com
+    #[arg(long, global = true, env = "VENUS_PUBLIC_URL")]
+    pub public_url: Option<String>,
+
+    /// What accounts with an unverified email may do: "full", "read-only" or "none" (no login)
+    #[arg(long, global = true, env = "VENUS_UNVERIFIED_ACCESS")]
+    pub unverified_access: Option<UnverifiedAccess>,
+
+    /// How emails are delivered: "log", "file" or "smtp"
+    #[arg(long, global = true, env = "VENUS_MAIL_BACKEND")]
+    pub mail_backend: Option<MailBackend>,
+
+    /// Comma separated addresses or CIDR ranges of reverse proxies whose forwarded headers are trusted
+    #[arg(long, global = true, env = "VENUS_TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Option<Vec<String>>,
+
+    /// Accept users authenticated in front of venus: "off", "headers" (identity headers from a trusted proxy) or "url"
+    #[arg(long, global = true, env = "VENUS_FORWARD_AUTH")]
+    pub forward_auth: Option<ForwardAuthMode>,
+
+    /// Auth service asked whether a request's cookies belong to a signed-in user (forward auth "url" mode)
+    #[arg(long, global = true, env = "VENUS_FORWARD_AUTH_URL")]
+    pub forward_auth_url: Option<String>,
+
+    /// OpenID Connect issuer to offer single sign-on with, e.g. https://login.example.com/realms/main
+    #[arg(long, global = true, env = "VENUS_OIDC_ISSUER")]
+    pub oidc_issuer: Option<String>,
+
+    /// Client id venus is registered with at the OpenID Connect provider
+    #[arg(long, global = true, env = "VENUS_OIDC_CLIENT_ID")]
+    pub oidc_client_id: Option<String>,
+
+    /// Client secret for the OpenID Connect provider
+    #[arg(long, global = true, env = "VENUS_OIDC_CLIENT_SECRET", hide_env_values = true)]
+    pub oidc_client_secret: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (default)
+    Serve,
+    /// Detect the real type and dimensions of stored images and fill in missing metadata
+    BackfillImages,
+    /// Generate missing image variants and project thumbnails, then exit
+    BackfillThumbnails,
+    /// Move images embedded as data URLs in stored scenes into the image store, then exit
+    OffloadFiles,
+    /// Find images and files nothing references any more and delete them
+    Gc {
+        /// Only report what would be deleted, without changing anything (grace periods do not start either)
+        #[arg(long)]
+        dry_run: bool,
+        /// Only delete orphans first found unreferenced at least this long ago (defaults to gc.grace_period_secs)
+        #[arg(long)]
+        grace_period_secs: Option<u64>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub storage: StorageConfig,
+    pub auth: AuthConfig,
+    pub history: HistoryConfig,
+    pub collab: CollabConfig,
+    pub gc: GcConfig,
+    pub mail: MailConfig,
+    pub oidc: OidcConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    pub cors_origins: Vec<String>,
+    // Base of the links put into emails
+    pub public_url: String,
+    // Addresses or CIDR ranges of reverse proxies allowed to tell venus the client address
+    // (X-Forwarded-For) and, with auth.forward.mode = "headers", who the user is
+    pub trusted_proxies: Vec<String>,
+}
+
+impl ServerConfig {
+    // Whether public_url is the built-in default or otherwise only reachable from this machine
+    pub fn links_to_localhost(&self) -> bool {
+        let Some(host) = Url::parse(&self.public_url).ok().and_then(|url| url.host_str().map(str::to_owned)) else {
+            return false;
+        };
+        match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Ok(ip) => ip.is_loopback(),
+            Err(_) => host.eq_ignore_ascii_case("localhost"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8085)),
+            cors_origins: vec!["*".to_string()],
+            public_url: "http://localhost:8085".to_string(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite:./venus.db".to_string(),
+            max_connections: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default]
+    Filesystem,
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    // Root directory of the filesystem backend
+    pub upload_dir: PathBuf,
+    // Default lifetime of signed image URLs
+    pub signed_url_ttl_secs: i64,
+    // Largest single image upload
+    pub max_upload_bytes: u64,
+    // Total size of the images each user may upload, 0 for no limit
+    pub user_quota_bytes: u64,
+    pub s3: S3Config,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Filesystem,
+            upload_dir: PathBuf::from("uploads/images"),
+            signed_url_ttl_secs: 60 * 60,
+            max_upload_bytes: 20 * 1024 * 1024,
+            user_quota_bytes: 0,
+            s3: S3Config::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    // Prepended to every object key, e.g. "venus/images/"
+    pub prefix: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    // Address buckets as endpoint/bucket/key instead of bucket.endpoint/key (MinIO and most
+    // self-hosted stores need this)
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: String::new(),
+            region: "us-east-1".to_string(),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+            path_style: true,
+        }
+    }
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("access_key_id", &self.access_key_id)
+            .field("secret_access_key", &"<redacted>")
+            .field("path_style", &self.path_style)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    // Newest revisions kept in full for every project
+    pub keep_last: u32,
+    // Older revisions are thinned to one per day and dropped after this many days (0 = never)
+    pub daily_retention_days: u32,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            keep_last: 50,
+            daily_retention_days: 90,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CollabConfig {
+    // How often live collaboration rooms are written back to the projects table
+    pub persist_interval_secs: u64,
+    // Those saves add a history revision at most this often, and once more when the last
+    // collaborator leaves
+    pub revision_interval_secs: u64,
+}
+
+impl Default for CollabConfig {
+    fn default() -> Self {
+        Self {
+            persist_interval_secs: 5,
+            revision_interval_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    // Run the orphaned image collector in the background
+    pub enabled: bool,
+    pub interval_secs: u64,
+    // Orphans younger than this are kept, so uploads not yet saved into a scene survive
+    pub grace_period_secs: u64,
+    // Only log what would be deleted; nothing is written, so grace periods do not start either
+    pub dry_run: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_secs: 6 * 60 * 60,
+            grace_period_secs: 7 * 24 * 60 * 60,
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MailBackend {
+    // Write emails to the log; for local testing only, since reset links end up in it
+    #[default]
+    Log,
+    // Write each email as an .eml file into mail.dir
+    File,
+    Smtp,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub backend: MailBackend,
+    pub from: String,
+    // Directory of the file backend
+    pub dir: PathBuf,
+    pub smtp: SmtpConfig,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            backend: MailBackend::Log,
+            from: "Venus <venus@localhost>".to_string(),
+            dir: PathBuf::from("mail"),
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Plain connection upgraded with STARTTLS (usually port 587)
+    #[default]
+    Starttls,
+    // TLS from the start (usually port 465)
+    Tls,
+    // No encryption; only for relays on the same host or network
+    None,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    // Leave empty for relays that don't require authentication
+    pub username: String,
+    pub password: String,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: 587,
+            security: SmtpSecurity::Starttls,
+            username: String::new(),
+            password: String::new(),
+        }
+    }
+}
+
+impl std::fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnverifiedAccess {
+    #[default]
+    Full,
+    // Can sign in and look at everything they have access to, but not change anything
+    ReadOnly,
+    // Cannot sign in until the email address is verified
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum JwtAlgorithm {
+    // Ed25519 keys; other services verify tokens with the keys published at /.well-known/jwks.json
+    #[default]
+    #[value(name = "eddsa")]
+    EdDSA,
+    Rs256,
+    // The shared auth.jwt_secret; nothing is published
+    Hs256,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardAuthMode {
+    // Only venus's own logins
+    #[default]
+    Off,
+    // Trust the user and email headers a proxy in server.trusted_proxies sets
+    Headers,
+    // Ask auth.forward.url whether the cookies of a request belong to a signed-in user
+    Url,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardAuthConfig {
+    pub mode: ForwardAuthMode,
+    // Request headers in "headers" mode, response headers of the auth service in "url" mode
+    pub user_header: String,
+    pub email_header: String,
+    pub url: String,
+    // How long an answer of the auth service is reused for the same cookies
+    pub cache_secs: u64,
+    // Create an account the first time the proxy sends someone venus doesn't know
+    pub allow_signup: bool,
+}
+
+impl Default for ForwardAuthConfig {
+    fn default() -> Self {
+        Self {
+            mode: ForwardAuthMode::Off,
+            user_header: "X-Forwarded-User".to_string(),
+            email_header: "X-Forwarded-Email".to_string(),
+            url: String::new(),
+            cache_secs: 30,
+            allow_signup: true,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    pub jwt_algorithm: JwtAlgorithm,
+    // Private key for eddsa and rs256, created on first run
+    pub signing_key_file: PathBuf,
+    // Keys of earlier rotations, private or public; tokens they signed are still accepted
+    pub verification_key_files: Vec<PathBuf>,
+    // Lifetime of access tokens; clients renew them with their refresh token
+    pub token_ttl_secs: i64,
+    // How long a login stays valid without being used
+    pub refresh_token_ttl_secs: i64,
+    pub url_signing_secret: String,
+    // How long a password reset link can be used
+    pub password_reset_ttl_secs: i64,
+    // How long the link sent to verify an email address can be used
+    pub email_verification_ttl_secs: i64,
+    pub unverified_access: UnverifiedAccess,
+    // Name authenticator apps show next to the account
+    pub totp_issuer: String,
+    pub forward: ForwardAuthConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: String::new(),
+            jwt_algorithm: JwtAlgorithm::EdDSA,
+            signing_key_file: PathBuf::from("jwt-signing-key.pem"),
+            verification_key_files: Vec::new(),
+            token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 30 * 24 * 60 * 60,
+            url_signing_secret: String::new(),
+            password_reset_ttl_secs: 60 * 60,
+            email_verification_ttl_secs: 48 * 60 * 60,
+            unverified_access: UnverifiedAccess::Full,
+            totp_issuer: "Venus".to_string(),
+            forward: ForwardAuthConfig::default(),
+        }
+    }
+}
+
+// Keep secrets out of debug output and logs
+impl std::fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("jwt_secret", &"<redacted>")
+            .field("jwt_algorithm", &self.jwt_algorithm)
+            .field("signing_key_file", &self.signing_key_file)
+            .field("verification_key_files", &self.verification_key_files)
+            .field("token_ttl_secs", &self.token_ttl_secs)
+            .field("refresh_token_ttl_secs", &self.refresh_token_ttl_secs)
+            .field("url_signing_secret", &"<redacted>")
+            .field("password_reset_ttl_secs", &self.password_reset_ttl_secs)
+            .field("email_verification_ttl_secs", &self.email_verification_ttl_secs)
+            .field("unverified_access", &self.unverified_access)
+            .field("totp_issuer", &self.totp_issuer)
+            .field("forward", &self.forward)
+            .finish()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    // Single sign-on is offered when this is set
+    pub issuer: String,
+    pub client_id: String,
+    // Empty for public clients, which only rely on PKCE
+    pub client_secret: String,
+    // Defaults to {server.public_url}/api/auth/oidc/callback
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    // Shown on the login button
+    pub name: String,
+    // Create an account the first time someone signs in through the provider
+    pub allow_signup: bool,
+    // Treat the provider's email as verified even when it doesn't send email_verified
+    pub trust_email: bool,
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: String::new(),
+            redirect_url: String::new(),
+            scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+            name: "SSO".to_string(),
+            allow_signup: true,
+            trust_email: false,
+        }
+    }
+}
+
+impl OidcConfig {
+    pub fn enabled(&self) -> bool {
+        !self.issuer.is_empty()
+    }
+}
+
+impl std::fmt::Debug for OidcConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcConfig")
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("redirect_url", &self.redirect_url)
+            .field("scopes", &self.scopes)
+            .field("name", &self.name)
+            .field("allow_signup", &self.allow_signup)
+            .field("trust_email", &self.trust_email)
+            .finish()
+    }
+}
+
+impl Config {
+    // Layering order: built-in defaults < config file < VENUS_* env vars < CLI flags
+    pub fn load(cli: &Cli) -> Result<Self> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_overrides(cli);
+
+        if config.auth.jwt_secret.is_empty() {
+            if config.auth.jwt_algorithm == JwtAlgorithm::Hs256 {
+                tracing::warn!(
+                    "No JWT secret configured, generating a random one; issued tokens will not survive a restart"
+                );
+            } else if config.auth.url_signing_secret.is_empty() {
+                tracing::warn!(
+                    "No URL signing secret configured, generating a random one; signed image URLs will not survive a restart"
+                );
+            }
+            config.auth.jwt_secret = random_token(64);
+        }
+        if config.auth.url_signing_secret.is_empty() {
+            config.auth.url_signing_secret = config.auth.jwt_secret.clone();
+        }
+        if config.oidc.redirect_url.is_empty() {
+            config.oidc.redirect_url =
+                format!("{}/api/auth/oidc/callback", config.server.public_url.trim_end_matches('/'));
+        }
+
+        config.validate()?;
+
+        // 默认配置只适合在本机试用，对外监听时要大声提醒
+        if !config.server.bind.ip().is_loopback() {
+            if config.mail.backend == MailBackend::Log {
+                tracing::warn!(
+                    "mail.backend is \"log\" while listening on {}: password reset and verification links are written to the log instead of being emailed, and anyone who can read the log can take over accounts; configure [mail] before letting others use this server",
+                    config.server.bind
+                );
+            }
+            if config.server.links_to_localhost() {
+                tracing::warn!(
+                    "server.public_url is {:?} while listening on {}: links in emails and SSO redirects will point at this machine's localhost; set it to the address users reach venus at",
+                    config.server.public_url,
+                    config.server.bind
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&raw)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+
+    fn apply_overrides(&mut self, cli: &Cli) {
+        if let Some(bind) = cli.bind {
+            self.server.bind = bind;
+        }
+        if let Some(url) = &cli.database_url {
+            self.database.url = url.clone();
+        }
+        if let Some(max_connections) = cli.database_max_connections {
+            self.database.max_connections = max_connections;
+        }
+        if let Some(backend) = cli.storage_backend {
+            self.storage.backend = backend;
+        }
+        if let Some(dir) = &cli.upload_dir {
+            self.storage.upload_dir = dir.clone();
+        }
+        if let Some(endpoint) = &cli.s3_endpoint {
+            self.storage.s3.endpoint = endpoint.clone();
+        }
+        if let Some(bucket) = &cli.s3_bucket {
+            self.storage.s3.bucket = bucket.clone();
+        }
+        if let Some(key) = &cli.s3_access_key_id {
+            self.storage.s3.access_key_id = key.clone();
+        }
+        if let Some(secret) = &cli.s3_secret_access_key {
+            self.storage.s3.secret_access_key = secret.clone();
+        }
+        if let Some(origins) = &cli.cors_origins {
+            self.server.cors_origins = origins.clone();
+        }
+        if let Some(ttl) = cli.token_ttl {
+            self.auth.token_ttl_secs = ttl;
+        }
+        if let Some(secret) = &cli.jwt_secret {
+            self.auth.jwt_secret = secret.clone();
+        }
+        if let Some(algorithm) = cli.jwt_algorithm {
+            self.auth.jwt_algorithm = algorithm;
+        }
+        if let Some(path) = &cli.signing_key_file {
+            self.auth.signing_key_file = path.clone();
+        }
+        if let Some(paths) = &cli.verification_key_files {
+            self.auth.verification_key_files = paths.clone();
+        }
+        if let Some(secret) = &cli.url_signing_secret {
+            self.auth.url_signing_secret = secret.clone();
+        }
+        if let Some(url) = &cli.public_url {
+            self.server.public_url = url.clone();
+        }
+        if let Some(access) = cli.unverified_access {
+            self.auth.unverified_access = access;
+        }
+        if let Some(backend) = cli.mail_backend {
+            self.mail.backend = backend;
+        }
+        if let Some(proxies) = &cli.trusted_proxies {
+            self.server.trusted_proxies = proxies.clone();
+        }
+        if let Some(mode) = cli.forward_auth {
+            self.auth.forward.mode = mode;
+        }
+        if let Some(url) = &cli.forward_auth_url {
+            self.auth.forward.url = url.clone();
+        }
+        if let Some(issuer) = &cli.oidc_issuer {
+            self.oidc.issuer = issuer.clone();
+        }
+        if let Some(client_id) = &cli.oidc_client_id {
+            self.oidc.client_id = client_id.clone();
+        }
+        if let Some(secret) = &cli.oidc_client_secret {
+            self.oidc.client_secret = secret.clone();
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !self.database.url.starts_with("sqlite:") {
+            bail!("database.url must be a sqlite: URL, got {:?}", self.database.url);
+        }
+        if self.database.max_connections == 0 {
+            bail!("database.max_connections must be greater than 0");
+        }
+        match self.storage.backend {
+            StorageBackend::Filesystem if self.storage.upload_dir.as_os_str().is_empty() => {
+                bail!("storage.upload_dir must not be empty");
+            }
+            StorageBackend::Filesystem => {}
+            StorageBackend::S3 => {
+                let s3 = &self.storage.s3;
+                if !(s3.endpoint.starts_with("http://") || s3.endpoint.starts_with("https://")) {
+                    bail!("storage.s3.endpoint must be an http(s) URL, got {:?}", s3.endpoint);
+                }
+                if s3.bucket.is_empty() || s3.region.is_empty() {
+                    bail!("storage.s3.bucket and storage.s3.region must be set");
+                }
+                if s3.access_key_id.is_empty() || s3.secret_access_key.is_empty() {
+                    bail!("storage.s3.access_key_id and storage.s3.secret_access_key must be set");
+                }
+            }
+        }
+        if self.storage.max_upload_bytes == 0 {
+            bail!("storage.max_upload_bytes must be greater than 0");
+        }
+        if self.server.cors_origins.is_empty() {
+            bail!("server.cors_origins must list at least one origin (use \"*\" to allow any)");
+        }
+        for origin in &self.server.cors_origins {
+            if origin == "*" {
+                continue;
+            }
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || HeaderValue::from_str(origin).is_err()
+            {
+                bail!("invalid CORS origin {:?}", origin);
+            }
+        }
+        if self.server.cors_origins.len() > 1 && self.server.cors_origins.iter().any(|o| o == "*") {
+            bail!("\"*\" cannot be combined with explicit CORS origins");
+        }
+        if !(self.server.public_url.starts_with("http://") || self.server.public_url.starts_with("https://")) {
+            bail!("server.public_url must be an http(s) URL, got {:?}", self.server.public_url);
+        }
+        for proxy in &self.server.trusted_proxies {
+            proxy.parse::<Cidr>().with_context(|| format!("invalid server.trusted_proxies entry {:?}", proxy))?;
+        }
+        let forward = &self.auth.forward;
+        if forward.mode != ForwardAuthMode::Off {
+            for name in [&forward.user_header, &forward.email_header] {
+                if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                    bail!("invalid header name {:?} in auth.forward", name);
+                }
+            }
+        }
+        match forward.mode {
+            ForwardAuthMode::Headers if self.server.trusted_proxies.is_empty() => {
+                bail!("auth.forward.mode = \"headers\" needs server.trusted_proxies, or anyone could claim to be anyone");
+            }
+            ForwardAuthMode::Url if !(forward.url.starts_with("http://") || forward.url.starts_with("https://")) => {
+                bail!("auth.forward.url must be an http(s) URL, got {:?}", forward.url);
+            }
+            _ => {}
+        }
+        if self.auth.token_ttl_secs <= 0 {
+            bail!("auth.token_ttl_secs must be positive");
+        }
+        if self.auth.refresh_token_ttl_secs < self.auth.token_ttl_secs {
+            bail!("auth.refresh_token_ttl_secs must be at least auth.token_ttl_secs");
+        }
+        if self.auth.jwt_secret.len() < MIN_SECRET_LEN {
+            bail!("auth.jwt_secret must be at least {} characters", MIN_SECRET_LEN);
+        }
+        if self.auth.jwt_secret.starts_with(PLACEHOLDER_SECRET_PREFIX) {
+            bail!("auth.jwt_secret is still the placeholder from venus.example.toml; set a random secret or remove it");
+        }
+        if self.auth.jwt_algorithm != JwtAlgorithm::Hs256 && self.auth.signing_key_file.as_os_str().is_empty() {
+            bail!("auth.signing_key_file must be set for auth.jwt_algorithm = \"eddsa\" or \"rs256\"");
+        }
+        if self.auth.url_signing_secret.len() < MIN_SECRET_LEN {
+            bail!("auth.url_signing_secret must be at least {} characters", MIN_SECRET_LEN);
+        }
+        if self.auth.url_signing_secret.starts_with(PLACEHOLDER_SECRET_PREFIX) {
+            bail!("auth.url_signing_secret is still a placeholder; set a random secret or remove it");
+        }
+        if self.auth.password_reset_ttl_secs <= 0 {
+            bail!("auth.password_reset_ttl_secs must be positive");
+        }
+        if self.auth.totp_issuer.is_empty() || self.auth.totp_issuer.contains(':') {
+            bail!("auth.totp_issuer must be set and must not contain ':'");
+        }
+        if self.auth.email_verification_ttl_secs <= 0 {
+            bail!("auth.email_verification_ttl_secs must be positive");
+        }
+        if self.mail.from.parse::<Mailbox>().is_err() {
+            bail!("mail.from must be an address like \"Venus <venus@example.com>\", got {:?}", self.mail.from);
+        }
+        match self.mail.backend {
+            MailBackend::File if self.mail.dir.as_os_str().is_empty() => bail!("mail.dir must not be empty"),
+            MailBackend::Smtp if self.mail.smtp.host.is_empty() => bail!("mail.smtp.host must be set"),
+            MailBackend::Smtp if self.server.links_to_localhost() => bail!(
+                "server.public_url is {:?}, so the links in emails sent to users would not work; set it to the address users reach venus at",
+                self.server.public_url
+            ),
+            _ => {}
+        }
+        if self.oidc.enabled() {
+            let oidc = &self.oidc;
+            if !(oidc.issuer.starts_with("http://") || oidc.issuer.starts_with("https://")) {
+                bail!("oidc.issuer must be an http(s) URL, got {:?}", oidc.issuer);
+            }
+            if oidc.client_id.is_empty() {
+                bail!("oidc.client_id must be set");
+            }
+            if !(oidc.redirect_url.starts_with("http://") || oidc.redirect_url.starts_with("https://")) {
+                bail!("oidc.redirect_url must be an http(s) URL, got {:?}", oidc.redirect_url);
+            }
+            if !oidc.scopes.iter().any(|scope| scope == "openid") {
+                bail!("oidc.scopes must include \"openid\"");
+            }
+        }
+        if self.storage.signed_url_ttl_secs <= 0 {
+            bail!("storage.signed_url_ttl_secs must be positive");
+        }
+        if self.history.keep_last == 0 {
+            bail!("history.keep_last must be at least 1");
+        }
+        if self.collab.persist_interval_secs == 0 {
+            bail!("collab.persist_interval_secs must be greater than 0");
+        }
+        if self.gc.interval_secs == 0 {
+            bail!("gc.interval_secs must be greater than 0");
+        }
+        Ok(())
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.server.cors_origins.iter().any(|o| o == "*")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("venus-config-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn load(file: &Path, args: &[&str]) -> Result<Config> {
+        let mut argv = vec!["venus", "--config", file.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        Config::load(&Cli::try_parse_from(argv).unwrap())
+    }
+
+    #[test]
+    fn layers_file_env_and_cli() {
+        let file = config_file(
+            "layers",
+            &format!("[database]\nmax_connections = 3\n[auth]\njwt_secret = \"{}\"\ntoken_ttl_secs = 60\n", SECRET),
+        );
+
+        let config = load(&file, &[]).unwrap();
+        assert_eq!(config.database.max_connections, 3);
+        assert_eq!(config.auth.token_ttl_secs, 60);
+        // 文件里没有的保持默认值
+        assert_eq!(config.database.url, DatabaseConfig::default().url);
+
+        // 测试并行运行，不修改进程的环境变量，只检查 clap 的绑定
+        let command = Cli::command();
+        let arg = command.get_arguments().find(|arg| arg.get_id() == "database_max_connections").unwrap();
+        assert_eq!(arg.get_env().and_then(|env| env.to_str()), Some("VENUS_DATABASE_MAX_CONNECTIONS"));
+
+        let config = load(&file, &["--database-max-connections", "7", "--token-ttl", "120"]).unwrap();
+        assert_eq!(config.database.max_connections, 7);
+        assert_eq!(config.auth.token_ttl_secs, 120);
+        std::fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = load(Path::new("venus.example.toml"), &[]).unwrap();
+        // 示例里的占位密钥是注释掉的，启动时生成随机密钥
+        assert!(!config.auth.jwt_secret.starts_with(PLACEHOLDER_SECRET_PREFIX));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let cases = [
+            ("placeholder", "[auth]\njwt_secret = \"change-me-to-a-long-random-string-of-32+-chars\"\n", "placeholder"),
+            ("short", "[auth]\njwt_secret = \"short\"\n", "at least 32 characters"),
+            ("connections", "[database]\nmax_connections = 0\n", "max_connections"),
+            ("cors", "[server]\ncors_origins = [\"*\", \"https://a.example\"]\n", "cannot be combined"),
+            ("smtp", "[mail]\nbackend = \"smtp\"\n[mail.smtp]\nhost = \"mail.example.com\"\n", "public_url"),
+            ("unknown", "[server]\nport = 80\n", "failed to parse"),
+        ];
+        for (name, contents, expected) in cases {
+            let file = config_file(name, contents);
+            let error = load(&file, &[]).unwrap_err();
+            std::fs::remove_file(file).unwrap();
+            assert!(format!("{:#}", error).contains(expected), "{}: {:#}", name, error);
+        }
+
+        // 命令行的值同样要校验
+        let file = config_file("cli", "");
+        let error = load(&file, &["--database-max-connections", "0"]).unwrap_err();
+        std::fs::remove_file(file).unwrap();
+        assert!(error.to_string().contains("max_connections"));
+    }
+
+    #[test]
+    fn detects_localhost_public_url() {
+        for (url, local) in [
+            ("http://localhost:8085", true),
+            ("http://LOCALHOST", true),
+            ("http://127.0.0.1:8085/venus", true),
+            ("http://[::1]:8085", true),
+            ("https://draw.example.com", false),
+            ("http://10.0.0.5:8085", false),
+        ] {
+            let server = ServerConfig { public_url: url.to_string(), ..ServerConfig::default() };
+            assert_eq!(server.links_to_localhost(), local, "{}", url);
+        }
+    }
+}
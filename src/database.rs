@@ -2,12 +2,17 @@ use anyhow::Result;
 use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
 use std::{fs::File, path::Path};
 
+use crate::auth::token_hash;
+
+// Length of the share tokens handed out before they were stored hashed
+const LEGACY_SHARE_TOKEN_LEN: i64 = 32;
+
 pub struct Database {
     pool: SqlitePool,
 }
 
 impl Database {
-    pub async fn new(database_url: &str) -> Result<Self> {
+    pub async fn new(database_url: &str, max_connections: u32) -> Result<Self> {
         // Extract the database file path from the URL
         if let Some(db_path) = database_url.strip_prefix("sqlite:") {
             // Create parent directory if it doesn't exist
@@ -22,7 +27,7 @@ impl Database {
         }
 
         let pool = SqlitePoolOptions::new()
-            .max_connections(10)
+            .max_connections(max_connections)
             .connect(database_url)
             .await?;
 
@@ -31,11 +36,43 @@ impl Database {
 
     pub async fn migrate(&self) -> Result<()> {
         sqlx::migrate!("./migrations").run(&self.pool).await?;
+        self.hash_legacy_share_tokens().await?;
         Ok(())
     }
 
+    // SQL migrations can't hash, so share links created before tokens were hashed are converted
+    // here; their URLs keep working
+    async fn hash_legacy_share_tokens(&self) -> Result<()> {
+        let legacy: Vec<(String, String)> =
+            sqlx::query_as("SELECT id, token_hash FROM project_shares WHERE length(token_hash) = ?")
+                .bind(LEGACY_SHARE_TOKEN_LEN)
+                .fetch_all(&self.pool)
+                .await?;
+        for (id, token) in legacy {
+            sqlx::query("UPDATE project_shares SET token_hash = ? WHERE id = ?")
+                .bind(token_hash(&token))
+                .bind(&id)
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
 
     pub fn pool(&self) -> SqlitePool {
         self.pool.clone()
     }
-}
\ No newline at end of file
+}
+// A migrated in-memory database. One connection that is never closed, because each connection to
+// `sqlite::memory:` gets its own empty database.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+    pool
+}
@@ -0,0 +1,496 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use serde_json::Value;
+use std::collections::HashSet;
+
+use crate::{
+    blobs::{delete_blob, remove_image},
+    config::GcConfig,
+    models::Image,
+    scene::referenced_image_ids,
+    state::AppState,
+    thumbnail::delete_variants,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct GcOptions {
+    pub grace_period: Duration,
+    pub dry_run: bool,
+}
+
+impl GcOptions {
+    pub fn from_config(config: &GcConfig) -> Self {
+        Self {
+            grace_period: Duration::seconds(config.grace_period_secs as i64),
+            dry_run: config.dry_run,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcReport {
+    pub orphan_images: usize,
+    pub orphan_blobs: usize,
+    pub orphan_files: usize,
+    pub orphan_bytes: u64,
+    pub repaired_refcounts: usize,
+    pub missing_files: usize,
+}
+
+impl GcReport {
+    pub fn log(&self, dry_run: bool) {
+        tracing::info!(
+            "Garbage collection{}: {} orphaned images, {} orphaned blobs, {} orphaned files ({} bytes), \
+             {} reference counts repaired, {} images missing their file",
+            if dry_run { " (dry run)" } else { "" },
+            self.orphan_images,
+            self.orphan_blobs,
+            self.orphan_files,
+            self.orphan_bytes,
+            self.repaired_refcounts,
+            self.missing_files
+        );
+    }
+}
+
+// Cross-reference the images table, the stored files and the image ids used by every project
+// scene (including its saved revisions), and delete whatever has been unreachable for longer
+// than the grace period:
+//
+// - project images no scene or revision references, or whose project was deleted (images
+//   uploaded to the personal library without a project are kept)
+// - blobs no images row points at, and files in storage no row knows about
+//
+// Images whose file is missing are only reported. A dry run changes nothing, not even the
+// bookkeeping of when the grace period started.
+pub async fn collect_garbage(state: &AppState, options: GcOptions) -> Result<GcReport> {
+    let now = Utc::now();
+    let cutoff = now - options.grace_period;
+    let action = if options.dry_run { "Would delete" } else { "Deleting" };
+    let mut report = GcReport::default();
+
+    let referenced = referenced_images(state).await?;
+    let projects: HashSet<String> = sqlx::query_scalar("SELECT id FROM projects")
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .collect();
+
+    let images: Vec<(String, String, Option<DateTime<Utc>>)> =
+        sqlx::query_as("SELECT id, project_id, orphaned_at FROM images WHERE project_id IS NOT NULL")
+            .fetch_all(&state.pool)
+            .await?;
+
+    for (id, project_id, orphaned_at) in images {
+        if referenced.contains(&id) {
+            if orphaned_at.is_some() {
+                mark_orphaned(state, options, "images", "id", &id, None).await?;
+            }
+            continue;
+        }
+        // 宽限期从第一次发现没有引用时开始计算
+        let Some(orphaned_at) = orphaned_at else {
+            mark_orphaned(state, options, "images", "id", &id, Some(now)).await?;
+            continue;
+        };
+        if orphaned_at > cutoff {
+            continue;
+        }
+
+        let Some(image) = sqlx::query_as::<_, Image>("SELECT * FROM images WHERE id = ?")
+            .bind(&id)
+            .fetch_optional(&state.pool)
+            .await?
+        else {
+            continue;
+        };
+        let reason = if projects.contains(&project_id) { "not used by any scene" } else { "project deleted" };
+        tracing::info!("{} image {} ({}): {}", action, image.id, image.filename, reason);
+        report.orphan_images += 1;
+
+        if !options.dry_run {
+            purge_image(state, &image).await?;
+        }
+    }
+
+    if !options.dry_run {
+        sqlx::query("DELETE FROM image_variants WHERE image_id NOT IN (SELECT id FROM images)")
+            .execute(&state.pool)
+            .await?;
+    }
+
+    // 引用计数和 images 记录在同一个事务里修改，单条语句重新统计不会和上传或删除交错
+    let repaired: Vec<(String, i64)> = if options.dry_run {
+        sqlx::query_as(
+            r#"
+            SELECT key, (SELECT COUNT(*) FROM images i WHERE i.blob_hash = blobs.hash) AS actual FROM blobs
+            WHERE refcount != actual
+            "#
+        )
+        .fetch_all(&state.pool)
+        .await?
+    } else {
+        sqlx::query_as(
+            r#"
+            UPDATE blobs SET refcount = (SELECT COUNT(*) FROM images i WHERE i.blob_hash = blobs.hash)
+            WHERE refcount != (SELECT COUNT(*) FROM images i WHERE i.blob_hash = blobs.hash)
+            RETURNING key, refcount
+            "#
+        )
+        .fetch_all(&state.pool)
+        .await?
+    };
+    let repair = if options.dry_run { "Would repair" } else { "Repaired" };
+    for (key, refcount) in repaired {
+        tracing::info!("{} reference count of blob {}: {} images use it", repair, key, refcount);
+        report.repaired_refcounts += 1;
+    }
+
+    let blobs: Vec<(String, String, i64, Option<DateTime<Utc>>)> =
+        sqlx::query_as("SELECT hash, key, refcount, orphaned_at FROM blobs WHERE pending_since IS NULL")
+            .fetch_all(&state.pool)
+            .await?;
+
+    for (hash, key, refcount, orphaned_at) in blobs {
+        if refcount > 0 {
+            if orphaned_at.is_some() {
+                mark_orphaned(state, options, "blobs", "hash", &hash, None).await?;
+            }
+            continue;
+        }
+        let Some(orphaned_at) = orphaned_at else {
+            mark_orphaned(state, options, "blobs", "hash", &hash, Some(now)).await?;
+            continue;
+        };
+        if orphaned_at > cutoff {
+            continue;
+        }
+
+        tracing::info!("{} blob {}: no image references it", action, key);
+        report.orphan_blobs += 1;
+
+        if !options.dry_run {
+            // 先标记为删除中，删除文件期间相同内容的上传会等待而不是复用它
+            let claimed = sqlx::query(
+                "UPDATE blobs SET pending_since = ? WHERE hash = ? AND refcount = 0 AND pending_since IS NULL"
+            )
+            .bind(now)
+            .bind(&hash)
+            .execute(&state.pool)
+            .await?
+            .rows_affected();
+            if claimed > 0 {
+                delete_blob(state, &hash, &key).await?;
+            }
+        }
+    }
+
+    // 写入或删除到一半时进程退出留下的记录
+    let abandoned: Vec<(String, String)> =
+        sqlx::query_as("SELECT hash, key FROM blobs WHERE refcount = 0 AND pending_since <= ?")
+            .bind(cutoff)
+            .fetch_all(&state.pool)
+            .await?;
+    for (hash, key) in abandoned {
+        tracing::info!("{} blob {}: abandoned while being written or deleted", action, key);
+        report.orphan_blobs += 1;
+
+        if !options.dry_run {
+            let claimed = sqlx::query("UPDATE blobs SET pending_since = ? WHERE hash = ? AND pending_since <= ?")
+                .bind(now)
+                .bind(&hash)
+                .bind(cutoff)
+                .execute(&state.pool)
+                .await?
+                .rows_affected();
+            if claimed > 0 {
+                delete_blob(state, &hash, &key).await?;
+            }
+        }
+    }
+
+    let known = known_files(state).await?;
+    let entries = state.blobs.list().await?;
+
+    for entry in &entries {
+        if known.contains(&entry.key) || entry.modified.is_none_or(|modified| modified > cutoff) {
+            continue;
+        }
+
+        tracing::info!("{} file {} ({} bytes): no image or blob refers to it", action, entry.key, entry.size);
+        report.orphan_files += 1;
+        report.orphan_bytes += entry.size;
+
+        if !options.dry_run {
+            state.blobs.delete(&entry.key).await?;
+        }
+    }
+
+    let stored: HashSet<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+    let filenames: Vec<(String, String)> = sqlx::query_as("SELECT id, filename FROM images")
+        .fetch_all(&state.pool)
+        .await?;
+    for (id, filename) in filenames {
+        if !stored.contains(filename.as_str()) {
+            tracing::warn!("Image {} points at {}, which is missing from storage", id, filename);
+            report.missing_files += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+// Record when an image or blob was first found unreferenced, or clear it when it is in use again.
+// A dry run only logs the orphans whose grace period would start now.
+async fn mark_orphaned(
+    state: &AppState,
+    options: GcOptions,
+    table: &str,
+    key: &str,
+    id: &str,
+    at: Option<DateTime<Utc>>,
+) -> Result<()> {
+    if options.dry_run {
+        if at.is_some() {
+            tracing::info!("Would start the grace period of {} {}: nothing references it", table.trim_end_matches('s'), id);
+        }
+        return Ok(());
+    }
+    sqlx::query(&format!("UPDATE {} SET orphaned_at = ? WHERE {} = ?", table, key))
+        .bind(at)
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+    Ok(())
+}
+
+// Delete an images row together with its variants and, once nothing else uses it, its file
+pub async fn purge_image(state: &AppState, image: &Image) -> Result<()> {
+    let removed = remove_image(state, image).await?;
+    delete_variants(state, &image.id, removed).await
+}
+
+async fn referenced_images(state: &AppState) -> Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+
+    // 历史版本里的图片也要保留，否则恢复旧版本后图片会丢失
+    for query in [
+        "SELECT content FROM projects",
+        "SELECT r.content FROM project_revisions r JOIN projects p ON p.id = r.project_id",
+    ] {
+        let mut rows = sqlx::query_scalar::<_, String>(query).fetch(&state.pool);
+        while let Some(content) = rows.try_next().await? {
+            let scene: Value = serde_json::from_str(&content).unwrap_or_default();
+            referenced.extend(referenced_image_ids(&scene));
+        }
+    }
+
+    Ok(referenced)
+}
+
+async fn known_files(state: &AppState) -> Result<HashSet<String>> {
+    let files: Vec<String> = sqlx::query_scalar(
+        r#"
+        SELECT filename FROM images
+        UNION SELECT key FROM blobs
+        UNION SELECT filename FROM image_variants
+        "#
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(files.into_iter().collect())
+}
+
+pub async fn gc_loop(state: AppState) {
+    let options = GcOptions::from_config(&state.config.gc);
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(state.config.gc.interval_secs));
+    // 第一次 tick 立即返回，跳过它以免拖慢启动
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        match collect_garbage(&state, options).await {
+            Ok(report) => report.log(options.dry_run),
+            Err(e) => tracing::warn!("Garbage collection failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{test_image, test_project, test_state, test_user};
+    use serde_json::json;
+
+    fn options(grace_period: Duration, dry_run: bool) -> GcOptions {
+        GcOptions { grace_period, dry_run }
+    }
+
+    async fn image_ids(state: &AppState) -> Vec<String> {
+        sqlx::query_scalar("SELECT id FROM images ORDER BY id").fetch_all(&state.pool).await.unwrap()
+    }
+
+    fn scene(images: &[&Image]) -> Value {
+        let files: serde_json::Map<_, _> = images
+            .iter()
+            .map(|image| (image.id.clone(), json!({ "dataURL": format!("/api/images/{}", image.id) })))
+            .collect();
+        json!({ "elements": [], "files": files })
+    }
+
+    async fn set_content(state: &AppState, project_id: &str, content: &Value) {
+        sqlx::query("UPDATE projects SET content = ? WHERE id = ?")
+            .bind(content.to_string())
+            .bind(project_id)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+    }
+
+    // 数据库里与回收相关的所有内容和存储中的文件
+    async fn snapshot(state: &AppState) -> (Vec<String>, Vec<String>, i64, Vec<String>) {
+        let images = sqlx::query_scalar("SELECT id || ':' || COALESCE(orphaned_at, '') FROM images ORDER BY id")
+            .fetch_all(&state.pool)
+            .await
+            .unwrap();
+        let blobs = sqlx::query_scalar(
+            "SELECT hash || ':' || refcount || ':' || COALESCE(orphaned_at, '') || ':' || COALESCE(pending_since, '') FROM blobs ORDER BY hash"
+        )
+        .fetch_all(&state.pool)
+        .await
+        .unwrap();
+        let variants = sqlx::query_scalar("SELECT COUNT(*) FROM image_variants").fetch_one(&state.pool).await.unwrap();
+        let mut files: Vec<String> = state.blobs.list().await.unwrap().into_iter().map(|entry| entry.key).collect();
+        files.sort();
+        (images, blobs, variants, files)
+    }
+
+    #[tokio::test]
+    async fn collects_only_unreferenced_project_images() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+        let project = test_project(&state, uid, &json!({})).await;
+
+        let used = test_image(&state, uid, Some(&project), 10).await;
+        let in_revision = test_image(&state, uid, Some(&project), 20).await;
+        let unused = test_image(&state, uid, Some(&project), 30).await;
+        let personal = test_image(&state, uid, None, 40).await;
+        let deleted_project = test_image(&state, uid, Some("deleted"), 50).await;
+
+        set_content(&state, &project, &scene(&[&used])).await;
+        let mut conn = state.pool.acquire().await.unwrap();
+        crate::revisions::record_revision(&mut conn, &project, uid, &scene(&[&in_revision]).to_string(), Utc::now())
+            .await
+            .unwrap();
+        drop(conn);
+
+        // 第一次只记录，第二次才删除
+        let report = collect_garbage(&state, options(Duration::zero(), false)).await.unwrap();
+        assert_eq!(report.orphan_images, 0);
+        let report = collect_garbage(&state, options(Duration::zero(), false)).await.unwrap();
+        assert_eq!(report.orphan_images, 2);
+
+        let mut kept = vec![used.id, in_revision.id, personal.id];
+        kept.sort();
+        assert_eq!(image_ids(&state).await, kept);
+        assert!(state.blobs.get(&unused.filename).await.unwrap().is_none());
+        assert!(state.blobs.get(&deleted_project.filename).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_orphans_for_the_grace_period() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+        let project = test_project(&state, uid, &json!({})).await;
+        let image = test_image(&state, uid, Some(&project), 10).await;
+        let grace = options(Duration::days(1), false);
+
+        collect_garbage(&state, grace).await.unwrap();
+        collect_garbage(&state, grace).await.unwrap();
+        assert_eq!(image_ids(&state).await, vec![image.id.clone()]);
+
+        // 在宽限期内重新被引用，计时清零
+        set_content(&state, &project, &scene(&[&image])).await;
+        collect_garbage(&state, grace).await.unwrap();
+        let orphaned_at: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT orphaned_at FROM images WHERE id = ?")
+            .bind(&image.id)
+            .fetch_one(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(orphaned_at, None);
+
+        set_content(&state, &project, &json!({})).await;
+        collect_garbage(&state, grace).await.unwrap();
+        sqlx::query("UPDATE images SET orphaned_at = ?")
+            .bind(Utc::now() - Duration::days(2))
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        let report = collect_garbage(&state, grace).await.unwrap();
+        assert_eq!(report.orphan_images, 1);
+        assert!(image_ids(&state).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_abandoned_pending_blobs() {
+        let state = test_state().await;
+        let now = Utc::now();
+        for (hash, pending_since) in [("old", now - Duration::days(2)), ("recent", now)] {
+            let key = format!("{}.png", hash);
+            state.blobs.put(&key, vec![1, 2, 3], "image/png").await.unwrap();
+            sqlx::query("INSERT INTO blobs (hash, key, size, refcount, pending_since) VALUES (?, ?, 3, 0, ?)")
+                .bind(hash)
+                .bind(&key)
+                .bind(pending_since)
+                .execute(&state.pool)
+                .await
+                .unwrap();
+        }
+
+        let report = collect_garbage(&state, options(Duration::days(1), false)).await.unwrap();
+        assert_eq!(report.orphan_blobs, 1);
+
+        let blobs: Vec<String> = sqlx::query_scalar("SELECT hash FROM blobs").fetch_all(&state.pool).await.unwrap();
+        assert_eq!(blobs, ["recent"]);
+        assert!(state.blobs.get("old.png").await.unwrap().is_none());
+        assert!(state.blobs.get("recent.png").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+        let project = test_project(&state, uid, &json!({})).await;
+
+        let used = test_image(&state, uid, Some(&project), 10).await;
+        let expired = test_image(&state, uid, Some(&project), 20).await;
+        test_image(&state, uid, Some(&project), 30).await;
+        set_content(&state, &project, &scene(&[&used])).await;
+
+        let long_ago = Utc::now() - Duration::days(30);
+        for (query, id) in [
+            ("UPDATE images SET orphaned_at = ? WHERE id = ?", &used.id),
+            ("UPDATE images SET orphaned_at = ? WHERE id = ?", &expired.id),
+            ("UPDATE blobs SET refcount = 5, orphaned_at = ? WHERE hash = ?", used.blob_hash.as_ref().unwrap()),
+        ] {
+            sqlx::query(query).bind(long_ago).bind(id).execute(&state.pool).await.unwrap();
+        }
+        sqlx::query("INSERT INTO blobs (hash, key, size, refcount, pending_since) VALUES ('abandoned', 'abandoned.png', 3, 0, ?)")
+            .bind(long_ago)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        state.blobs.put("stray.png", vec![1, 2, 3], "image/png").await.unwrap();
+
+        let before = snapshot(&state).await;
+        let report = collect_garbage(&state, options(Duration::zero(), true)).await.unwrap();
+        assert_eq!(snapshot(&state).await, before);
+
+        assert_eq!(report.orphan_images, 1);
+        assert_eq!(report.repaired_refcounts, 1);
+        assert_eq!(report.orphan_blobs, 1);
+        assert_eq!(report.orphan_files, 1);
+    }
+}
@@ -1,45 +1,56 @@
 use axum::{
     extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::Json,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{IntoResponse, Json, Response},
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde_json::json;
-use sqlx::SqlitePool;
+use sqlx::SqliteConnection;
 use uuid::Uuid;
 
 use crate::{
-    auth::extract_uid_from_headers,
-    models::{CreateProjectRequest, Project, ProjectRow, ProjectSummary, UpdateProjectRequest}
+    access::require_role,
+    auth::{authenticate_with_cookie, extract_uid_from_headers},
+    models::{CreateProjectRequest, Project, ProjectRow, ProjectSummary, ProjectSummaryRow, Role, UpdateProjectRequest},
+    revisions::{prune_revisions, record_baseline, record_revision},
+    offload::{inline_files, offload_inline_files},
+    scene::{merge_scenes, normalize_content},
+    state::AppState,
+    thumbnail::render_scene_svg,
 };
 
 pub async fn get_projects(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<Vec<ProjectSummary>>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
+    let uid = extract_uid_from_headers(&state, &headers).await?;
     
-    let project_rows = sqlx::query_as::<_, ProjectRow>(
-        "SELECT id, name, content, uid, created_at, updated_at FROM projects WHERE uid = ? ORDER BY created_at DESC"
+    // 列表不读取 content，预览使用保存时生成的缩略图
+    let project_rows = sqlx::query_as::<_, ProjectSummaryRow>(
+        r#"
+        SELECT p.id, p.name, p.version, p.thumbnail IS NOT NULL AS has_thumbnail, p.updated_at
+        FROM projects p JOIN project_members m ON m.project_id = p.id
+        WHERE m.uid = ? ORDER BY p.created_at DESC
+        "#
     )
     .bind(uid)
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let summaries: Vec<ProjectSummary> = project_rows
         .into_iter()
-        .map(|row| Project::from(row).into())
+        .map(ProjectSummary::from)
         .collect();
     Ok(Json(summaries))
 }
 
 pub async fn create_project(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     Json(req): Json<CreateProjectRequest>,
 ) -> Result<Json<Project>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
+    let uid = extract_uid_from_headers(&state, &headers).await?;
     let id = Uuid::new_v4().to_string();
     let now = Utc::now();
     
@@ -49,11 +60,13 @@ pub async fn create_project(
         "files": {}
     });
 
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     let project_row = sqlx::query_as::<_, ProjectRow>(
         r#"
         INSERT INTO projects (id, name, content, uid, created_at, updated_at)
         VALUES (?, ?, ?, ?, ?, ?)
-        RETURNING id, name, content, uid, created_at, updated_at
+        RETURNING id, name, content, uid, version, created_at, updated_at
         "#
     )
     .bind(&id)
@@ -62,73 +75,234 @@ pub async fn create_project(
     .bind(uid)
     .bind(now)
     .bind(now)
-    .fetch_one(&pool)
+    .fetch_one(&mut *tx)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
+    // 初始内容作为第一个历史版本
+    record_revision(&mut tx, &id, uid, &project_row.content, now)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query("INSERT INTO project_members (project_id, uid, role, created_at) VALUES (?, ?, ?, ?)")
+        .bind(&id)
+        .bind(uid)
+        .bind(Role::Owner)
+        .bind(now)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     Ok(Json(Project::from(project_row)))
 }
 
 pub async fn get_project_by_id(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     Path(id): Path<String>,
-) -> Result<Json<Project>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
-    
+) -> Result<([(HeaderName, String); 1], Json<Project>), StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
     let project_row = sqlx::query_as::<_, ProjectRow>(
-        "SELECT id, name, content, uid, created_at, updated_at FROM projects WHERE id = ? AND uid = ?"
+        "SELECT id, name, content, uid, version, created_at, updated_at FROM projects WHERE id = ?"
     )
     .bind(&id)
-    .bind(uid)
-    .fetch_optional(&pool)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     match project_row {
-        Some(row) => Ok(Json(Project::from(row))),
+        Some(row) => Ok(([(header::ETAG, project_etag(row.version))], Json(Project::from(row)))),
         None => Err(StatusCode::NOT_FOUND),
     }
 }
 
 pub async fn update_project(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     Path(id): Path<String>,
     Json(req): Json<UpdateProjectRequest>,
-) -> Result<Json<serde_json::Value>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
-    let now = Utc::now();
-    
-    let result = sqlx::query(
-        "UPDATE projects SET content = ?, updated_at = ? WHERE id = ? AND uid = ?"
+) -> Result<Response, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+    let precondition = parse_if_match(&headers);
+
+    // 先检查 If-Match，过期的请求不转存图片
+    let current = project_version(&state.pool, &id).await?;
+    if !precondition.matches(current) {
+        return Ok(precondition_failed(current));
+    }
+
+    // 内嵌在 files 里的 base64 图片转存到图片存储，场景里只保留引用
+    let content = match offload_inline_files(&state, &id, uid, &req.content).await {
+        Ok(Some(offloaded)) => offloaded.content.to_string(),
+        Ok(None) => req.content.to_string(),
+        Err(e) => {
+            tracing::error!("Failed to offload files of project {}: {:#}", id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // 转存期间可能有人保存过，在事务里再检查一次
+    let current = project_version(&mut *tx, &id).await?;
+    if !precondition.matches(current) {
+        return Ok(precondition_failed(current));
+    }
+
+    let Some((version, rev)) = store_content(&mut tx, &id, uid, current, &content)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(precondition_failed(current));
+    };
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    prune_history(&state, &id).await;
+
+    Ok((
+        [(header::ETAG, project_etag(version))],
+        Json(json!({"status": "success", "rev": rev, "version": version})),
     )
-    .bind(req.content.to_string())
-    .bind(now)
-    .bind(&id)
-    .bind(uid)
-    .execute(&pool)
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        .into_response())
+}
 
-    if result.rows_affected() == 0 {
-        return Err(StatusCode::NOT_FOUND);
+async fn project_version<'e, E>(executor: E, id: &str) -> Result<i64, StatusCode>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query_scalar("SELECT version FROM projects WHERE id = ?")
+        .bind(id)
+        .fetch_optional(executor)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// PATCH: 与服务器上的场景按元素合并后保存，不同图形上的并发修改都会保留
+pub async fn merge_project(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<UpdateProjectRequest>,
+) -> Result<Response, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+
+    let incoming = match offload_inline_files(&state, &id, uid, &req.content).await {
+        Ok(Some(offloaded)) => offloaded.content,
+        Ok(None) => req.content,
+        Err(e) => {
+            tracing::error!("Failed to offload files of project {}: {:#}", id, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    for _ in 0..MERGE_ATTEMPTS {
+        let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let (stored, current): (String, i64) = sqlx::query_as(
+            "SELECT content, version FROM projects WHERE id = ?"
+        )
+        .bind(&id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+        let stored: serde_json::Value = serde_json::from_str(&stored).unwrap_or(json!({}));
+        let merged = merge_scenes(&stored, &incoming);
+        let content = merged.to_string();
+
+        let Some((version, rev)) = store_content(&mut tx, &id, uid, current, &content)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        else {
+            // 读取之后又有人保存，重新合并
+            continue;
+        };
+
+        tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        prune_history(&state, &id).await;
+
+        return Ok((
+            [(header::ETAG, project_etag(version))],
+            Json(json!({"status": "success", "rev": rev, "version": version, "content": merged})),
+        )
+            .into_response());
     }
 
-    Ok(Json(json!({"status": "success"})))
+    Err(StatusCode::CONFLICT)
+}
+
+// 导出为 .excalidraw 文件，图片重新内嵌为 dataURL，脱离服务器也能打开
+pub async fn export_project(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
+    let (name, content): (String, String) = sqlx::query_as("SELECT name, content FROM projects WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut scene = normalize_content(&serde_json::from_str(&content).unwrap_or(json!({})));
+    inline_files(&state, uid, &mut scene).await.map_err(|e| {
+        tracing::error!("Failed to export project {}: {}", id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let export = json!({
+        "type": "excalidraw",
+        "version": 2,
+        "source": "venus",
+        "elements": scene.get("elements").cloned().unwrap_or(json!([])),
+        "appState": scene.get("appState").cloned().unwrap_or(json!({})),
+        "files": scene.get("files").cloned().unwrap_or(json!({})),
+    });
+
+    // 文件名里去掉引号和路径分隔符等会破坏响应头的字符
+    let filename: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '"' | '\\' | '/') { '_' } else { c })
+        .collect();
+    let disposition = format!(
+        "attachment; filename=\"{}.excalidraw\"",
+        if filename.trim().is_empty() { "project" } else { filename.trim() }
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        export.to_string(),
+    )
+        .into_response())
 }
 
 pub async fn delete_project(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     Path(id): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
-    
-    let result = sqlx::query("DELETE FROM projects WHERE id = ? AND uid = ?")
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Owner).await?;
+
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let result = sqlx::query("DELETE FROM projects WHERE id = ?")
         .bind(&id)
-        .bind(uid)
-        .execute(&pool)
+        .execute(&mut *tx)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -136,5 +310,187 @@ pub async fn delete_project(
         return Err(StatusCode::NOT_FOUND);
     }
 
+    // 项目的图片由垃圾回收在宽限期之后清理
+    for table in ["project_revisions", "project_members", "project_shares"] {
+        sqlx::query(&format!("DELETE FROM {} WHERE project_id = ?", table))
+            .bind(&id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     Ok(StatusCode::NO_CONTENT)
-}
\ No newline at end of file
+}
+
+pub async fn get_project_thumbnail(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Response, StatusCode> {
+    let uid = authenticate_with_cookie(&state, &headers).await?.uid;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
+    let thumbnail: String = sqlx::query_scalar("SELECT thumbnail FROM projects WHERE id = ?")
+        .bind(&id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .flatten()
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/svg+xml"),
+            (header::CACHE_CONTROL, "private, max-age=86400"),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (header::CONTENT_SECURITY_POLICY, "default-src 'none'; style-src 'unsafe-inline'; sandbox"),
+        ],
+        thumbnail,
+    )
+        .into_response())
+}
+
+pub fn project_etag(version: i64) -> String {
+    format!("\"{}\"", version)
+}
+
+enum IfMatch {
+    Absent,
+    Any,
+    Versions(Vec<i64>),
+}
+
+impl IfMatch {
+    fn matches(&self, version: i64) -> bool {
+        match self {
+            IfMatch::Absent | IfMatch::Any => true,
+            IfMatch::Versions(versions) => versions.contains(&version),
+        }
+    }
+}
+
+fn parse_if_match(headers: &HeaderMap) -> IfMatch {
+    let Some(value) = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok()) else {
+        return IfMatch::Absent;
+    };
+
+    if value.trim() == "*" {
+        return IfMatch::Any;
+    }
+
+    // If-Match 只做强比较，弱 ETag 和无法解析的 ETag 都不会匹配，请求会得到 412
+    let versions = value
+        .split(',')
+        .filter_map(|tag| {
+            let tag = tag.trim();
+            if tag.starts_with("W/") {
+                return None;
+            }
+            tag.trim_matches('"').parse().ok()
+        })
+        .collect();
+    IfMatch::Versions(versions)
+}
+
+fn precondition_failed(current: i64) -> Response {
+    (
+        StatusCode::PRECONDITION_FAILED,
+        [(header::ETAG, project_etag(current))],
+        Json(json!({"error": "version_conflict", "version": current})),
+    )
+        .into_response()
+}
+
+const MERGE_ATTEMPTS: usize = 3;
+
+// 版本仍是 expected 时写入并记录历史版本，返回新版本号和 rev；期间有人保存过则返回 None
+pub async fn store_content(
+    conn: &mut SqliteConnection,
+    id: &str,
+    uid: i64,
+    expected: i64,
+    content: &str,
+) -> Result<Option<(i64, i64)>, sqlx::Error> {
+    let now = Utc::now();
+    let Some(version) = update_content(conn, id, expected, content, now).await? else {
+        return Ok(None);
+    };
+
+    let rev = record_revision(conn, id, uid, content, now).await?;
+    Ok(Some((version, rev)))
+}
+
+// 同 store_content，但不记录历史版本
+pub async fn update_content(
+    conn: &mut SqliteConnection,
+    id: &str,
+    expected: i64,
+    content: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let thumbnail = scene_thumbnail(content);
+    record_baseline(&mut *conn, id).await?;
+
+    sqlx::query_scalar(
+        r#"
+        UPDATE projects SET content = ?, thumbnail = ?, updated_at = ?, version = version + 1
+        WHERE id = ? AND version = ?
+        RETURNING version
+        "#
+    )
+    .bind(content)
+    .bind(thumbnail)
+    .bind(now)
+    .bind(id)
+    .bind(expected)
+    .fetch_optional(&mut *conn)
+    .await
+}
+
+pub fn scene_thumbnail(content: &str) -> Option<String> {
+    serde_json::from_str(content).ok().as_ref().and_then(render_scene_svg)
+}
+
+pub async fn prune_history(state: &AppState, id: &str) {
+    if let Err(e) = prune_revisions(&state.pool, &state.config.history, id).await {
+        tracing::warn!("Failed to prune revisions for project {}: {}", id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn if_match(value: &str) -> IfMatch {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_MATCH, HeaderValue::from_str(value).unwrap());
+        parse_if_match(&headers)
+    }
+
+    #[test]
+    fn missing_or_wildcard_if_match_matches_any_version() {
+        assert!(parse_if_match(&HeaderMap::new()).matches(7));
+        assert!(if_match("*").matches(7));
+        assert!(if_match(" * ").matches(7));
+    }
+
+    #[test]
+    fn if_match_lists_strong_versions_only() {
+        assert!(if_match("\"7\"").matches(7));
+        assert!(!if_match("\"7\"").matches(8));
+        assert!(!if_match("W/\"7\"").matches(7));
+
+        let list = if_match("\"3\", W/\"5\" ,\"9\"");
+        assert!(list.matches(3) && list.matches(9));
+        assert!(!list.matches(4) && !list.matches(5));
+    }
+
+    #[test]
+    fn unparseable_if_match_matches_nothing() {
+        assert!(!if_match("\"abc\"").matches(1));
+        assert!(!if_match("").matches(1));
+    }
+}
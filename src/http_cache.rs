@@ -0,0 +1,187 @@
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Utc};
+
+// A single byte range from a `Range: bytes=...` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    // `bytes=start-` or `bytes=start-end` (end inclusive)
+    From(u64, Option<u64>),
+    // `bytes=-n`: the last n bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // Only single ranges are supported; anything else (multiple ranges, other units, garbage)
+    // returns None and the whole file is served, which RFC 9110 allows.
+    pub fn parse(value: &str) -> Option<Self> {
+        let spec = value.trim().strip_prefix("bytes=")?.trim();
+        if spec.contains(',') {
+            return None;
+        }
+
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return end.parse().ok().filter(|&n| n > 0).map(ByteRange::Suffix);
+        }
+
+        let start = start.parse().ok()?;
+        let end = match end {
+            "" => None,
+            end => Some(end.parse().ok().filter(|&end| end >= start)?),
+        };
+        Some(ByteRange::From(start, end))
+    }
+
+    // The inclusive span of a `total`-byte file this range selects, or None when it selects
+    // nothing (416 Range Not Satisfiable)
+    pub fn resolve(self, total: u64) -> Option<(u64, u64)> {
+        if total == 0 {
+            return None;
+        }
+        match self {
+            ByteRange::From(start, _) if start >= total => None,
+            ByteRange::From(start, end) => Some((start, end.unwrap_or(u64::MAX).min(total - 1))),
+            ByteRange::Suffix(n) => Some((total.saturating_sub(n), total - 1)),
+        }
+    }
+
+    pub fn to_header(self) -> String {
+        match self {
+            ByteRange::From(start, Some(end)) => format!("bytes={}-{}", start, end),
+            ByteRange::From(start, None) => format!("bytes={}-", start),
+            ByteRange::Suffix(n) => format!("bytes=-{}", n),
+        }
+    }
+}
+
+pub fn http_date(time: DateTime<Utc>) -> String {
+    time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+// Whether a GET for a resource with this ETag and modification time can be answered with
+// 304 Not Modified. If-Modified-Since is only consulted when If-None-Match is absent.
+pub fn not_modified(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> bool {
+    if let Some(value) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        // If-None-Match 使用弱比较
+        let etag = etag.strip_prefix("W/").unwrap_or(etag);
+        return value.split(',').map(str::trim).any(|tag| {
+            tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag
+        });
+    }
+
+    match headers.get(header::IF_MODIFIED_SINCE).and_then(|v| v.to_str().ok()).and_then(parse_http_date) {
+        // HTTP 日期只精确到秒
+        Some(since) => last_modified.timestamp() <= since.timestamp(),
+        None => false,
+    }
+}
+
+// The Range to honour, if any. A range is ignored when If-Range names a different version of
+// the resource than the one about to be served.
+pub fn requested_range(headers: &HeaderMap, etag: &str, last_modified: DateTime<Utc>) -> Option<ByteRange> {
+    let range = headers.get(header::RANGE).and_then(|v| v.to_str().ok()).and_then(ByteRange::parse)?;
+
+    match headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()).map(str::trim) {
+        None => Some(range),
+        // If-Range 的 ETag 必须强匹配，弱 ETag 永远不匹配
+        Some(tag) if tag.starts_with('"') || tag.starts_with("W/") => {
+            (!tag.starts_with("W/") && tag == etag).then_some(range)
+        }
+        // 日期必须与 Last-Modified 完全相同
+        Some(date) => parse_http_date(date)
+            .filter(|date| last_modified.timestamp() == date.timestamp())
+            .map(|_| range),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(ByteRange::parse("bytes=0-99"), Some(ByteRange::From(0, Some(99))));
+        assert_eq!(ByteRange::parse(" bytes= 100- "), Some(ByteRange::From(100, None)));
+        assert_eq!(ByteRange::parse("bytes=-500"), Some(ByteRange::Suffix(500)));
+        assert_eq!(ByteRange::parse("bytes=5-5"), Some(ByteRange::From(5, Some(5))));
+
+        for invalid in ["bytes=10-5", "bytes=-0", "bytes=0-1,5-6", "items=0-1", "bytes=a-b", "bytes=", "0-1"] {
+            assert_eq!(ByteRange::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn resolves_ranges_against_the_size() {
+        assert_eq!(ByteRange::From(0, Some(99)).resolve(1000), Some((0, 99)));
+        assert_eq!(ByteRange::From(900, None).resolve(1000), Some((900, 999)));
+        // 超出末尾的部分被截断
+        assert_eq!(ByteRange::From(900, Some(5000)).resolve(1000), Some((900, 999)));
+        assert_eq!(ByteRange::From(1000, None).resolve(1000), None);
+        assert_eq!(ByteRange::Suffix(100).resolve(1000), Some((900, 999)));
+        assert_eq!(ByteRange::Suffix(5000).resolve(1000), Some((0, 999)));
+        assert_eq!(ByteRange::From(0, None).resolve(0), None);
+        assert_eq!(ByteRange::Suffix(1).resolve(0), None);
+    }
+
+    #[test]
+    fn if_none_match_compares_weakly() {
+        let modified = Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap();
+        let check = |pairs: &[(header::HeaderName, &str)]| not_modified(&headers(pairs), "\"abc\"", modified);
+
+        assert!(check(&[(header::IF_NONE_MATCH, "\"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "W/\"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "\"x\", \"abc\"")]));
+        assert!(check(&[(header::IF_NONE_MATCH, "*")]));
+        assert!(!check(&[(header::IF_NONE_MATCH, "\"other\"")]));
+        // If-None-Match 存在时忽略 If-Modified-Since
+        assert!(!check(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, "Fri, 02 Jan 2026 03:04:05 GMT"),
+        ]));
+
+        assert!(check(&[(header::IF_MODIFIED_SINCE, "Fri, 02 Jan 2026 03:04:05 GMT")]));
+        assert!(!check(&[(header::IF_MODIFIED_SINCE, "Fri, 02 Jan 2026 03:04:04 GMT")]));
+        assert!(!check(&[(header::IF_MODIFIED_SINCE, "not a date")]));
+        assert!(!check(&[]));
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let modified = Utc.with_ymd_and_hms(2026, 1, 2, 3, 4, 5).unwrap();
+        let range = |if_range: Option<&str>| {
+            let mut pairs = vec![(header::RANGE, "bytes=0-9")];
+            pairs.extend(if_range.map(|value| (header::IF_RANGE, value)));
+            requested_range(&headers(&pairs), "\"abc\"", modified)
+        };
+
+        assert_eq!(range(None), Some(ByteRange::From(0, Some(9))));
+        assert_eq!(range(Some("\"abc\"")), Some(ByteRange::From(0, Some(9))));
+        assert_eq!(range(Some("W/\"abc\"")), None);
+        assert_eq!(range(Some("\"other\"")), None);
+        assert_eq!(range(Some("Fri, 02 Jan 2026 03:04:05 GMT")), Some(ByteRange::From(0, Some(9))));
+        assert_eq!(range(Some("Fri, 02 Jan 2026 03:04:06 GMT")), None);
+        assert_eq!(range(Some("Fri, 02 Jan 2026 03:04:04 GMT")), None);
+
+        // 弱 ETag 的资源不能用 ETag 做 If-Range
+        let weak = headers(&[(header::RANGE, "bytes=0-9"), (header::IF_RANGE, "W/\"abc\"")]);
+        assert_eq!(requested_range(&weak, "W/\"abc\"", modified), None);
+        // 多个范围时返回整个文件
+        assert_eq!(requested_range(&headers(&[(header::RANGE, "bytes=0-1,4-5")]), "\"abc\"", modified), None);
+    }
+}
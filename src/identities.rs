@@ -0,0 +1,289 @@
+use anyhow::{bail, Result};
+use chrono::{Duration, Utc};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{auth::random_token, models::User, verification::normalize_email};
+
+// Don't write last_login_at on every request for identities that are checked per request
+const LAST_LOGIN_RESOLUTION_SECS: i64 = 60;
+// Accounts need an email address; those created without one get a unique address under this
+// reserved domain (RFC 2606), which never receives mail
+pub const NO_EMAIL_DOMAIN: &str = "no-email.invalid";
+
+// Why an external identity could not be mapped to a user
+#[derive(Debug, thiserror::Error)]
+pub enum IdentityError {
+    #[error("the provider did not share a verified email address")]
+    NoVerifiedEmail,
+    #[error("an account with this email address exists but has not verified it")]
+    AccountNotVerified,
+    #[error("creating accounts for new external identities is disabled")]
+    SignupDisabled,
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl IdentityError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::NoVerifiedEmail => "no_verified_email",
+            Self::AccountNotVerified => "account_not_verified",
+            Self::SignupDisabled => "signup_disabled",
+            Self::Other(_) => "failed",
+        }
+    }
+}
+
+impl From<sqlx::Error> for IdentityError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
+
+// Someone authenticated by a system venus trusts: an OpenID Connect provider or a reverse proxy
+pub struct ExternalIdentity<'a> {
+    // Who vouches for the identity, e.g. the OIDC issuer
+    pub issuer: &'a str,
+    pub subject: &'a str,
+    // Only an address the issuer has verified
+    pub verified_email: Option<&'a str>,
+    pub preferred_username: Option<&'a str>,
+    // Whether an identity without a verified address still gets an account of its own. It is then
+    // never linked to an existing one.
+    pub email_optional: bool,
+}
+
+// The user an external identity signs in as. The first time, it is linked to the account with the
+// same email address, or a new account is created when `allow_signup` is set.
+pub async fn find_or_create_user(
+    pool: &SqlitePool,
+    identity: &ExternalIdentity<'_>,
+    allow_signup: bool,
+) -> Result<User, IdentityError> {
+    let now = Utc::now();
+
+    let linked = sqlx::query_as::<_, User>(
+        r#"
+        SELECT u.id, u.username, u.email, u.password_hash, u.verified_at, u.created_at, u.updated_at
+        FROM users u JOIN user_identities i ON i.uid = u.id
+        WHERE i.issuer = ? AND i.subject = ?
+        "#
+    )
+    .bind(identity.issuer)
+    .bind(identity.subject)
+    .fetch_optional(pool)
+    .await?;
+    if let Some(user) = linked {
+        sqlx::query(
+            r#"
+            UPDATE user_identities SET email = COALESCE(?, email), last_login_at = ?
+            WHERE issuer = ? AND subject = ? AND (last_login_at IS NULL OR last_login_at < ?)
+            "#
+        )
+        .bind(identity.verified_email)
+        .bind(now)
+        .bind(identity.issuer)
+        .bind(identity.subject)
+        .bind(now - Duration::seconds(LAST_LOGIN_RESOLUTION_SECS))
+        .execute(pool)
+        .await?;
+        return Ok(user);
+    }
+
+    // 只有身份提供方确认过的邮箱才能用来关联或创建账号
+    let Some(email) = identity.verified_email.and_then(normalize_email) else {
+        if !identity.email_optional {
+            return Err(IdentityError::NoVerifiedEmail);
+        }
+        if !allow_signup {
+            return Err(IdentityError::SignupDisabled);
+        }
+        let placeholder = format!("{}@{}", Uuid::new_v4().simple(), NO_EMAIL_DOMAIN);
+        let user = create_user(pool, &placeholder, Some(identity.subject)).await?;
+        link_identity(pool, user.id, identity, None).await?;
+        return Ok(user);
+    };
+
+    // 升级时直接标记为已验证的旧账号按未验证处理
+    let existing = sqlx::query_as::<_, User>(
+        r#"
+        SELECT id, username, email, password_hash, CASE WHEN legacy_verified THEN NULL ELSE verified_at END AS verified_at,
+               created_at, updated_at
+        FROM users WHERE email = ? COLLATE NOCASE
+        "#
+    )
+    .bind(&email)
+    .fetch_optional(pool)
+    .await?;
+    let user = match existing {
+        // 否则任何人都可以先用别人的邮箱注册，等对方通过外部登录进入这个账号
+        Some(user) if user.verified_at.is_none() => return Err(IdentityError::AccountNotVerified),
+        Some(user) => user,
+        None if !allow_signup => return Err(IdentityError::SignupDisabled),
+        None => create_user(pool, &email, identity.preferred_username).await?,
+    };
+
+    link_identity(pool, user.id, identity, Some(&email)).await?;
+    Ok(user)
+}
+
+async fn link_identity(pool: &SqlitePool, uid: i64, identity: &ExternalIdentity<'_>, email: Option<&str>) -> Result<()> {
+    let now = Utc::now();
+    sqlx::query(
+        "INSERT INTO user_identities (id, uid, issuer, subject, email, created_at, last_login_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(uid)
+    .bind(identity.issuer)
+    .bind(identity.subject)
+    .bind(email)
+    .bind(now)
+    .bind(now)
+    .execute(pool)
+    .await?;
+    tracing::info!("Linked identity {} of {} to user {}", identity.subject, identity.issuer, uid);
+    Ok(())
+}
+
+// Accounts created for external identities have no password until the user sets one with a reset
+// link. The username comes from the provider when it is still free.
+async fn create_user(pool: &SqlitePool, email: &str, preferred_username: Option<&str>) -> Result<User> {
+    let base = preferred_username
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default())
+        .to_string();
+    let candidates = std::iter::once(base.clone())
+        .chain((2..10).map(|n| format!("{}-{}", base, n)))
+        .chain(std::iter::once(format!("{}-{}", base, random_token(6).to_lowercase())));
+
+    let now = Utc::now();
+    for username in candidates {
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (username, email, password_hash, verified_at, created_at, updated_at)
+            VALUES (?, ?, '', ?, ?, ?)
+            ON CONFLICT DO NOTHING
+            RETURNING id, username, email, password_hash, verified_at, created_at, updated_at
+            "#
+        )
+        .bind(&username)
+        .bind(email)
+        .bind(now)
+        .bind(now)
+        .bind(now)
+        .fetch_optional(pool)
+        .await?;
+        if let Some(user) = user {
+            tracing::info!("Created user {} ({}) for an external identity", user.id, user.username);
+            return Ok(user);
+        }
+    }
+    bail!("no free username for {}", email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+
+    const ISSUER: &str = "https://idp.example.com";
+
+    fn identity<'a>(subject: &'a str, verified_email: Option<&'a str>) -> ExternalIdentity<'a> {
+        ExternalIdentity {
+            issuer: ISSUER,
+            subject,
+            verified_email,
+            preferred_username: Some("alice"),
+            email_optional: false,
+        }
+    }
+
+    async fn add_user(pool: &SqlitePool, username: &str, email: &str, verified: bool) -> i64 {
+        let now = Utc::now();
+        sqlx::query_scalar(
+            "INSERT INTO users (username, email, password_hash, verified_at, created_at, updated_at) VALUES (?, ?, 'x', ?, ?, ?) RETURNING id"
+        )
+        .bind(username)
+        .bind(email)
+        .bind(verified.then_some(now))
+        .bind(now)
+        .bind(now)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn links_verified_account_with_same_email() {
+        let pool = test_pool().await;
+        let uid = add_user(&pool, "alice", "alice@example.com", true).await;
+
+        let user = find_or_create_user(&pool, &identity("s1", Some("Alice@Example.com")), false).await.unwrap();
+        assert_eq!(user.id, uid);
+
+        // 关联之后按 subject 查找，即使提供方不再给出邮箱
+        let user = find_or_create_user(&pool, &identity("s1", None), false).await.unwrap();
+        assert_eq!(user.id, uid);
+    }
+
+    #[tokio::test]
+    async fn refuses_unverified_email_or_account() {
+        let pool = test_pool().await;
+        add_user(&pool, "mallory", "victim@example.com", false).await;
+
+        let result = find_or_create_user(&pool, &identity("s1", None), true).await;
+        assert!(matches!(result, Err(IdentityError::NoVerifiedEmail)));
+        let result = find_or_create_user(&pool, &identity("s1", Some("victim@example.com")), true).await;
+        assert!(matches!(result, Err(IdentityError::AccountNotVerified)));
+    }
+
+    #[tokio::test]
+    async fn does_not_link_accounts_verified_by_upgrade() {
+        let pool = test_pool().await;
+        let uid = add_user(&pool, "alice", "alice@example.com", true).await;
+        sqlx::query("UPDATE users SET legacy_verified = 1 WHERE id = ?").bind(uid).execute(&pool).await.unwrap();
+
+        let result = find_or_create_user(&pool, &identity("s1", Some("alice@example.com")), true).await;
+        assert!(matches!(result, Err(IdentityError::AccountNotVerified)));
+    }
+
+    #[tokio::test]
+    async fn creates_account_only_when_signup_is_allowed() {
+        let pool = test_pool().await;
+        add_user(&pool, "alice", "someone@example.com", true).await;
+
+        let result = find_or_create_user(&pool, &identity("s1", Some("alice@example.com")), false).await;
+        assert!(matches!(result, Err(IdentityError::SignupDisabled)));
+
+        // 用户名已被占用时换一个
+        let user = find_or_create_user(&pool, &identity("s1", Some("alice@example.com")), true).await.unwrap();
+        assert_eq!(user.username, "alice-2");
+        assert!(user.password_hash.is_empty());
+        assert!(user.verified_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn proxy_identities_may_come_without_email() {
+        let pool = test_pool().await;
+        add_user(&pool, "bob", "bob@example.com", true).await;
+        let identity = ExternalIdentity {
+            issuer: "proxy",
+            subject: "bob",
+            verified_email: None,
+            preferred_username: Some("bob"),
+            email_optional: true,
+        };
+
+        assert!(matches!(
+            find_or_create_user(&pool, &identity, false).await,
+            Err(IdentityError::SignupDisabled)
+        ));
+        // 不按用户名关联已有账号
+        let user = find_or_create_user(&pool, &identity, true).await.unwrap();
+        assert_eq!(user.username, "bob-2");
+        assert!(user.email.ends_with(NO_EMAIL_DOMAIN));
+        assert_eq!(find_or_create_user(&pool, &identity, false).await.unwrap().id, user.id);
+    }
+}
@@ -1,34 +1,43 @@
 use axum::{
-    extract::{Multipart, Path, State},
+    body::Body,
+    extract::{Multipart, Path, Query, State},
     http::{header, HeaderMap, StatusCode},
     response::Response,
     Json,
 };
 use crate::{
-    auth::extract_uid_from_headers,
-    models::{Image, ImageResponse},
+    access::{can_delete_image, can_view_image, require_role},
+    auth::{authenticate_with_cookie, extract_uid_from_headers, sign_url, verify_url_signature},
+    blobs::{reference_blob, remove_image, store_blob, store_blob_file, StoredBlob},
+    http_cache::{http_date, not_modified, requested_range},
+    image_probe::{probe, ImageInfo, ImageKind},
+    models::{Image, ImageResponse, ImageVariantRow, Role, SignedImageQuery, SignedUrlRequest, SignedUrlResponse},
+    quota::{storage_used, QuotaExceeded, UploadLimit},
+    spool::{spool_field, SpoolError},
+    state::AppState,
+    storage::BlobRead,
+    thumbnail::{delete_variants, share_variants, store_variants, ImageSource},
 };
-use sqlx::SqlitePool;
-use std::{fs, path::Path as StdPath};
-use tokio::io::AsyncWriteExt;
+use anyhow::Context;
+use chrono::{Duration, Utc};
 use uuid::Uuid;
 
+const MAX_SIGNED_URL_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
 pub async fn upload_image(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     mut multipart: Multipart,
 ) -> Result<Json<ImageResponse>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
-
-    // 创建上传目录
-    let upload_dir = "uploads/images";
-    if !StdPath::new(upload_dir).exists() {
-        fs::create_dir_all(upload_dir).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
+    let uid = extract_uid_from_headers(&state, &headers).await?;
 
     let mut project_id: Option<String> = None;
 
-    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+    let limit = UploadLimit::for_user(&state, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    while let Some(mut field) = multipart.next_field().await.map_err(|e| e.status())? {
         let name = field.name().unwrap_or("").to_string();
         
         if name == "project_id" {
@@ -42,58 +51,44 @@ pub async fn upload_image(
             continue;
         }
 
+        // 上传到项目需要该项目的编辑权限
+        if let Some(project_id) = &project_id {
+            require_role(&state.pool, project_id, uid, Role::Editor).await?;
+        }
+
         let filename = field.file_name().unwrap_or("unknown").to_string();
-        let content_type = field.content_type().unwrap_or("application/octet-stream").to_string();
-        
-        let data = field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?;
-        let size = data.len() as i64;
-
-        // 生成唯一文件名
-        let id = Uuid::new_v4().to_string();
-        let ext = StdPath::new(&filename)
-            .extension()
-            .and_then(|s| s.to_str())
-            .unwrap_or("bin");
-        let stored_filename = format!("{}.{}", id, ext);
-        let file_path = format!("{}/{}", upload_dir, stored_filename);
-
-        // 保存文件
-        let mut file = tokio::fs::File::create(&file_path)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        file.write_all(&data)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-        // 获取图片尺寸（简单实现，可以使用image crate进行更精确的处理）
-        let (width, height): (Option<i32>, Option<i32>) = if content_type.starts_with("image/") {
-            // 这里可以使用image crate来获取真实的图片尺寸
-            // 暂时返回None，后续可以优化
-            (None, None)
-        } else {
-            (None, None)
+        // 边接收边写入临时文件，超过上限立即拒绝
+        let spooled = spool_field(&mut field, limit.max_bytes).await.map_err(|e| match e {
+            SpoolError::TooLarge => limit.exceeded_status(),
+            SpoolError::Read(e) => e.status(),
+            SpoolError::Io(e) => {
+                tracing::error!("Failed to spool upload: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        })?;
+
+        // 根据文件内容识别真实类型和尺寸，不信任客户端提供的 content type 和扩展名
+        let info = probe(&spooled.head).ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+
+        let stored = async {
+            let blob = store_blob_file(&state, &spooled, info.kind).await?;
+            let image = record_image(&state, &blob, spooled.size, info, &filename, project_id.as_deref(), uid).await?;
+            anyhow::Ok((blob, image))
         };
+        let (blob, image) = stored.await.map_err(|e| {
+            if e.is::<QuotaExceeded>() {
+                return StatusCode::INSUFFICIENT_STORAGE;
+            }
+            tracing::error!("Failed to store image: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
 
-        // 保存到数据库
-        let image = sqlx::query_as::<_, Image>(
-            r#"
-            INSERT INTO images (id, filename, original_name, mime_type, size, width, height, project_id, uploaded_by)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
-            RETURNING *
-            "#
-        )
-        .bind(&id)
-        .bind(&stored_filename)
-        .bind(&filename)
-        .bind(&content_type)
-        .bind(size)
-        .bind(width)
-        .bind(height)
-        .bind(project_id.as_deref())
-        .bind(uid)
-        .fetch_one(&pool)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        // SVG 没有缩略图；位图直接从临时文件解码，不再整个读进内存
+        if info.kind != ImageKind::Svg {
+            let source = ImageSource::File(spooled.path().to_path_buf());
+            add_variants(&state, &image, blob.created, source).await;
+        }
 
         return Ok(Json(image.to_response()));
     }
@@ -101,49 +96,264 @@ pub async fn upload_image(
     Err(StatusCode::BAD_REQUEST)
 }
 
+// Store image bytes and record them as a new images row. Each call creates its own row even when
+// identical bytes are already stored; the file and its variants are shared.
+pub async fn create_image(
+    state: &AppState,
+    data: &[u8],
+    info: ImageInfo,
+    original_name: &str,
+    project_id: Option<&str>,
+    uid: i64,
+) -> anyhow::Result<Image> {
+    // 按内容哈希保存文件，相同内容只存一份
+    let blob = store_blob(state, data, info.kind).await?;
+    let image = record_image(state, &blob, data.len() as u64, info, original_name, project_id, uid).await?;
+    add_variants(state, &image, blob.created, ImageSource::Bytes(data.to_vec())).await;
+    Ok(image)
+}
+
+// Insert the images row for a stored blob together with its reference to the blob
+async fn record_image(
+    state: &AppState,
+    blob: &StoredBlob,
+    size: u64,
+    info: ImageInfo,
+    original_name: &str,
+    project_id: Option<&str>,
+    uid: i64,
+) -> anyhow::Result<Image> {
+    let id = Uuid::new_v4().to_string();
+    let mut tx = state.pool.begin().await?;
+    reference_blob(&mut tx, &blob.hash).await?;
+
+    let image = sqlx::query_as::<_, Image>(
+        r#"
+        INSERT INTO images (id, filename, original_name, mime_type, size, width, height, project_id, uploaded_by, blob_hash)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#
+    )
+    .bind(&id)
+    .bind(&blob.key)
+    .bind(original_name)
+    .bind(info.kind.mime_type())
+    .bind(size as i64)
+    .bind(info.width)
+    .bind(info.height)
+    .bind(project_id)
+    .bind(uid)
+    .bind(&blob.hash)
+    .fetch_one(&mut *tx)
+    .await
+    .with_context(|| format!("failed to record image {}", id))?;
+
+    // 并发上传可能都通过了开始时的配额检查；这里已经持有写锁，按包含本条记录的用量再检查一次
+    // 超出时回滚，新写入的文件没有引用，由垃圾回收清理
+    let quota = state.config.storage.user_quota_bytes;
+    if quota > 0 && storage_used(&mut *tx, uid).await?.0 > quota {
+        return Err(QuotaExceeded.into());
+    }
+    tx.commit().await?;
+
+    Ok(image)
+}
+
+// 已有相同内容时复用它的缩略图；生成失败时仍然保留原图
+async fn add_variants(state: &AppState, image: &Image, created: bool, source: ImageSource) {
+    let variants = if created {
+        store_variants(state, image, source).await
+    } else {
+        share_variants(state, image, source).await
+    };
+    if let Err(e) = variants {
+        tracing::warn!("Failed to generate variants for image {}: {}", image.id, e);
+    }
+}
+
 pub async fn get_image(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
     Path(image_id): Path<String>,
+    Query(query): Query<SignedImageQuery>,
 ) -> Result<Response, StatusCode> {
+    // 签名 URL 无需登录，否则按上传者和项目成员鉴权
+    let uid = match (query.expires, query.sig.as_deref()) {
+        (Some(expires), Some(sig)) => {
+            let path = format!("/api/images/{}", image_id);
+            if !verify_url_signature(&state.config.auth, &path, expires, sig) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            None
+        }
+        _ => Some(authenticate_with_cookie(&state, &headers).await?.uid),
+    };
+
     // 从数据库获取图片信息
     let image = sqlx::query_as::<_, Image>(
         "SELECT * FROM images WHERE id = ?"
     )
     .bind(&image_id)
-    .fetch_optional(&pool)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     .ok_or(StatusCode::NOT_FOUND)?;
 
-    // 读取文件
-    let file_path = format!("uploads/images/{}", image.filename);
-    let file_data = tokio::fs::read(&file_path)
+    if let Some(uid) = uid {
+        let allowed = can_view_image(&state.pool, &image, uid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !allowed {
+            return Err(StatusCode::NOT_FOUND);
+        }
+    }
+
+    // 请求的尺寸不存在（原图已经足够小或是 SVG）时返回原图
+    let image = match query.size {
+        Some(size) => {
+            let variant = sqlx::query_as::<_, ImageVariantRow>(
+                "SELECT filename, mime_type, width, height FROM image_variants WHERE image_id = ? AND size = ?"
+            )
+            .bind(&image.id)
+            .bind(size)
+            .fetch_optional(&state.pool)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            match variant {
+                Some(variant) => Image {
+                    filename: variant.filename,
+                    mime_type: variant.mime_type,
+                    width: Some(variant.width),
+                    height: Some(variant.height),
+                    ..image
+                },
+                None => image,
+            }
+        }
+        None => image,
+    };
+
+    // 受保护的图片只允许浏览器私有缓存
+    image_file_response(&state, &image, &headers, "private, max-age=3600").await
+}
+
+pub async fn get_signed_image_url(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(image_id): Path<String>,
+    Query(query): Query<SignedUrlRequest>,
+) -> Result<Json<SignedUrlResponse>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    let image = sqlx::query_as::<_, Image>("SELECT * FROM images WHERE id = ?")
+        .bind(&image_id)
+        .fetch_optional(&state.pool)
         .await
-        .map_err(|_| StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    // 返回文件响应
-    let response = Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, &image.mime_type)
-        .header(header::CONTENT_LENGTH, file_data.len())
-        .header(header::CACHE_CONTROL, "public, max-age=31536000") // 缓存1年
-        .body(file_data.into())
+    let allowed = can_view_image(&state.pool, &image, uid)
+        .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !allowed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let ttl = query
+        .ttl_secs
+        .unwrap_or(state.config.storage.signed_url_ttl_secs)
+        .clamp(1, MAX_SIGNED_URL_TTL_SECS);
+    let expires_at = Utc::now() + Duration::seconds(ttl);
+    let path = format!("/api/images/{}", image.id);
+    let sig = sign_url(&state.config.auth, &path, expires_at.timestamp());
 
-    Ok(response)
+    Ok(Json(SignedUrlResponse {
+        url: format!("{}?expires={}&sig={}", path, expires_at.timestamp(), sig),
+        expires_at,
+    }))
+}
+
+// Stream an image file with validators so browsers can revalidate with 304 and fetch parts of it
+// with Range requests. Stored files never change (their names are content hashes or fresh
+// uuids), so the file name serves as a strong ETag.
+pub async fn image_file_response(
+    state: &AppState,
+    image: &Image,
+    headers: &HeaderMap,
+    cache_control: &str,
+) -> Result<Response, StatusCode> {
+    let etag = format!("\"{}\"", image.filename);
+    let last_modified = http_date(image.created_at);
+
+    if not_modified(headers, &etag, image.created_at) {
+        return Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, &etag)
+            .header(header::LAST_MODIFIED, &last_modified)
+            .header(header::CACHE_CONTROL, cache_control)
+            .body(Body::empty())
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let range = requested_range(headers, &etag, image.created_at);
+    let read = state
+        .blobs
+        .read(&image.filename, range)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to read image {}: {}", image.filename, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let (total, range, body) = match read {
+        BlobRead::Found { total, range, body } => (total, range, body),
+        BlobRead::Unsatisfiable { total } => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+                .header(header::ETAG, &etag)
+                .body(Body::empty())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut builder = Response::builder();
+    if image.mime_type == "image/svg+xml" {
+        // SVG 可以包含脚本，直接打开时禁止执行
+        builder = builder.header(header::CONTENT_SECURITY_POLICY, "default-src 'none'; style-src 'unsafe-inline'; sandbox");
+    }
+    builder = match range {
+        Some((start, end)) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, total))
+            .header(header::CONTENT_LENGTH, end - start + 1),
+        None => builder.status(StatusCode::OK).header(header::CONTENT_LENGTH, total),
+    };
+
+    builder
+        .header(header::CONTENT_TYPE, &image.mime_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from_stream(body))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
 pub async fn list_images(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
 ) -> Result<Json<Vec<ImageResponse>>, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
+    let uid = extract_uid_from_headers(&state, &headers).await?;
 
     let images = sqlx::query_as::<_, Image>(
         "SELECT * FROM images WHERE uploaded_by = ? ORDER BY created_at DESC"
     )
     .bind(uid)
-    .fetch_all(&pool)
+    .fetch_all(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -156,35 +366,36 @@ pub async fn list_images(
 }
 
 pub async fn delete_image(
-    State(pool): State<SqlitePool>,
+    State(state): State<AppState>,
     headers: HeaderMap,
     Path(image_id): Path<String>,
 ) -> Result<StatusCode, StatusCode> {
-    let uid = extract_uid_from_headers(&headers)?;
+    let uid = extract_uid_from_headers(&state, &headers).await?;
 
-    // 获取图片信息（确保用户有权限删除）
+    // 获取图片信息（确保用户有权限删除：上传者或项目 owner）
     let image = sqlx::query_as::<_, Image>(
-        "SELECT * FROM images WHERE id = ? AND uploaded_by = ?"
+        "SELECT * FROM images WHERE id = ?"
     )
     .bind(&image_id)
-    .bind(uid)
-    .fetch_optional(&pool)
+    .fetch_optional(&state.pool)
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
     .ok_or(StatusCode::NOT_FOUND)?;
 
-    // 先从数据库删除记录
-    sqlx::query("DELETE FROM images WHERE id = ?")
-        .bind(&image_id)
-        .execute(&pool)
+    let allowed = can_delete_image(&state.pool, &image, uid)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !allowed {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
-    // 然后删除文件
-    let file_path = format!("uploads/images/{}", image.filename);
-    if let Err(e) = tokio::fs::remove_file(&file_path).await {
-        // 记录错误但不返回失败，因为数据库记录已经删除
-        eprintln!("Warning: Failed to delete image file {}: {}", file_path, e);
+    // 删除记录并释放文件，只有最后一个引用被删除时才真正删除文件
+    let removed = remove_image(&state, &image).await.map_err(|e| {
+        tracing::error!("Failed to delete image {}: {}", image.id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if let Err(e) = delete_variants(&state, &image.id, removed).await {
+        tracing::warn!("Failed to delete variants of image {}: {}", image.id, e);
     }
 
     Ok(StatusCode::NO_CONTENT)
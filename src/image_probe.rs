@@ -0,0 +1,238 @@
+// Detect the real type and dimensions of uploaded images from their bytes instead of trusting
+// the client supplied content type and file extension.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Svg,
+}
+
+impl ImageKind {
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            ImageKind::Png => "image/png",
+            ImageKind::Jpeg => "image/jpeg",
+            ImageKind::Gif => "image/gif",
+            ImageKind::Webp => "image/webp",
+            ImageKind::Svg => "image/svg+xml",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageKind::Png => "png",
+            ImageKind::Jpeg => "jpg",
+            ImageKind::Gif => "gif",
+            ImageKind::Webp => "webp",
+            ImageKind::Svg => "svg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ImageInfo {
+    pub kind: ImageKind,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+}
+
+pub fn sniff(data: &[u8]) -> Option<ImageKind> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(ImageKind::Png)
+    } else if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageKind::Jpeg)
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some(ImageKind::Gif)
+    } else if data.len() >= 12 && &data[0..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some(ImageKind::Webp)
+    } else if looks_like_svg(data) {
+        Some(ImageKind::Svg)
+    } else {
+        None
+    }
+}
+
+// Returns None when the bytes are not one of the allowed image types
+pub fn probe(data: &[u8]) -> Option<ImageInfo> {
+    let kind = sniff(data)?;
+
+    let (width, height) = match kind {
+        ImageKind::Svg => svg_dimensions(data),
+        _ => match imagesize::blob_size(data) {
+            Ok(size) => (i32::try_from(size.width).ok(), i32::try_from(size.height).ok()),
+            Err(_) => (None, None),
+        },
+    };
+
+    Some(ImageInfo { kind, width, height })
+}
+
+fn svg_prefix(data: &[u8]) -> &str {
+    let head = &data[..data.len().min(4096)];
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    // 截断可能落在多字节字符中间，只取合法的 UTF-8 前缀
+    match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&head[..e.valid_up_to()]).unwrap_or_default(),
+    }
+}
+
+// The text from the root element on, when that element is <svg>. Only an XML declaration,
+// processing instructions, comments, a doctype and whitespace may come before it.
+fn svg_root(text: &str) -> Option<&str> {
+    let mut rest = text.trim_start();
+    loop {
+        let skipped = if let Some(after) = rest.strip_prefix("<?") {
+            &after[after.find("?>")? + 2..]
+        } else if let Some(after) = rest.strip_prefix("<!--") {
+            &after[after.find("-->")? + 3..]
+        } else if rest.get(..9).is_some_and(|start| start.eq_ignore_ascii_case("<!DOCTYPE")) {
+            // 内部子集中可能出现 '>'
+            let end = match (rest.find('['), rest.find('>')) {
+                (Some(open), Some(close)) if open < close => open + rest[open..].find(']')? + 1,
+                _ => 0,
+            };
+            &rest[end + rest[end..].find('>')? + 1..]
+        } else {
+            break;
+        };
+        rest = skipped.trim_start();
+    }
+
+    let after = rest.strip_prefix("<svg")?;
+    after
+        .starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .then_some(rest)
+}
+
+fn looks_like_svg(data: &[u8]) -> bool {
+    svg_root(svg_prefix(data)).is_some()
+}
+
+fn svg_dimensions(data: &[u8]) -> (Option<i32>, Option<i32>) {
+    let Some(tag) = svg_root(svg_prefix(data)) else {
+        return (None, None);
+    };
+    let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+
+    let width = svg_attr(tag, "width").and_then(svg_length);
+    let height = svg_attr(tag, "height").and_then(svg_length);
+    if width.is_some() && height.is_some() {
+        return (width, height);
+    }
+
+    // 没有 width/height 时使用 viewBox 的尺寸
+    let view_box: Vec<f64> = svg_attr(tag, "viewBox")
+        .map(|v| {
+            v.split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .filter_map(|s| s.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    match view_box.as_slice() {
+        [_, _, w, h] => (width.or(Some(w.round() as i32)), height.or(Some(h.round() as i32))),
+        _ => (width, height),
+    }
+}
+
+fn svg_attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(pos) = rest.find(name) {
+        let before = rest[..pos].chars().last();
+        let after = &rest[pos + name.len()..];
+        let after_trimmed = after.trim_start();
+
+        if before.is_some_and(char::is_whitespace) && after_trimmed.starts_with('=') {
+            let value = after_trimmed[1..].trim_start();
+            let quote = value.chars().next()?;
+            if quote == '"' || quote == '\'' {
+                let value = &value[1..];
+                return value.find(quote).map(|end| &value[..end]);
+            }
+        }
+        rest = after;
+    }
+    None
+}
+
+// Only absolute pixel lengths are meaningful; percentages and other units are ignored
+fn svg_length(value: &str) -> Option<i32> {
+    let value = value.trim();
+    let number = value.strip_suffix("px").unwrap_or(value);
+    number.parse::<f64>().ok().filter(|n| *n > 0.0).map(|n| n.round() as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+    use std::io::Cursor;
+
+    fn encode(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        let mut data = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(width, height).write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    #[test]
+    fn probes_bitmaps() {
+        let fixtures = [
+            (ImageFormat::Png, ImageKind::Png),
+            (ImageFormat::Jpeg, ImageKind::Jpeg),
+            (ImageFormat::Gif, ImageKind::Gif),
+            (ImageFormat::WebP, ImageKind::Webp),
+        ];
+        for (format, kind) in fixtures {
+            let data = encode(format, 30, 20);
+            let info = probe(&data).unwrap();
+            assert_eq!((info.kind, info.width, info.height), (kind, Some(30), Some(20)), "{:?}", format);
+
+            // 截断的文件仍能识别类型，但不一定有尺寸
+            assert_eq!(sniff(&data[..12]), Some(kind), "{:?}", format);
+            assert_eq!(probe(&data[..12]).map(|info| info.kind), Some(kind));
+        }
+
+        assert_eq!(sniff(b""), None);
+        assert_eq!(sniff(b"\x89PN"), None);
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVE"), None);
+        assert_eq!(sniff(b"GIF90a"), None);
+    }
+
+    #[test]
+    fn probes_svg() {
+        let svg = br#"<?xml version="1.0"?>
+<!-- made with <svg> tools -->
+<!DOCTYPE svg PUBLIC "-//W3C//DTD SVG 1.1//EN" "http://www.w3.org/Graphics/SVG/1.1/DTD/svg11.dtd" [
+  <!ENTITY ns "http://www.w3.org/2000/svg">
+]>
+<svg xmlns="http://www.w3.org/2000/svg" width="120px" height="80"><rect/></svg>"#;
+        let info = probe(svg).unwrap();
+        assert_eq!((info.kind, info.width, info.height), (ImageKind::Svg, Some(120), Some(80)));
+
+        let info = probe(b"\xEF\xBB\xBF  <svg viewBox=\"0 0 64.4 32\"/>").unwrap();
+        assert_eq!((info.kind, info.width, info.height), (ImageKind::Svg, Some(64), Some(32)));
+        assert_eq!(probe(b"<svg>").map(|info| info.kind), Some(ImageKind::Svg));
+    }
+
+    #[test]
+    fn rejects_documents_that_only_mention_svg() {
+        let not_svg: [&[u8]; 7] = [
+            b"<html><body><svg></svg></body></html>",
+            b"<!-- <svg> --><html></html>",
+            b"<?xml version=\"1.0\"?><svgfoo/>",
+            b"hello <svg></svg>",
+            // 截断在注释、声明或 doctype 中间
+            b"<?xml version=\"1.0\"",
+            b"<!-- <svg>",
+            b"<!DOCTYPE svg [ <!ENTITY x \">\"> <svg>",
+        ];
+        for data in not_svg {
+            assert_eq!(sniff(data), None, "{}", String::from_utf8_lossy(data));
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use anyhow::{anyhow, bail, Context, Result};
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters,
+        OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey, EncodeRsaPrivateKey},
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, LineEnding},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, fs, io::Write, path::Path};
+
+use crate::config::{Config, JwtAlgorithm};
+
+const RSA_BITS: usize = 2048;
+// `aud` of every token venus issues; other services check for it
+pub const TOKEN_AUDIENCE: &str = "venus";
+// DER of an Ed25519 SubjectPublicKeyInfo up to the 32 key bytes
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+// A key read from a PEM file: its public half as a JWK, and what it signs with when it is private
+struct KeyFile {
+    jwk: Jwk,
+    algorithm: Algorithm,
+    encoding_key: Option<EncodingKey>,
+}
+
+// The registered claims venus adds to everything it signs
+#[derive(Serialize)]
+struct Registered<'a, T> {
+    iss: &'a str,
+    aud: &'a str,
+    #[serde(flatten)]
+    claims: &'a T,
+}
+
+// Keys venus signs access tokens with and accepts them from. Each key is identified by its RFC 7638
+// thumbprint, sent as `kid` in the token header. Tokens name server.public_url as their issuer.
+pub struct JwtKeys {
+    issuer: String,
+    algorithm: Algorithm,
+    kid: Option<String>,
+    encoding_key: EncodingKey,
+    // 按 kid 查找；HS256 的密钥没有 kid，存在空字符串下
+    decoding_keys: HashMap<String, (Algorithm, DecodingKey)>,
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    pub fn load(config: &Config) -> Result<Self> {
+        let issuer = config.server.public_url.trim_end_matches('/').to_string();
+        let config = &config.auth;
+        let mut keys = match config.jwt_algorithm {
+            JwtAlgorithm::Hs256 => Self {
+                issuer,
+                algorithm: Algorithm::HS256,
+                kid: None,
+                encoding_key: EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+                decoding_keys: HashMap::from([(
+                    String::new(),
+                    (Algorithm::HS256, DecodingKey::from_secret(config.jwt_secret.as_bytes())),
+                )]),
+                jwks: JwkSet { keys: Vec::new() },
+            },
+            algorithm => {
+                let path = &config.signing_key_file;
+                if !path.exists() {
+                    generate_key(algorithm, path)?;
+                    tracing::info!("Generated a new token signing key in {}", path.display());
+                }
+                let key = read_key(path)?;
+                let expected = match algorithm {
+                    JwtAlgorithm::Rs256 => Algorithm::RS256,
+                    _ => Algorithm::EdDSA,
+                };
+                if key.algorithm != expected {
+                    bail!("{} does not hold an {:?} key as auth.jwt_algorithm asks for", path.display(), expected);
+                }
+                let encoding_key = key
+                    .encoding_key
+                    .with_context(|| format!("{} is a public key, signing needs the private key", path.display()))?;
+                let mut keys = Self {
+                    issuer,
+                    algorithm: key.algorithm,
+                    kid: key.jwk.common.key_id.clone(),
+                    encoding_key,
+                    decoding_keys: HashMap::new(),
+                    jwks: JwkSet { keys: Vec::new() },
+                };
+                keys.add_verification_key(key.jwk)?;
+                keys
+            }
+        };
+
+        for path in &config.verification_key_files {
+            let key = read_key(path)?;
+            keys.add_verification_key(key.jwk)?;
+        }
+        tracing::info!(
+            "Signing access tokens with {:?}{}, accepting {} key(s)",
+            keys.algorithm,
+            keys.kid.as_deref().map(|kid| format!(" key {}", kid)).unwrap_or_default(),
+            keys.decoding_keys.len()
+        );
+
+        Ok(keys)
+    }
+
+    fn add_verification_key(&mut self, jwk: Jwk) -> Result<()> {
+        let kid = jwk.common.key_id.clone().unwrap_or_default();
+        if self.decoding_keys.contains_key(&kid) {
+            return Ok(());
+        }
+        let algorithm = match &jwk.algorithm {
+            AlgorithmParameters::RSA(_) => Algorithm::RS256,
+            _ => Algorithm::EdDSA,
+        };
+        self.decoding_keys.insert(kid, (algorithm, DecodingKey::from_jwk(&jwk)?));
+        self.jwks.keys.push(jwk);
+        Ok(())
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        let claims = Registered {
+            iss: &self.issuer,
+            aud: TOKEN_AUDIENCE,
+            claims,
+        };
+        encode(&header, &claims, &self.encoding_key)
+    }
+
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T> {
+        let header = decode_header(token)?;
+        let (algorithm, key) = self
+            .decoding_keys
+            .get(header.kid.as_deref().unwrap_or_default())
+            .ok_or_else(|| anyhow!("unknown signing key {:?}", header.kid))?;
+        // 算法取自密钥而不是令牌头，防止用公钥冒充 HMAC 密钥
+        let mut validation = Validation::new(*algorithm);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[TOKEN_AUDIENCE]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        Ok(decode::<T>(token, key, &validation)?.claims)
+    }
+
+    // Public keys for other services to verify venus's tokens with
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+}
+
+fn read_key(path: &Path) -> Result<KeyFile> {
+    let raw = fs::read(path).with_context(|| format!("failed to read key file {}", path.display()))?;
+    let pem = pem::parse(&raw).with_context(|| format!("{} is not a PEM file", path.display()))?;
+    let der = pem.contents();
+
+    let key = match pem.tag() {
+        "PRIVATE KEY" => match Ed25519KeyPair::from_pkcs8_maybe_unchecked(der) {
+            Ok(pair) => ed25519_key(pair.public_key().as_ref(), Some(EncodingKey::from_ed_der(der))),
+            Err(_) => rsa_private_key(RsaPrivateKey::from_pkcs8_der(der)?)?,
+        },
+        "RSA PRIVATE KEY" => rsa_private_key(RsaPrivateKey::from_pkcs1_der(der)?)?,
+        "PUBLIC KEY" => match der.strip_prefix(&ED25519_SPKI_PREFIX[..]) {
+            Some(public_key) if public_key.len() == 32 => ed25519_key(public_key, None),
+            _ => rsa_key(&RsaPublicKey::from_public_key_der(der)?, None)?,
+        },
+        "RSA PUBLIC KEY" => rsa_key(&RsaPublicKey::from_pkcs1_der(der)?, None)?,
+        tag => bail!("{} holds a {}, expected an Ed25519 or RSA key", path.display(), tag),
+    };
+    Ok(key)
+}
+
+fn ed25519_key(public_key: &[u8], encoding_key: Option<EncodingKey>) -> KeyFile {
+    let x = BASE64URL_NOPAD.encode(public_key);
+    let kid = thumbprint(&format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x));
+    KeyFile {
+        jwk: Jwk {
+            common: common_parameters(kid, KeyAlgorithm::EdDSA),
+            algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x,
+            }),
+        },
+        algorithm: Algorithm::EdDSA,
+        encoding_key,
+    }
+}
+
+fn rsa_private_key(key: RsaPrivateKey) -> Result<KeyFile> {
+    let encoding_key = EncodingKey::from_rsa_der(key.to_pkcs1_der()?.as_bytes());
+    rsa_key(&key.to_public_key(), Some(encoding_key))
+}
+
+fn rsa_key(public_key: &RsaPublicKey, encoding_key: Option<EncodingKey>) -> Result<KeyFile> {
+    if public_key.size() * 8 < RSA_BITS {
+        bail!("RSA keys must have at least {} bits", RSA_BITS);
+    }
+    let n = BASE64URL_NOPAD.encode(&public_key.n().to_bytes_be());
+    let e = BASE64URL_NOPAD.encode(&public_key.e().to_bytes_be());
+    let kid = thumbprint(&format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, e, n));
+    Ok(KeyFile {
+        jwk: Jwk {
+            common: common_parameters(kid, KeyAlgorithm::RS256),
+            algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                key_type: RSAKeyType::RSA,
+                n,
+                e,
+            }),
+        },
+        algorithm: Algorithm::RS256,
+        encoding_key,
+    })
+}
+
+fn common_parameters(kid: String, algorithm: KeyAlgorithm) -> CommonParameters {
+    CommonParameters {
+        public_key_use: Some(PublicKeyUse::Signature),
+        key_algorithm: Some(algorithm),
+        key_id: Some(kid),
+        ..Default::default()
+    }
+}
+
+// RFC 7638: SHA-256 of the required members in lexicographic order, without whitespace
+fn thumbprint(canonical_jwk: &str) -> String {
+    BASE64URL_NOPAD.encode(&Sha256::digest(canonical_jwk.as_bytes()))
+}
+
+fn generate_key(algorithm: JwtAlgorithm, path: &Path) -> Result<()> {
+    let pem = match algorithm {
+        JwtAlgorithm::Rs256 => RsaPrivateKey::new(&mut rand::thread_rng(), RSA_BITS)?
+            .to_pkcs8_pem(LineEnding::LF)?
+            .to_string(),
+        _ => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| anyhow!("failed to generate an Ed25519 key"))?;
+            pem::encode(&pem::Pem::new("PRIVATE KEY", pkcs8.as_ref()))
+        }
+    };
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .with_context(|| format!("failed to write key file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kid_is_rfc7638_thumbprint() {
+        // RFC 7638, section 3.1
+        let n = "0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw";
+        let public_key = RsaPublicKey::new(
+            rsa::BigUint::from_bytes_be(&BASE64URL_NOPAD.decode(n.as_bytes()).unwrap()),
+            rsa::BigUint::from(65537u32),
+        )
+        .unwrap();
+
+        let key = rsa_key(&public_key, None).unwrap();
+        assert_eq!(key.jwk.common.key_id.as_deref(), Some("NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs"));
+    }
+
+    #[derive(Debug, Serialize, serde::Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    // 固定的过期时间，前后两次生成的 claims 才能相等
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "42".to_string(),
+            exp: 4_102_444_800, // 2100-01-01
+        }
+    }
+
+    // A fresh directory for key files under the system temp dir
+    fn key_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("venus-jwt-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(algorithm: JwtAlgorithm, signing_key_file: &Path, verification_key_files: &[&Path]) -> Config {
+        let mut config = Config::default();
+        config.server.public_url = "https://draw.example.com/".to_string();
+        config.auth.jwt_secret = "0123456789abcdef0123456789abcdef".to_string();
+        config.auth.jwt_algorithm = algorithm;
+        config.auth.signing_key_file = signing_key_file.to_path_buf();
+        config.auth.verification_key_files = verification_key_files.iter().map(|path| path.to_path_buf()).collect();
+        config
+    }
+
+    #[test]
+    fn round_trips_with_every_algorithm() {
+        let dir = key_dir("round-trip");
+        for (algorithm, file) in [
+            (JwtAlgorithm::EdDSA, "ed25519.pem"),
+            (JwtAlgorithm::Rs256, "rsa.pem"),
+            (JwtAlgorithm::Hs256, "unused.pem"),
+        ] {
+            let keys = JwtKeys::load(&config(algorithm, &dir.join(file), &[])).unwrap();
+            let token = keys.sign(&claims()).unwrap();
+            assert_eq!(keys.verify::<TestClaims>(&token).unwrap(), claims());
+
+            // 令牌带有签发者和受众
+            let payload = token.split('.').nth(1).unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&BASE64URL_NOPAD.decode(payload.as_bytes()).unwrap()).unwrap();
+            assert_eq!(payload["iss"], "https://draw.example.com");
+            assert_eq!(payload["aud"], TOKEN_AUDIENCE);
+        }
+        // 已有的密钥文件被重新使用而不是覆盖
+        let first = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &dir.join("ed25519.pem"), &[])).unwrap();
+        let second = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &dir.join("ed25519.pem"), &[])).unwrap();
+        assert_eq!(first.kid, second.kid);
+        assert!(JwtKeys::load(&config(JwtAlgorithm::Rs256, &dir.join("ed25519.pem"), &[])).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_issuers_and_audiences() {
+        let dir = key_dir("issuer");
+        let keys = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &dir.join("key.pem"), &[])).unwrap();
+
+        let mut other = config(JwtAlgorithm::EdDSA, &dir.join("key.pem"), &[]);
+        other.server.public_url = "https://other.example.com".to_string();
+        let other = JwtKeys::load(&other).unwrap();
+        assert!(keys.verify::<TestClaims>(&other.sign(&claims()).unwrap()).is_err());
+
+        // 同一把密钥签发的、没有 venus 受众的令牌
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = keys.kid.clone();
+        let foreign = encode(&header, &claims(), &keys.encoding_key).unwrap();
+        assert!(keys.verify::<TestClaims>(&foreign).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_keys_still_verify() {
+        let dir = key_dir("rotation");
+        let (current, retired) = (dir.join("current.pem"), dir.join("retired.pem"));
+        let old_keys = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &current, &[])).unwrap();
+        let old_token = old_keys.sign(&claims()).unwrap();
+
+        fs::rename(&current, &retired).unwrap();
+        let keys = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &current, &[&retired])).unwrap();
+        assert_ne!(keys.kid, old_keys.kid);
+        assert_eq!(keys.jwks().keys.len(), 2);
+        assert_eq!(keys.verify::<TestClaims>(&old_token).unwrap(), claims());
+        assert_eq!(keys.verify::<TestClaims>(&keys.sign(&claims()).unwrap()).unwrap(), claims());
+
+        // 不再列出的旧密钥和从未见过的密钥都被拒绝
+        let without_retired = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &current, &[])).unwrap();
+        assert!(without_retired.verify::<TestClaims>(&old_token).is_err());
+        let stranger = JwtKeys::load(&config(JwtAlgorithm::EdDSA, &dir.join("stranger.pem"), &[])).unwrap();
+        assert!(keys.verify::<TestClaims>(&stranger.sign(&claims()).unwrap()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
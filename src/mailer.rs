@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+use crate::config::{MailBackend, MailConfig, SmtpSecurity};
+
+// A plain-text email to a single recipient
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+pub fn build(config: &MailConfig) -> Result<Arc<dyn Mailer>> {
+    let from: Mailbox = config.from.parse().context("invalid mail.from address")?;
+    let mailer: Arc<dyn Mailer> = match config.backend {
+        MailBackend::Log => Arc::new(LogMailer),
+        MailBackend::File => Arc::new(FileMailer::new(config.dir.clone(), from)?),
+        MailBackend::Smtp => Arc::new(SmtpMailer::new(config, from)?),
+    };
+    Ok(mailer)
+}
+
+// How long a link in an email stays valid, in words. Uses the largest unit that states it exactly,
+// so "90 minutes" rather than a rounded "1 hour".
+pub fn format_duration(secs: i64) -> String {
+    let (count, unit) = [(24 * 60 * 60, "day"), (60 * 60, "hour"), (60, "minute")]
+        .into_iter()
+        .find(|(unit_secs, _)| secs >= *unit_secs && secs % unit_secs == 0)
+        .map(|(unit_secs, unit)| (secs / unit_secs, unit))
+        .unwrap_or((secs, "second"));
+    format!("{} {}{}", count, unit, if count == 1 { "" } else { "s" })
+}
+
+fn message(from: &Mailbox, email: &Email) -> Result<Message> {
+    let to: Mailbox = email.to.parse().with_context(|| format!("invalid recipient {:?}", email.to))?;
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())
+        .context("failed to build email")
+}
+
+pub struct LogMailer;
+
+#[async_trait]
+impl Mailer for LogMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        tracing::info!("Email to {}: {}\n{}", email.to, email.subject, email.body);
+        Ok(())
+    }
+}
+
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: Mailbox) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create mail directory {}", dir.display()))?;
+        Ok(Self { dir, from })
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = message(&self.from, email)?;
+        // 文件名按时间排序，方便找到最新的邮件
+        let path = self.dir.join(format!("{}-{}.eml", Utc::now().format("%Y%m%dT%H%M%S%.3f"), Uuid::new_v4()));
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        tracing::info!("Email to {} written to {}", email.to, path.display());
+        Ok(())
+    }
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn new(config: &MailConfig, from: Mailbox) -> Result<Self> {
+        let smtp = &config.smtp;
+        let builder = match smtp.security {
+            SmtpSecurity::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)?,
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)?,
+            SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+        };
+        let mut builder = builder.port(smtp.port);
+        if !smtp.username.is_empty() {
+            builder = builder.credentials(Credentials::new(smtp.username.clone(), smtp.password.clone()));
+        }
+        Ok(Self { transport: builder.build(), from })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = message(&self.from, email)?;
+        self.transport.send(message).await.context("SMTP delivery failed")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_stated_exactly() {
+        assert_eq!(format_duration(24 * 60 * 60), "1 day");
+        assert_eq!(format_duration(3 * 24 * 60 * 60), "3 days");
+        assert_eq!(format_duration(60 * 60), "1 hour");
+        assert_eq!(format_duration(90 * 60), "90 minutes");
+        assert_eq!(format_duration(30 * 60), "30 minutes");
+        assert_eq!(format_duration(45), "45 seconds");
+        assert_eq!(format_duration(61), "61 seconds");
+    }
+}
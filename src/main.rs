@@ -1,26 +1,89 @@
+mod access;
+mod api_token_handlers;
+mod api_tokens;
 mod auth;
 mod auth_handlers;
+mod blobs;
+mod collab;
+mod collab_handlers;
+mod config;
 mod database;
+mod gc;
 mod handlers;
+mod http_cache;
 mod image_handlers;
+mod identities;
+mod image_probe;
+mod jwt_keys;
+mod mailer;
+mod maintenance;
+mod member_handlers;
 mod models;
+mod offload;
+mod oidc;
+mod oidc_handlers;
+mod password_reset;
+mod proxy;
+mod quota;
+mod revision_handlers;
+mod revisions;
+mod s3;
+mod scene;
+mod session_handlers;
+mod sessions;
+mod share_handlers;
+mod spool;
+mod state;
+mod storage;
+mod thumbnail;
+mod totp;
+mod two_factor;
+mod two_factor_handlers;
+mod verification;
 
 use axum::{
-    extract::Request,
+    extract::{DefaultBodyLimit, Request},
     http::{header, StatusCode},
+    middleware,
     response::{Html, IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
+use clap::Parser;
 use rust_embed::RustEmbed;
-use tower_http::cors::{Any, CorsLayer};
-use tracing_subscriber;
+use std::{net::SocketAddr, sync::Arc};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 
 use crate::{
-    auth_handlers::{get_current_user, login, register},
+    api_token_handlers::{create_api_token, list_api_tokens, revoke_api_token},
+    api_tokens::restrict_api_tokens,
+    auth_handlers::{
+        change_password, forgot_password, get_current_user, get_jwks, get_storage_usage, login, login_two_factor, logout, refresh,
+        register,
+        resend_verification, reset_password, verify_email,
+    },
+    collab::{persist_loop, Rooms},
+    collab_handlers::project_ws,
+    config::{Cli, Command, Config, StorageConfig},
     database::Database,
-    handlers::{create_project, delete_project, get_project_by_id, get_projects, update_project},
-    image_handlers::{upload_image, get_image, list_images, delete_image},
+    gc::gc_loop,
+    handlers::{
+        create_project, delete_project, export_project, get_project_by_id, get_project_thumbnail, get_projects, merge_project,
+        update_project,
+    },
+    image_handlers::{upload_image, get_image, get_signed_image_url, list_images, delete_image},
+    jwt_keys::JwtKeys,
+    member_handlers::{add_member, list_members, remove_member, update_member},
+    oidc_handlers::{get_oidc_provider, oidc_callback, oidc_login, oidc_token},
+    proxy::{trust_proxy, ReverseProxy},
+    revision_handlers::{get_revision, list_revisions, restore_revision},
+    session_handlers::{list_sessions, revoke_other_sessions, revoke_session},
+    share_handlers::{create_share, get_shared, get_shared_image, list_shares, revoke_share, unlock_share},
+    state::AppState,
+    two_factor_handlers::{
+        disable_two_factor, enable_two_factor, get_two_factor_status, regenerate_recovery_codes, setup_two_factor,
+    },
+    verification::restrict_unverified,
 };
 
 #[derive(RustEmbed)]
@@ -31,21 +94,91 @@ struct Assets;
 async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt::init();
 
-    let database = Database::new("sqlite:./venus.db").await?;
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+
+    let blobs = storage::build(&config.storage)?;
+    let mailer = mailer::build(&config.mail)?;
+    let oidc = oidc::build(&config.oidc)?;
+    let jwt_keys = Arc::new(JwtKeys::load(&config)?);
+    let proxy = Arc::new(ReverseProxy::new(&config)?);
+
+    let database = Database::new(&config.database.url, config.database.max_connections).await?;
     database.migrate().await?;
 
-    let pool = database.pool();
+    let state = AppState {
+        pool: database.pool(),
+        config: Arc::new(config),
+        jwt_keys,
+        rooms: Rooms::default(),
+        blobs,
+        mailer,
+        oidc,
+        proxy,
+    };
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(state).await,
+        Command::BackfillImages => maintenance::backfill_images(&state).await,
+        Command::BackfillThumbnails => maintenance::backfill_thumbnails(&state).await,
+        Command::OffloadFiles => maintenance::offload_files(&state).await,
+        Command::Gc { dry_run, grace_period_secs } => maintenance::gc(&state, dry_run, grace_period_secs).await,
+    }
+}
+
+async fn serve(state: AppState) -> anyhow::Result<()> {
+    tokio::spawn(persist_loop(state.clone()));
+    if state.config.gc.enabled {
+        tokio::spawn(gc_loop(state.clone()));
+    }
+
+    let allow_origin = if state.config.allows_any_origin() {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            state
+                .config
+                .server
+                .cors_origins
+                .iter()
+                .map(|origin| origin.parse())
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
 
     let cors = CorsLayer::new()
-        .allow_origin(Any)
+        .allow_origin(allow_origin)
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([header::ETAG, header::CONTENT_RANGE, header::ACCEPT_RANGES]);
 
     let auth_routes = Router::new()
         .route("/register", post(register))
         .route("/login", post(login))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+        .route("/password", post(change_password))
+        .route("/password/forgot", post(forgot_password))
+        .route("/password/reset", post(reset_password))
+        .route("/verify", post(verify_email))
+        .route("/verify/resend", post(resend_verification))
+        .route("/2fa", get(get_two_factor_status))
+        .route("/2fa/setup", post(setup_two_factor))
+        .route("/2fa/enable", post(enable_two_factor))
+        .route("/2fa/disable", post(disable_two_factor))
+        .route("/2fa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/2fa/verify", post(login_two_factor))
+        .route("/oidc", get(get_oidc_provider))
+        .route("/oidc/login", get(oidc_login))
+        .route("/oidc/callback", get(oidc_callback))
+        .route("/oidc/token", post(oidc_token))
         .route("/user", get(get_current_user))
-        .with_state(pool.clone());
+        .route("/user/usage", get(get_storage_usage))
+        .route("/sessions", get(list_sessions).delete(revoke_other_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/tokens", get(list_api_tokens).post(create_api_token))
+        .route("/tokens/:id", delete(revoke_api_token))
+        .with_state(state.clone());
 
     let api_routes = Router::new()
         .route("/projects", get(get_projects).post(create_project))
@@ -53,28 +186,62 @@ async fn main() -> anyhow::Result<()> {
             "/projects/:id",
             get(get_project_by_id)
                 .put(update_project)
+                .patch(merge_project)
                 .delete(delete_project),
         )
-        .route("/images", post(upload_image).get(list_images))
+        .route("/projects/:id/thumbnail", get(get_project_thumbnail))
+        .route("/projects/:id/export", get(export_project))
+        .route("/projects/:id/members", get(list_members).post(add_member))
+        .route("/projects/:id/members/:uid", put(update_member).delete(remove_member))
+        .route("/projects/:id/shares", get(list_shares).post(create_share))
+        .route("/projects/:id/shares/:share_id", delete(revoke_share))
+        .route("/shared/:token", get(get_shared))
+        .route("/shared/:token/access", post(unlock_share))
+        .route("/shared/:token/images/:image_id", get(get_shared_image))
+        .route("/projects/:id/ws", get(project_ws))
+        .route("/projects/:id/revisions", get(list_revisions))
+        .route("/projects/:id/revisions/:rev", get(get_revision))
+        .route("/projects/:id/revisions/:rev/restore", post(restore_revision))
+        .route(
+            "/images",
+            post(upload_image)
+                .get(list_images)
+                .layer(DefaultBodyLimit::max(upload_body_limit(&state.config.storage))),
+        )
         .route("/images/:id", get(get_image).delete(delete_image))
-        .with_state(pool.clone());
+        .route("/images/:id/signed-url", get(get_signed_image_url))
+        .route_layer(middleware::from_fn_with_state(state.clone(), restrict_unverified))
+        .with_state(state.clone());
 
     let app = Router::new()
         .route("/", get(serve_index))
+        .route("/.well-known/jwks.json", get(get_jwks).with_state(state.clone()))
         .nest("/api/auth", auth_routes)
         .nest("/api", api_routes)
         .fallback(serve_static_handler)
+        .layer(middleware::from_fn_with_state(state.clone(), restrict_api_tokens))
+        .layer(middleware::from_fn_with_state(state.clone(), trust_proxy))
         .layer(cors);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8085").await?;
+    let bind = state.config.server.bind;
+    let listener = tokio::net::TcpListener::bind(bind).await?;
 
-    tracing::info!("Server running on http://0.0.0.0:8085");
+    tracing::info!("Server running on http://{}", bind);
 
-    axum::serve(listener, app).await?;
+    // 会话列表需要记录客户端地址，也用来判断请求是否来自可信的反向代理
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
 
+// The upload handler enforces the per-file limit itself; this only bounds the whole multipart
+// body, leaving room for the other fields and boundaries.
+fn upload_body_limit(storage: &StorageConfig) -> usize {
+    usize::try_from(storage.max_upload_bytes)
+        .unwrap_or(usize::MAX)
+        .saturating_add(1024 * 1024)
+}
+
 async fn serve_static_handler(request: Request) -> Response {
     let path = request.uri().path().trim_start_matches('/');
 
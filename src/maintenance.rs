@@ -0,0 +1,195 @@
+use anyhow::{anyhow, Result};
+use chrono::Duration;
+use serde_json::Value;
+
+use crate::{
+    gc::{collect_garbage, GcOptions},
+    handlers::scene_thumbnail,
+    image_probe::probe,
+    models::Image,
+    offload::offload_inline_files,
+    revisions::content_hash,
+    state::AppState,
+    thumbnail::{store_variants, ImageSource},
+};
+
+// Re-read every stored image, fill in missing dimensions and correct the stored MIME type.
+// Rows whose file is missing or is not a recognised image are reported and left untouched.
+pub async fn backfill_images(state: &AppState) -> Result<()> {
+    let images = sqlx::query_as::<_, Image>("SELECT * FROM images ORDER BY created_at")
+        .fetch_all(&state.pool)
+        .await?;
+
+    let (mut updated, mut skipped) = (0, 0);
+
+    for image in &images {
+        let data = match state.blobs.get(&image.filename).await {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                tracing::warn!("Image {}: {} is missing from storage", image.id, image.filename);
+                skipped += 1;
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Image {}: cannot read {}: {}", image.id, image.filename, e);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let Some(info) = probe(&data) else {
+            tracing::warn!("Image {}: {} is not a supported image type", image.id, image.filename);
+            skipped += 1;
+            continue;
+        };
+
+        let width = image.width.or(info.width);
+        let height = image.height.or(info.height);
+        let mime_type = info.kind.mime_type();
+
+        if width == image.width && height == image.height && mime_type == image.mime_type {
+            continue;
+        }
+
+        sqlx::query("UPDATE images SET width = ?, height = ?, mime_type = ? WHERE id = ?")
+            .bind(width)
+            .bind(height)
+            .bind(mime_type)
+            .bind(&image.id)
+            .execute(&state.pool)
+            .await?;
+        updated += 1;
+    }
+
+    tracing::info!(
+        "Backfilled image metadata: {} checked, {} updated, {} skipped",
+        images.len(),
+        updated,
+        skipped
+    );
+    Ok(())
+}
+
+// Generate resized variants for images uploaded before variants existed and render thumbnails
+// for projects that have not been saved since.
+pub async fn backfill_thumbnails(state: &AppState) -> Result<()> {
+    let images = sqlx::query_as::<_, Image>(
+        "SELECT * FROM images WHERE id NOT IN (SELECT image_id FROM image_variants) ORDER BY created_at"
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut variants = 0;
+    for image in &images {
+        let result = match state.blobs.get(&image.filename).await {
+            Ok(Some(data)) => store_variants(state, image, ImageSource::Bytes(data)).await,
+            Ok(None) => Err(anyhow!("{} is missing from storage", image.filename)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(count) => variants += count,
+            Err(e) => tracing::warn!("Image {}: cannot generate variants: {}", image.id, e),
+        }
+    }
+
+    let projects: Vec<(String, String)> =
+        sqlx::query_as("SELECT id, content FROM projects WHERE thumbnail IS NULL")
+            .fetch_all(&state.pool)
+            .await?;
+
+    let mut thumbnails = 0;
+    for (id, content) in &projects {
+        let Some(thumbnail) = scene_thumbnail(content) else {
+            continue;
+        };
+        sqlx::query("UPDATE projects SET thumbnail = ? WHERE id = ?")
+            .bind(thumbnail)
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+        thumbnails += 1;
+    }
+
+    tracing::info!(
+        "Backfilled thumbnails: {} image variants for {} images, {} of {} project thumbnails",
+        variants,
+        images.len(),
+        thumbnails,
+        projects.len()
+    );
+    Ok(())
+}
+
+pub async fn gc(state: &AppState, dry_run: bool, grace_period_secs: Option<u64>) -> Result<()> {
+    let mut options = GcOptions::from_config(&state.config.gc);
+    options.dry_run = dry_run;
+    if let Some(secs) = grace_period_secs {
+        options.grace_period = Duration::seconds(secs as i64);
+    }
+
+    collect_garbage(state, options).await?.log(dry_run);
+    Ok(())
+}
+
+// Move images embedded as base64 data URLs in existing projects and their revisions into the
+// image store. Projects keep their version; this only changes how the same scene is stored.
+pub async fn offload_files(state: &AppState) -> Result<()> {
+    let projects: Vec<(String, i64, String)> = sqlx::query_as("SELECT id, uid, content FROM projects")
+        .fetch_all(&state.pool)
+        .await?;
+
+    let (mut files, mut updated) = (0, 0);
+    for (id, uid, content) in &projects {
+        let Ok(content) = serde_json::from_str::<Value>(content) else {
+            continue;
+        };
+        let Some(offloaded) = offload_inline_files(state, id, *uid, &content).await? else {
+            continue;
+        };
+
+        sqlx::query("UPDATE projects SET content = ? WHERE id = ?")
+            .bind(offloaded.content.to_string())
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+        files += offloaded.files;
+        updated += 1;
+    }
+
+    let revisions: Vec<(i64, String, i64, String)> = sqlx::query_as(
+        "SELECT r.id, r.project_id, r.author_uid, r.content FROM project_revisions r JOIN projects p ON p.id = r.project_id"
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut updated_revisions = 0;
+    for (id, project_id, author, content) in &revisions {
+        let Ok(content) = serde_json::from_str::<Value>(content) else {
+            continue;
+        };
+        let Some(offloaded) = offload_inline_files(state, project_id, *author, &content).await? else {
+            continue;
+        };
+
+        let content = offloaded.content.to_string();
+        sqlx::query("UPDATE project_revisions SET content = ?, content_hash = ?, size = ? WHERE id = ?")
+            .bind(&content)
+            .bind(content_hash(&content))
+            .bind(content.len() as i64)
+            .bind(id)
+            .execute(&state.pool)
+            .await?;
+        files += offloaded.files;
+        updated_revisions += 1;
+    }
+
+    tracing::info!(
+        "Offloaded {} embedded files: {} of {} projects and {} of {} revisions updated",
+        files,
+        updated,
+        projects.len(),
+        updated_revisions,
+        revisions.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use chrono::Utc;
+use sqlx::SqliteConnection;
+
+use crate::{
+    access::require_role,
+    auth::extract_uid_from_headers,
+    models::{AddMemberRequest, ProjectMember, Role, UpdateMemberRequest},
+    state::AppState,
+};
+
+const MEMBER_SELECT: &str = r#"
+    SELECT m.uid, u.username, u.email, m.role, m.created_at
+    FROM project_members m JOIN users u ON u.id = m.uid
+"#;
+
+pub async fn list_members(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<ProjectMember>>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
+    let members = sqlx::query_as::<_, ProjectMember>(
+        &format!("{} WHERE m.project_id = ? ORDER BY m.created_at", MEMBER_SELECT)
+    )
+    .bind(&id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(members))
+}
+
+pub async fn add_member(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<AddMemberRequest>,
+) -> Result<Json<ProjectMember>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Owner).await?;
+
+    // 只能邀请已注册的用户
+    let member_uid: i64 = sqlx::query_scalar("SELECT id FROM users WHERE username = ? OR email = ? COLLATE NOCASE")
+        .bind(&req.user)
+        .bind(&req.user)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO project_members (project_id, uid, role, created_at) VALUES (?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(member_uid)
+    .bind(req.role)
+    .bind(Utc::now())
+    .execute(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::CONFLICT); // 已经是成员，使用 PUT 修改角色
+    }
+
+    fetch_member(&state, &id, member_uid).await.map(Json)
+}
+
+pub async fn update_member(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((id, member_uid)): Path<(String, i64)>,
+    Json(req): Json<UpdateMemberRequest>,
+) -> Result<Json<ProjectMember>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Owner).await?;
+
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let result = sqlx::query("UPDATE project_members SET role = ? WHERE project_id = ? AND uid = ?")
+        .bind(req.role)
+        .bind(&id)
+        .bind(member_uid)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    ensure_owner_remains(&mut tx, &id).await?;
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    state.rooms.access_changed(&id, member_uid);
+
+    fetch_member(&state, &id, member_uid).await.map(Json)
+}
+
+pub async fn remove_member(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((id, member_uid)): Path<(String, i64)>,
+) -> Result<StatusCode, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    // 成员可以自己退出项目，移除他人需要 owner 权限
+    let required = if member_uid == uid { Role::Viewer } else { Role::Owner };
+    require_role(&state.pool, &id, uid, required).await?;
+
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let result = sqlx::query("DELETE FROM project_members WHERE project_id = ? AND uid = ?")
+        .bind(&id)
+        .bind(member_uid)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    ensure_owner_remains(&mut tx, &id).await?;
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // 正在协作的连接立即失去权限，而不是等到下次定期检查
+    state.rooms.access_changed(&id, member_uid);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fetch_member(state: &AppState, id: &str, member_uid: i64) -> Result<ProjectMember, StatusCode> {
+    sqlx::query_as::<_, ProjectMember>(&format!("{} WHERE m.project_id = ? AND m.uid = ?", MEMBER_SELECT))
+        .bind(id)
+        .bind(member_uid)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// 项目至少要保留一个 owner
+async fn ensure_owner_remains(conn: &mut SqliteConnection, id: &str) -> Result<(), StatusCode> {
+    let owners: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM project_members WHERE project_id = ? AND role = 'owner'"
+    )
+    .bind(id)
+    .fetch_one(conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if owners == 0 {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handlers::update_project,
+        models::UpdateProjectRequest,
+        state::{test_project, test_state, test_user},
+    };
+    use serde_json::json;
+
+    async fn add(state: &AppState, headers: &HeaderMap, project: &str, user: &str, role: Role) -> Result<i64, StatusCode> {
+        let req = AddMemberRequest { user: user.to_string(), role };
+        add_member(State(state.clone()), headers.clone(), Path(project.to_string()), Json(req))
+            .await
+            .map(|Json(member)| member.uid)
+    }
+
+    async fn set_role(state: &AppState, headers: &HeaderMap, project: &str, uid: i64, role: Role) -> StatusCode {
+        let req = UpdateMemberRequest { role };
+        match update_member(State(state.clone()), headers.clone(), Path((project.to_string(), uid)), Json(req)).await {
+            Ok(_) => StatusCode::OK,
+            Err(status) => status,
+        }
+    }
+
+    async fn remove(state: &AppState, headers: &HeaderMap, project: &str, uid: i64) -> StatusCode {
+        match remove_member(State(state.clone()), headers.clone(), Path((project.to_string(), uid))).await {
+            Ok(status) | Err(status) => status,
+        }
+    }
+
+    async fn save(state: &AppState, headers: &HeaderMap, project: &str) -> StatusCode {
+        let req = UpdateProjectRequest { content: json!({ "elements": [] }) };
+        match update_project(State(state.clone()), headers.clone(), Path(project.to_string()), Json(req)).await {
+            Ok(response) => response.status(),
+            Err(status) => status,
+        }
+    }
+
+    #[tokio::test]
+    async fn viewers_cannot_write() {
+        let state = test_state().await;
+        let (owner, owner_headers) = test_user(&state, "alice").await;
+        let (_, viewer_headers) = test_user(&state, "bob").await;
+        let project = test_project(&state, owner, &json!({})).await;
+        let viewer = add(&state, &owner_headers, &project, "bob", Role::Viewer).await.unwrap();
+
+        assert_eq!(save(&state, &viewer_headers, &project).await, StatusCode::FORBIDDEN);
+
+        assert_eq!(set_role(&state, &owner_headers, &project, viewer, Role::Editor).await, StatusCode::OK);
+        assert_eq!(save(&state, &viewer_headers, &project).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn editors_cannot_manage_members() {
+        let state = test_state().await;
+        let (owner, owner_headers) = test_user(&state, "alice").await;
+        let (_, editor_headers) = test_user(&state, "bob").await;
+        test_user(&state, "carol").await;
+        let project = test_project(&state, owner, &json!({})).await;
+        let editor = add(&state, &owner_headers, &project, "bob", Role::Editor).await.unwrap();
+        let viewer = add(&state, &owner_headers, &project, "carol", Role::Viewer).await.unwrap();
+
+        assert_eq!(add(&state, &editor_headers, &project, "carol", Role::Editor).await, Err(StatusCode::FORBIDDEN));
+        assert_eq!(set_role(&state, &editor_headers, &project, viewer, Role::Editor).await, StatusCode::FORBIDDEN);
+        assert_eq!(set_role(&state, &editor_headers, &project, editor, Role::Owner).await, StatusCode::FORBIDDEN);
+        assert_eq!(remove(&state, &editor_headers, &project, viewer).await, StatusCode::FORBIDDEN);
+        assert_eq!(remove(&state, &editor_headers, &project, owner).await, StatusCode::FORBIDDEN);
+
+        // 成员可以自己退出
+        assert_eq!(remove(&state, &editor_headers, &project, editor).await, StatusCode::NO_CONTENT);
+    }
+
+    #[tokio::test]
+    async fn last_owner_stays() {
+        let state = test_state().await;
+        let (owner, owner_headers) = test_user(&state, "alice").await;
+        test_user(&state, "bob").await;
+        let project = test_project(&state, owner, &json!({})).await;
+
+        assert_eq!(remove(&state, &owner_headers, &project, owner).await, StatusCode::CONFLICT);
+        assert_eq!(set_role(&state, &owner_headers, &project, owner, Role::Editor).await, StatusCode::CONFLICT);
+        assert_eq!(require_role(&state.pool, &project, owner, Role::Owner).await, Ok(Role::Owner));
+
+        // 有了另一个 owner 之后就可以降级或退出
+        add(&state, &owner_headers, &project, "bob", Role::Owner).await.unwrap();
+        assert_eq!(set_role(&state, &owner_headers, &project, owner, Role::Editor).await, StatusCode::OK);
+        assert_eq!(remove(&state, &owner_headers, &project, owner).await, StatusCode::NO_CONTENT);
+    }
+}
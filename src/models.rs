@@ -9,6 +9,7 @@ pub struct ProjectRow {
     pub name: String,
     pub content: String, // JSON string from database
     pub uid: i64,
+    pub version: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -19,6 +20,7 @@ pub struct Project {
     pub name: String,
     pub content: serde_json::Value, // Parsed JSON object
     pub uid: i64,
+    pub version: i64,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -33,16 +35,28 @@ impl From<ProjectRow> for Project {
             name: row.name,
             content,
             uid: row.uid,
+            version: row.version,
             created_at: row.created_at,
             updated_at: row.updated_at,
         }
     }
 }
 
+#[derive(Debug, Deserialize, FromRow)]
+pub struct ProjectSummaryRow {
+    pub id: String,
+    pub name: String,
+    pub version: i64,
+    pub has_thumbnail: bool,
+    pub updated_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProjectSummary {
     pub id: String,
     pub name: String,
+    pub thumbnail_url: Option<String>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,11 +69,159 @@ pub struct UpdateProjectRequest {
     pub content: serde_json::Value,
 }
 
-impl From<Project> for ProjectSummary {
-    fn from(project: Project) -> Self {
+impl From<ProjectSummaryRow> for ProjectSummary {
+    fn from(row: ProjectSummaryRow) -> Self {
+        // 版本号参与 URL，保存后浏览器会重新加载缩略图
+        let thumbnail_url = row
+            .has_thumbnail
+            .then(|| format!("/api/projects/{}/thumbnail?v={}", row.id, row.version));
+
+        Self {
+            id: row.id,
+            name: row.name,
+            thumbnail_url,
+            updated_at: row.updated_at,
+        }
+    }
+}
+
+// 项目成员相关模型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ProjectMember {
+    pub uid: i64,
+    pub username: String,
+    pub email: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMemberRequest {
+    // 用户名或邮箱
+    pub user: String,
+    pub role: Role,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMemberRequest {
+    pub role: Role,
+}
+
+// 分享链接相关模型
+#[derive(Debug, FromRow)]
+pub struct ProjectShare {
+    pub id: String,
+    pub project_id: String,
+    pub password_hash: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateShareRequest {
+    pub expires_in_secs: Option<i64>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareResponse {
+    pub id: String,
+    // 只保存了哈希，链接只在创建时返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub password_protected: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ProjectShare {
+    pub fn to_response(&self) -> ShareResponse {
+        ShareResponse {
+            id: self.id.clone(),
+            token: None,
+            url: None,
+            password_protected: self.password_hash.is_some(),
+            expires_at: self.expires_at,
+            created_by: self.created_by,
+            created_at: self.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnlockShareRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShareAccess {
+    pub access: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SharedProject {
+    pub name: String,
+    pub content: serde_json::Value,
+    pub images: Vec<ImageResponse>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// 版本历史相关模型
+#[derive(Debug, FromRow)]
+pub struct RevisionRow {
+    pub rev: i64,
+    pub content: String,
+    pub content_hash: String,
+    pub size: i64,
+    pub author_uid: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct RevisionSummary {
+    pub rev: i64,
+    pub content_hash: String,
+    pub size: i64,
+    pub author_uid: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revision {
+    pub rev: i64,
+    pub content: serde_json::Value,
+    pub content_hash: String,
+    pub size: i64,
+    pub author_uid: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<RevisionRow> for Revision {
+    fn from(row: RevisionRow) -> Self {
+        let content = serde_json::from_str(&row.content)
+            .unwrap_or(serde_json::json!({}));
+
         Self {
-            id: project.id,
-            name: project.name,
+            rev: row.rev,
+            content,
+            content_hash: row.content_hash,
+            size: row.size,
+            author_uid: row.author_uid,
+            created_at: row.created_at,
         }
     }
 }
@@ -71,6 +233,8 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub password_hash: String,
+    // When the user proved they own the email address
+    pub verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -80,6 +244,7 @@ pub struct UserResponse {
     pub id: i64,
     pub username: String,
     pub email: String,
+    pub email_verified: bool,
     pub created_at: DateTime<Utc>,
 }
 
@@ -89,6 +254,7 @@ impl From<User> for UserResponse {
             id: user.id,
             username: user.username,
             email: user.email,
+            email_verified: user.verified_at.is_some(),
             created_at: user.created_at,
         }
     }
@@ -110,7 +276,114 @@ pub struct LoginRequest {
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
     pub user: UserResponse,
+    // Short-lived access token
+    pub token: String,
+    pub refresh_token: String,
+    // Seconds until `token` expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug, FromRow)]
+pub struct SessionRow {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionResponse {
+    pub id: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    // The session the request was made with
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordRequest {
     pub token: String,
+    pub new_password: String,
+}
+
+// Login answer for accounts with two-factor authentication: no tokens yet, only a challenge to
+// exchange together with a code at /api/auth/2fa/verify
+#[derive(Debug, Serialize)]
+pub struct LoginChallenge {
+    pub two_factor_required: bool,
+    pub challenge_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorStatus {
+    pub enabled: bool,
+    pub recovery_codes_left: i64,
+    // False for accounts created through single sign-on, which confirm by signing in again instead
+    pub password_required: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisableTwoFactorRequest {
+    #[serde(default)]
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerificationRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LogoutRequest {
+    pub refresh_token: Option<String>,
 }
 
 // 图片相关模型
@@ -126,6 +399,16 @@ pub struct Image {
     pub project_id: Option<String>,
     pub uploaded_by: i64,
     pub created_at: DateTime<Utc>,
+    pub blob_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageUsage {
+    pub used_bytes: u64,
+    pub images: i64,
+    // None when users have no quota
+    pub quota_bytes: Option<u64>,
+    pub max_upload_bytes: u64,
 }
 
 #[derive(Debug, Serialize)]
@@ -155,4 +438,160 @@ impl Image {
             created_at: self.created_at,
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum ImageVariant {
+    Thumb,
+    Medium,
+}
+
+impl ImageVariant {
+    pub const ALL: [ImageVariant; 2] = [ImageVariant::Thumb, ImageVariant::Medium];
+
+    // Longest edge in pixels
+    pub fn max_dimension(self) -> u32 {
+        match self {
+            ImageVariant::Thumb => 256,
+            ImageVariant::Medium => 1024,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ImageVariant::Thumb => "thumb",
+            ImageVariant::Medium => "medium",
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+pub struct ImageVariantRow {
+    pub filename: String,
+    pub mime_type: String,
+    pub width: i32,
+    pub height: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedImageQuery {
+    pub expires: Option<i64>,
+    pub sig: Option<String>,
+    // 缺省返回原图
+    pub size: Option<ImageVariant>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignedUrlRequest {
+    pub ttl_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SignedUrlResponse {
+    pub url: String,
+    pub expires_at: DateTime<Utc>,
+}
+#[derive(Debug, Serialize)]
+pub struct OidcProvider {
+    pub enabled: bool,
+    // Label for the login button
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcLoginQuery {
+    // Ask the provider to prompt for credentials even when the user is signed in there
+    #[serde(default)]
+    pub reauth: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    // Set instead of code when the user cancelled or the provider refused
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcTokenRequest {
+    pub code: String,
+}
+
+// What a personal access token may do; login sessions may do everything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenScope {
+    #[serde(rename = "projects:read")]
+    ProjectsRead,
+    #[serde(rename = "projects:write")]
+    ProjectsWrite,
+    #[serde(rename = "images:write")]
+    ImagesWrite,
+}
+
+impl TokenScope {
+    pub const ALL: [TokenScope; 3] = [TokenScope::ProjectsRead, TokenScope::ProjectsWrite, TokenScope::ImagesWrite];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::ProjectsRead => "projects:read",
+            TokenScope::ProjectsWrite => "projects:write",
+            TokenScope::ImagesWrite => "images:write",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scope| scope.as_str() == s)
+    }
+}
+
+#[derive(Debug, FromRow)]
+pub struct ApiTokenRow {
+    pub id: String,
+    pub name: String,
+    pub scopes: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    // 缺省永不过期
+    pub expires_in_secs: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiTokenResponse {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl ApiTokenRow {
+    pub fn to_response(&self) -> ApiTokenResponse {
+        ApiTokenResponse {
+            id: self.id.clone(),
+            name: self.name.clone(),
+            scopes: self.scopes.split_whitespace().filter_map(TokenScope::parse).collect(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            last_used_at: self.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreatedApiToken {
+    // Shown only in this response
+    pub token: String,
+    #[serde(flatten)]
+    pub api_token: ApiTokenResponse,
+}
@@ -0,0 +1,152 @@
+use anyhow::{Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::{json, Value};
+
+use crate::{
+    access::can_view_image,
+    blobs::blob_hash,
+    image_handlers::create_image,
+    image_probe::probe,
+    models::Image,
+    quota::{QuotaExceeded, UploadLimit},
+    scene::{image_id_from_url, is_inline_file, normalize_content, IMAGE_URL_PREFIX},
+    state::AppState,
+};
+
+pub struct Offloaded {
+    pub content: Value,
+    pub files: usize,
+}
+
+// Move images embedded in the scene's `files` map as base64 `data:` URLs into the image store and
+// point the entries at `/api/images/:id` instead, the same shape the frontend gives files it
+// uploads itself. Identical bytes already stored for the project reuse the existing image.
+//
+// Returns None when nothing was offloaded. The result keeps the form of the input, so a
+// string-encoded scene comes back string-encoded. Files that aren't images venus recognizes are
+// left inline, as are files over the upload limit or the user's quota. Failing to store a file
+// is an error.
+pub async fn offload_inline_files(
+    state: &AppState,
+    project_id: &str,
+    uid: i64,
+    content: &Value,
+) -> Result<Option<Offloaded>> {
+    let mut scene = normalize_content(content);
+    let Some(files) = scene.get_mut("files").and_then(Value::as_object_mut) else {
+        return Ok(None);
+    };
+
+    let mut offloaded = 0;
+    for (file_id, file) in files.iter_mut() {
+        if !is_inline_file(file) {
+            continue;
+        }
+
+        let image = offload_file(state, project_id, uid, file_id, file)
+            .await
+            .with_context(|| format!("cannot offload file {} of project {}", file_id, project_id))?;
+        if let Some(image) = image {
+            file["dataURL"] = json!(format!("{}{}", IMAGE_URL_PREFIX, image.id));
+            file["imageId"] = json!(image.id);
+            file["mimeType"] = json!(image.mime_type);
+            file["uploaded"] = json!(true);
+            offloaded += 1;
+        }
+    }
+
+    if offloaded == 0 {
+        return Ok(None);
+    }
+
+    let content = match content {
+        Value::String(_) => Value::String(scene.to_string()),
+        _ => scene,
+    };
+    Ok(Some(Offloaded { content, files: offloaded }))
+}
+
+async fn offload_file(state: &AppState, project_id: &str, uid: i64, file_id: &str, file: &Value) -> Result<Option<Image>> {
+    let Some(data) = file.get("dataURL").and_then(Value::as_str).and_then(decode_data_url) else {
+        return Ok(None);
+    };
+    // 只转存能识别的图片，其他内容原样保留
+    let Some(info) = probe(&data) else {
+        return Ok(None);
+    };
+
+    let existing = sqlx::query_as::<_, Image>(
+        "SELECT * FROM images WHERE project_id = ? AND blob_hash = ? ORDER BY created_at LIMIT 1"
+    )
+    .bind(project_id)
+    .bind(blob_hash(&data))
+    .fetch_optional(&state.pool)
+    .await?;
+    if let Some(image) = existing {
+        return Ok(Some(image));
+    }
+
+    // 与直接上传使用相同的大小限制和配额
+    let limit = UploadLimit::for_user(state, uid).await?;
+    if !limit.allows(data.len() as u64) {
+        tracing::debug!("Project {}: file {} of {} bytes stays inline, over the upload limit", project_id, file_id, data.len());
+        return Ok(None);
+    }
+
+    let original_name = format!("{}.{}", file_id, info.kind.extension());
+    match create_image(state, &data, info, &original_name, Some(project_id), uid).await {
+        Ok(image) => Ok(Some(image)),
+        // 并发上传用完了配额，和超过上限一样留在场景里
+        Err(e) if e.is::<QuotaExceeded>() => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn decode_data_url(url: &str) -> Option<Vec<u8>> {
+    let (header, payload) = url.strip_prefix("data:")?.split_once(',')?;
+    if !header.ends_with(";base64") {
+        return None;
+    }
+    STANDARD.decode(payload.trim()).ok()
+}
+
+// The reverse of offloading, for exports that must open without the server: replace every
+// `/api/images/:id` file the user can see with its bytes as a `data:` URL. Images that are gone
+// from storage keep their reference.
+pub async fn inline_files(state: &AppState, uid: i64, scene: &mut Value) -> Result<()> {
+    let Some(files) = scene.get_mut("files").and_then(Value::as_object_mut) else {
+        return Ok(());
+    };
+
+    for (file_id, file) in files.iter_mut() {
+        let Some(image_id) = file.get("dataURL").and_then(Value::as_str).and_then(image_id_from_url) else {
+            continue;
+        };
+
+        let image = sqlx::query_as::<_, Image>("SELECT * FROM images WHERE id = ?")
+            .bind(&image_id)
+            .fetch_optional(&state.pool)
+            .await?;
+        let Some(image) = image else {
+            continue;
+        };
+        // 场景可以引用任意图片 id，导出时仍然按图片本身的权限检查
+        if !can_view_image(&state.pool, &image, uid).await? {
+            continue;
+        }
+
+        let Some(data) = state.blobs.get(&image.filename).await? else {
+            tracing::warn!("Export: file {} points at {}, which is missing from storage", file_id, image.filename);
+            continue;
+        };
+
+        file["dataURL"] = json!(format!("data:{};base64,{}", image.mime_type, STANDARD.encode(data)));
+        file["mimeType"] = json!(image.mime_type);
+        if let Some(file) = file.as_object_mut() {
+            file.remove("imageId");
+            file.remove("uploaded");
+        }
+    }
+
+    Ok(())
+}
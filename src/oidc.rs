@@ -0,0 +1,702 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use data_encoding::BASE64URL_NOPAD;
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind,
+    jwk::{Jwk, PublicKeyUse},
+    Algorithm, DecodingKey, Validation,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{Client, RequestBuilder, Url};
+use serde::{de::DeserializeOwned, Deserialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+use crate::{
+    auth::{random_token, token_hash},
+    config::OidcConfig,
+    identities::{find_or_create_user, ExternalIdentity, IdentityError},
+    models::User,
+};
+
+// How long the user may take at the provider before the login request is forgotten
+pub const AUTH_REQUEST_TTL_SECS: i64 = 10 * 60;
+// The browser is redirected straight back to the app, which exchanges the code right away
+const HANDOFF_TTL_SECS: i64 = 60;
+
+const STATE_LEN: usize = 32;
+const NONCE_LEN: usize = 32;
+// RFC 7636 asks for 43 to 128 characters
+const CODE_VERIFIER_LEN: usize = 64;
+const HANDOFF_TOKEN_LEN: usize = 32;
+
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+// Refetch the provider's keys at most this often when a token names a key we don't know
+const KEY_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Symmetric algorithms are left out on purpose: the keys are public
+const ID_TOKEN_ALGORITHMS: &[Algorithm] = &[
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+// Why a single sign-on attempt did not end with a user. The code is passed on to the frontend.
+#[derive(Debug, thiserror::Error)]
+pub enum LoginError {
+    #[error("the login request is unknown or expired")]
+    InvalidState,
+    #[error(transparent)]
+    Identity(#[from] IdentityError),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl LoginError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidState => "invalid_state",
+            Self::Identity(e) => e.code(),
+            Self::Other(_) => "failed",
+        }
+    }
+}
+
+impl From<sqlx::Error> for LoginError {
+    fn from(e: sqlx::Error) -> Self {
+        Self::Other(e.into())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    userinfo_endpoint: Option<String>,
+    #[serde(default)]
+    token_endpoint_auth_methods_supported: Vec<String>,
+}
+
+struct SigningKey {
+    kid: Option<String>,
+    key: DecodingKey,
+}
+
+// Discovery document and signing keys, fetched together
+struct Provider {
+    metadata: ProviderMetadata,
+    keys: Vec<SigningKey>,
+    fetched_at: Instant,
+}
+
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+    access_token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Profile {
+    email: Option<String>,
+    // Some providers send "true" as a string
+    email_verified: Option<serde_json::Value>,
+    preferred_username: Option<String>,
+}
+
+impl Profile {
+    fn email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    azp: Option<String>,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+#[derive(Debug, Deserialize)]
+struct UserInfo {
+    sub: String,
+    #[serde(flatten)]
+    profile: Profile,
+}
+
+// OpenID Connect relying party: authorization code flow with PKCE against one provider
+pub struct OidcClient {
+    config: OidcConfig,
+    http: Client,
+    provider: RwLock<Option<Arc<Provider>>>,
+}
+
+pub fn build(config: &OidcConfig) -> Result<Option<Arc<OidcClient>>> {
+    if !config.enabled() {
+        return Ok(None);
+    }
+    let http = Client::builder().timeout(HTTP_TIMEOUT).build()?;
+    Ok(Some(Arc::new(OidcClient {
+        config: config.clone(),
+        http,
+        provider: RwLock::new(None),
+    })))
+}
+
+impl OidcClient {
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    // The provider, refetched when the cached copy is older than `max_age`. Nothing is fetched at
+    // startup so venus still starts while the provider is down.
+    async fn provider(&self, max_age: Duration) -> Result<Arc<Provider>> {
+        if let Some(provider) = self.provider.read().await.as_ref() {
+            if provider.fetched_at.elapsed() < max_age {
+                return Ok(provider.clone());
+            }
+        }
+
+        let mut cached = self.provider.write().await;
+        // 等锁期间可能已经被其他请求刷新过
+        if let Some(provider) = cached.as_ref() {
+            if provider.fetched_at.elapsed() < max_age {
+                return Ok(provider.clone());
+            }
+        }
+        let provider = Arc::new(self.fetch_provider().await?);
+        *cached = Some(provider.clone());
+        Ok(provider)
+    }
+
+    async fn fetch_provider(&self) -> Result<Provider> {
+        let url = format!("{}/.well-known/openid-configuration", self.config.issuer.trim_end_matches('/'));
+        let metadata: ProviderMetadata = json(self.http.get(&url)).await.context("OIDC discovery failed")?;
+        if metadata.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/') {
+            bail!("OIDC discovery at {} is for issuer {:?}", url, metadata.issuer);
+        }
+
+        let set: JwkSet = json(self.http.get(&metadata.jwks_uri))
+            .await
+            .context("failed to fetch the OIDC signing keys")?;
+        // 跳过无法解析或用于加密的密钥，而不是让整个登录失败
+        let keys: Vec<SigningKey> = set
+            .keys
+            .into_iter()
+            .filter_map(|value| {
+                let jwk: Jwk = serde_json::from_value(value).ok()?;
+                if matches!(jwk.common.public_key_use, Some(PublicKeyUse::Encryption)) {
+                    return None;
+                }
+                Some(SigningKey {
+                    kid: jwk.common.key_id.clone(),
+                    key: DecodingKey::from_jwk(&jwk).ok()?,
+                })
+            })
+            .collect();
+        tracing::info!("Loaded OIDC provider {} with {} signing keys", metadata.issuer, keys.len());
+
+        Ok(Provider {
+            metadata,
+            keys,
+            fetched_at: Instant::now(),
+        })
+    }
+
+    // Remember a new login and return the provider URL to send the browser to, together with the
+    // state parameter the callback has to come back with
+    pub async fn start_login(&self, pool: &SqlitePool, reauth: bool) -> Result<(Url, String)> {
+        let provider = self.provider(DISCOVERY_TTL).await?;
+        let now = Utc::now();
+        let state = random_token(STATE_LEN);
+        let nonce = random_token(NONCE_LEN);
+        let code_verifier = random_token(CODE_VERIFIER_LEN);
+        let code_challenge = BASE64URL_NOPAD.encode(&Sha256::digest(code_verifier.as_bytes()));
+
+        sqlx::query("DELETE FROM oidc_auth_requests WHERE expires_at <= ?")
+            .bind(now)
+            .execute(pool)
+            .await?;
+        sqlx::query(
+            "INSERT INTO oidc_auth_requests (state_hash, code_verifier, nonce, created_at, expires_at) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind(token_hash(&state))
+        .bind(&code_verifier)
+        .bind(&nonce)
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(AUTH_REQUEST_TTL_SECS))
+        .execute(pool)
+        .await?;
+
+        let mut url = Url::parse_with_params(
+            &provider.metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", self.config.client_id.as_str()),
+                ("redirect_uri", self.config.redirect_url.as_str()),
+                ("scope", self.config.scopes.join(" ").as_str()),
+                ("state", state.as_str()),
+                ("nonce", nonce.as_str()),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .context("invalid OIDC authorization endpoint")?;
+        if reauth {
+            url.query_pairs_mut().append_pair("prompt", "login");
+        }
+
+        Ok((url, state))
+    }
+
+    // Handle the provider redirecting back with an authorization code: redeem it, check the ID
+    // token and find, link or create the venus user it belongs to
+    pub async fn finish_login(&self, pool: &SqlitePool, state: &str, code: &str) -> Result<User, LoginError> {
+        // 每个 state 只能使用一次
+        let request: Option<(String, String)> = sqlx::query_as(
+            "DELETE FROM oidc_auth_requests WHERE state_hash = ? AND expires_at > ? RETURNING code_verifier, nonce"
+        )
+        .bind(token_hash(state))
+        .bind(Utc::now())
+        .fetch_optional(pool)
+        .await?;
+        let Some((code_verifier, nonce)) = request else {
+            return Err(LoginError::InvalidState);
+        };
+
+        let provider = self.provider(DISCOVERY_TTL).await?;
+        let tokens = self.exchange_code(&provider, code, &code_verifier).await?;
+        let claims = self.validate_id_token(&tokens.id_token, &nonce).await?;
+
+        // ID token 中没有邮箱时再从 userinfo 接口获取
+        let mut profile = claims.profile;
+        if profile.email.is_none() {
+            if let (Some(endpoint), Some(access_token)) = (&provider.metadata.userinfo_endpoint, &tokens.access_token) {
+                let info: UserInfo = json(self.http.get(endpoint).bearer_auth(access_token))
+                    .await
+                    .context("failed to fetch OIDC userinfo")?;
+                if info.sub == claims.sub {
+                    profile = info.profile;
+                }
+            }
+        }
+
+        let email_verified = self.config.trust_email || profile.email_verified();
+        let identity = ExternalIdentity {
+            issuer: &provider.metadata.issuer,
+            subject: &claims.sub,
+            verified_email: profile.email.as_deref().filter(|_| email_verified),
+            preferred_username: profile.preferred_username.as_deref(),
+            email_optional: false,
+        };
+        Ok(find_or_create_user(pool, &identity, self.config.allow_signup).await?)
+    }
+
+    async fn exchange_code(&self, provider: &Provider, code: &str, code_verifier: &str) -> Result<TokenResponse> {
+        let config = &self.config;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", config.redirect_url.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        let mut request = self.http.post(&provider.metadata.token_endpoint);
+
+        // client_secret_basic 是规范中的默认方式，只有提供方明确不支持时才放在表单中
+        let methods = &provider.metadata.token_endpoint_auth_methods_supported;
+        if config.client_secret.is_empty() {
+            form.push(("client_id", config.client_id.as_str()));
+        } else if methods.is_empty() || methods.iter().any(|method| method == "client_secret_basic") {
+            request = request.basic_auth(
+                utf8_percent_encode(&config.client_id, NON_ALPHANUMERIC),
+                Some(utf8_percent_encode(&config.client_secret, NON_ALPHANUMERIC)),
+            );
+        } else {
+            form.push(("client_id", config.client_id.as_str()));
+            form.push(("client_secret", config.client_secret.as_str()));
+        }
+
+        json(request.form(&form)).await.context("OIDC token request failed")
+    }
+
+    async fn validate_id_token(&self, id_token: &str, nonce: &str) -> Result<IdTokenClaims> {
+        let header = decode_header(id_token).context("malformed ID token")?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            bail!("ID token is signed with unsupported algorithm {:?}", header.alg);
+        }
+
+        let provider = self.provider(DISCOVERY_TTL).await?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&provider.metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        let claims = match decode_id_token(&provider, id_token, header.kid.as_deref(), &validation)? {
+            Some(claims) => claims,
+            None => {
+                // 提供方可能轮换了签名密钥
+                let provider = self.provider(KEY_REFRESH_INTERVAL).await?;
+                decode_id_token(&provider, id_token, header.kid.as_deref(), &validation)?
+                    .context("no signing key of the provider verifies the ID token")?
+            }
+        };
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("ID token nonce does not match the login request");
+        }
+        if claims.azp.as_deref().is_some_and(|azp| azp != self.config.client_id) {
+            bail!("ID token was issued to another client");
+        }
+        Ok(claims)
+    }
+}
+
+// Try the keys the token may be signed with. None means no key fits, which can mean the provider
+// rotated its keys; any other problem with the token is an error.
+fn decode_id_token(
+    provider: &Provider,
+    id_token: &str,
+    kid: Option<&str>,
+    validation: &Validation,
+) -> Result<Option<IdTokenClaims>> {
+    let mut last_error = None;
+    for key in provider.keys.iter().filter(|key| kid.is_none() || key.kid.as_deref() == kid) {
+        match decode::<IdTokenClaims>(id_token, &key.key, validation) {
+            Ok(data) => return Ok(Some(data.claims)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    match last_error {
+        None => Ok(None),
+        Some(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::InvalidSignature
+                    | ErrorKind::InvalidAlgorithm
+                    | ErrorKind::InvalidKeyFormat
+                    | ErrorKind::InvalidRsaKey(_)
+                    | ErrorKind::InvalidEcdsaKey
+            ) =>
+        {
+            Ok(None)
+        }
+        Some(e) => Err(anyhow::Error::new(e).context("invalid ID token")),
+    }
+}
+
+// Hand the signed-in user over to the frontend: the callback redirects the browser back to the app
+// with this single-use code instead of the tokens themselves
+pub async fn create_handoff(pool: &SqlitePool, uid: i64) -> Result<String> {
+    let now = Utc::now();
+    let token = random_token(HANDOFF_TOKEN_LEN);
+
+    sqlx::query("DELETE FROM oidc_handoffs WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    sqlx::query("INSERT INTO oidc_handoffs (token_hash, uid, created_at, expires_at) VALUES (?, ?, ?, ?)")
+        .bind(token_hash(&token))
+        .bind(uid)
+        .bind(now)
+        .bind(now + chrono::Duration::seconds(HANDOFF_TTL_SECS))
+        .execute(pool)
+        .await?;
+
+    Ok(token)
+}
+
+pub async fn redeem_handoff(pool: &SqlitePool, token: &str) -> Result<Option<User>> {
+    let uid: Option<i64> = sqlx::query_scalar(
+        "DELETE FROM oidc_handoffs WHERE token_hash = ? AND expires_at > ? RETURNING uid"
+    )
+    .bind(token_hash(token))
+    .bind(Utc::now())
+    .fetch_optional(pool)
+    .await?;
+    let Some(uid) = uid else {
+        return Ok(None);
+    };
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+    )
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user)
+}
+
+async fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T> {
+    let response = request.send().await?;
+    let url = response.url().clone();
+    let status = response.status();
+    let body = response.bytes().await?;
+    if !status.is_success() {
+        bail!("{} returned {}: {}", url, status, String::from_utf8_lossy(&body).trim());
+    }
+    serde_json::from_slice(&body).with_context(|| format!("unexpected response from {}", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, routing::{get, post}, Form, Json, Router};
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use ring::{rand::SystemRandom, signature::{Ed25519KeyPair, KeyPair}};
+    use serde_json::{json, Value};
+    use std::{collections::HashMap, sync::Mutex};
+
+    use crate::database::test_pool;
+
+    const CLIENT_ID: &str = "venus";
+
+    struct TestKey {
+        kid: String,
+        encoding_key: EncodingKey,
+        jwk: Value,
+    }
+
+    impl TestKey {
+        fn generate(kid: &str) -> Self {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            Self {
+                kid: kid.to_string(),
+                encoding_key: EncodingKey::from_ed_der(pkcs8.as_ref()),
+                jwk: json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": BASE64URL_NOPAD.encode(pair.public_key().as_ref()),
+                    "kid": kid,
+                    "use": "sig",
+                }),
+            }
+        }
+
+        fn sign(&self, claims: &Value) -> String {
+            let mut header = Header::new(Algorithm::EdDSA);
+            header.kid = Some(self.kid.clone());
+            encode(&header, claims, &self.encoding_key).unwrap()
+        }
+    }
+
+    // Stand-in provider: discovery, a swappable key set and a token endpoint handing out a fixed
+    // ID token
+    #[derive(Default)]
+    struct MockProvider {
+        issuer: String,
+        jwks: Mutex<Vec<Value>>,
+        jwks_fetches: Mutex<usize>,
+        id_token: Mutex<String>,
+        token_requests: Mutex<Vec<HashMap<String, String>>>,
+    }
+
+    async fn start_provider(keys: &[&TestKey]) -> Arc<MockProvider> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mock = Arc::new(MockProvider {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            jwks: Mutex::new(keys.iter().map(|key| key.jwk.clone()).collect()),
+            ..Default::default()
+        });
+
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(|State(mock): State<Arc<MockProvider>>| async move {
+                    Json(json!({
+                        "issuer": mock.issuer,
+                        "authorization_endpoint": format!("{}/authorize", mock.issuer),
+                        "token_endpoint": format!("{}/token", mock.issuer),
+                        "jwks_uri": format!("{}/jwks", mock.issuer),
+                    }))
+                }),
+            )
+            .route(
+                "/jwks",
+                get(|State(mock): State<Arc<MockProvider>>| async move {
+                    *mock.jwks_fetches.lock().unwrap() += 1;
+                    Json(json!({ "keys": *mock.jwks.lock().unwrap() }))
+                }),
+            )
+            .route(
+                "/token",
+                post(|State(mock): State<Arc<MockProvider>>, Form(form): Form<HashMap<String, String>>| async move {
+                    mock.token_requests.lock().unwrap().push(form);
+                    Json(json!({ "id_token": *mock.id_token.lock().unwrap(), "token_type": "Bearer" }))
+                }),
+            )
+            .with_state(mock.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        mock
+    }
+
+    fn client(mock: &MockProvider) -> Arc<OidcClient> {
+        let config = OidcConfig {
+            issuer: mock.issuer.clone(),
+            client_id: CLIENT_ID.to_string(),
+            redirect_url: "http://venus.test/api/auth/oidc/callback".to_string(),
+            ..Default::default()
+        };
+        build(&config).unwrap().unwrap()
+    }
+
+    fn claims(mock: &MockProvider, nonce: &str) -> Value {
+        json!({
+            "iss": mock.issuer,
+            "aud": CLIENT_ID,
+            "sub": "alice-at-idp",
+            "exp": Utc::now().timestamp() + 300,
+            "iat": Utc::now().timestamp(),
+            "nonce": nonce,
+            "email": "alice@example.com",
+            "email_verified": true,
+            "preferred_username": "alice",
+        })
+    }
+
+    fn with(mut claims: Value, key: &str, value: Value) -> Value {
+        claims[key] = value;
+        claims
+    }
+
+    // Pretend the cached keys were fetched long enough ago to be refetched
+    async fn age_cached_provider(client: &OidcClient) {
+        let mut cached = client.provider.write().await;
+        let provider = Arc::try_unwrap(cached.take().unwrap()).ok().unwrap();
+        *cached = Some(Arc::new(Provider {
+            fetched_at: Instant::now() - KEY_REFRESH_INTERVAL,
+            ..provider
+        }));
+    }
+
+    #[tokio::test]
+    async fn id_token_claims_are_checked() {
+        let key = TestKey::generate("k1");
+        let mock = start_provider(&[&key]).await;
+        let client = client(&mock);
+        let valid = claims(&mock, "n1");
+
+        let accepted = client.validate_id_token(&key.sign(&valid), "n1").await.unwrap();
+        assert_eq!(accepted.sub, "alice-at-idp");
+        let accepted = client
+            .validate_id_token(&key.sign(&with(valid.clone(), "azp", json!(CLIENT_ID))), "n1")
+            .await;
+        assert!(accepted.is_ok());
+
+        let rejected = [
+            (valid.clone(), "n2"),
+            (with(valid.clone(), "nonce", Value::Null), "n1"),
+            (with(valid.clone(), "iss", json!("http://evil.test")), "n1"),
+            (with(valid.clone(), "aud", json!("other-client")), "n1"),
+            (with(valid.clone(), "azp", json!("other-client")), "n1"),
+            (with(valid.clone(), "exp", json!(Utc::now().timestamp() - 3600)), "n1"),
+        ];
+        for (claims, nonce) in rejected {
+            let result = client.validate_id_token(&key.sign(&claims), nonce).await;
+            assert!(result.is_err(), "accepted {}", claims);
+        }
+
+        // 未知的密钥签名
+        let forged = TestKey::generate("k1").sign(&valid);
+        assert!(client.validate_id_token(&forged, "n1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unknown_kid_refetches_keys() {
+        let old_key = TestKey::generate("old");
+        let new_key = TestKey::generate("new");
+        let mock = start_provider(&[&old_key]).await;
+        let client = client(&mock);
+        let claims = claims(&mock, "n1");
+
+        client.validate_id_token(&old_key.sign(&claims), "n1").await.unwrap();
+        *mock.jwks.lock().unwrap() = vec![new_key.jwk.clone()];
+
+        // 刚获取过密钥，不会立即重新获取
+        assert!(client.validate_id_token(&new_key.sign(&claims), "n1").await.is_err());
+        assert_eq!(*mock.jwks_fetches.lock().unwrap(), 1);
+
+        age_cached_provider(&client).await;
+        client.validate_id_token(&new_key.sign(&claims), "n1").await.unwrap();
+        assert_eq!(*mock.jwks_fetches.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn login_round_trip() {
+        let pool = test_pool().await;
+        let key = TestKey::generate("k1");
+        let mock = start_provider(&[&key]).await;
+        let client = client(&mock);
+
+        let (url, state) = client.start_login(&pool, false).await.unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["state"], state);
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+        assert!(!params.contains_key("prompt"));
+        *mock.id_token.lock().unwrap() = key.sign(&claims(&mock, &params["nonce"]));
+
+        let user = client.finish_login(&pool, &state, "code-1").await.unwrap();
+        assert_eq!(user.email, "alice@example.com");
+        assert_eq!(user.username, "alice");
+
+        // PKCE：令牌请求带上的 verifier 与授权请求中的 challenge 对应
+        let request = mock.token_requests.lock().unwrap().pop().unwrap();
+        assert_eq!(request["code"], "code-1");
+        let challenge = BASE64URL_NOPAD.encode(&Sha256::digest(request["code_verifier"].as_bytes()));
+        assert_eq!(challenge, params["code_challenge"]);
+
+        // state 只能使用一次，也不能凭空捏造
+        let reused = client.finish_login(&pool, &state, "code-1").await;
+        assert!(matches!(reused, Err(LoginError::InvalidState)));
+        let forged = client.finish_login(&pool, "made-up", "code-1").await;
+        assert!(matches!(forged, Err(LoginError::InvalidState)));
+
+        // 再次登录找到同一个用户
+        let (url, state) = client.start_login(&pool, true).await.unwrap();
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        assert_eq!(params["prompt"], "login");
+        *mock.id_token.lock().unwrap() = key.sign(&claims(&mock, &params["nonce"]));
+        assert_eq!(client.finish_login(&pool, &state, "code-2").await.unwrap().id, user.id);
+    }
+
+    #[tokio::test]
+    async fn id_token_from_another_login_is_rejected() {
+        let pool = test_pool().await;
+        let key = TestKey::generate("k1");
+        let mock = start_provider(&[&key]).await;
+        let client = client(&mock);
+
+        let (_, state) = client.start_login(&pool, false).await.unwrap();
+        *mock.id_token.lock().unwrap() = key.sign(&claims(&mock, "nonce-of-another-login"));
+
+        let result = client.finish_login(&pool, &state, "code-1").await;
+        assert!(matches!(result, Err(LoginError::Other(_))));
+    }
+}
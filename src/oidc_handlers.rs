@@ -0,0 +1,121 @@
+use axum::{
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Redirect, Response},
+};
+use std::net::SocketAddr;
+
+use crate::{
+    auth_handlers::finish_login,
+    identities::IdentityError,
+    models::{OidcCallbackQuery, OidcLoginQuery, OidcProvider, OidcTokenRequest},
+    oidc::{self, LoginError, AUTH_REQUEST_TTL_SECS},
+    sessions::ClientInfo,
+    state::AppState,
+};
+
+// Ties the callback to the browser that started the login, so nobody can slip their own login
+// into someone else's browser
+pub const STATE_COOKIE: &str = "venus_oidc_state";
+const STATE_COOKIE_PATH: &str = "/api/auth/oidc";
+
+pub async fn get_oidc_provider(State(state): State<AppState>) -> Json<OidcProvider> {
+    Json(OidcProvider {
+        enabled: state.oidc.is_some(),
+        name: state.oidc.as_ref().map(|oidc| oidc.name().to_string()),
+    })
+}
+
+// 浏览器直接打开此地址，跳转到身份提供方登录；reauth 用于敏感操作前重新确认身份
+pub async fn oidc_login(
+    State(state): State<AppState>,
+    Query(query): Query<OidcLoginQuery>,
+) -> Result<Response, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    let (url, login_state) = oidc.start_login(&state.pool, query.reauth).await.map_err(|e| {
+        tracing::error!("Failed to start OIDC login: {:#}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    let cookie = state_cookie(&state, &login_state, AUTH_REQUEST_TTL_SECS);
+    Ok(([(header::SET_COOKIE, cookie)], Redirect::to(url.as_str())).into_response())
+}
+
+// The provider sends the browser back here. Whatever happens, the browser ends up in the app
+// again: with a code to exchange for tokens, or with the reason the login failed.
+pub async fn oidc_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<Response, StatusCode> {
+    let oidc = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let app_url = format!("{}/oidc-login", state.config.server.public_url.trim_end_matches('/'));
+    let clear_cookie = [(header::SET_COOKIE, state_cookie(&state, "", 0))];
+
+    if let Some(error) = &query.error {
+        tracing::info!("OIDC provider returned error {:?}", error);
+        let redirect = Redirect::to(&format!("{}?error=denied", app_url));
+        return Ok((clear_cookie, redirect).into_response());
+    }
+
+    let result = match (&query.code, &query.state) {
+        (Some(code), Some(login_state)) if cookie_value(&headers, STATE_COOKIE) == Some(login_state.as_str()) => {
+            oidc.finish_login(&state.pool, login_state, code).await
+        }
+        _ => Err(LoginError::InvalidState),
+    };
+
+    let redirect = match result {
+        Ok(user) => {
+            let code = oidc::create_handoff(&state.pool, user.id).await.map_err(|e| {
+                tracing::error!("Failed to hand over OIDC login of user {}: {}", user.id, e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+            format!("{}?code={}", app_url, code)
+        }
+        Err(e) => {
+            match &e {
+                LoginError::Other(e) | LoginError::Identity(IdentityError::Other(e)) => {
+                    tracing::error!("OIDC login failed: {:#}", e)
+                }
+                e => tracing::info!("OIDC login refused: {}", e),
+            }
+            format!("{}?error={}", app_url, e.code())
+        }
+    };
+
+    Ok((clear_cookie, Redirect::to(&redirect)).into_response())
+}
+
+// 前端用回调中的一次性 code 换取 token，和密码登录的结果相同
+pub async fn oidc_token(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<OidcTokenRequest>,
+) -> Result<Response, StatusCode> {
+    let user = oidc::redeem_handoff(&state.pool, &req.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    finish_login(&state, user, &ClientInfo::new(&headers, peer)).await
+}
+
+fn state_cookie(state: &AppState, value: &str, max_age: i64) -> String {
+    let secure = if state.config.oidc.redirect_url.starts_with("https://") { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE, value, STATE_COOKIE_PATH, max_age, secure
+    )
+}
+
+fn cookie_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(name)?.strip_prefix('='))
+}
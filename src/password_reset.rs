@@ -0,0 +1,193 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{
+    auth::{hash_password, random_token, token_hash},
+    mailer::{format_duration, Email},
+    models::User,
+    sessions::revoke_all_sessions,
+    state::AppState,
+};
+
+const RESET_TOKEN_LEN: usize = 32;
+
+// Email a reset link to the account registered with `email`. Unknown addresses are silently
+// ignored so that the endpoint doesn't reveal which addresses have an account; for the same
+// reason the email is sent in the background.
+pub async fn request_reset(state: &AppState, email: &str) -> Result<()> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE email = ? COLLATE NOCASE"
+    )
+    .bind(email.trim())
+    .fetch_optional(&state.pool)
+    .await?;
+    let Some(user) = user else {
+        return Ok(());
+    };
+
+    let now = Utc::now();
+    let ttl = state.config.auth.password_reset_ttl_secs;
+    let token = random_token(RESET_TOKEN_LEN);
+
+    // 每个用户只保留最新的重置链接
+    sqlx::query("DELETE FROM password_resets WHERE uid = ?")
+        .bind(user.id)
+        .execute(&state.pool)
+        .await?;
+    sqlx::query("INSERT INTO password_resets (id, uid, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(Uuid::new_v4().to_string())
+        .bind(user.id)
+        .bind(token_hash(&token))
+        .bind(now)
+        .bind(now + Duration::seconds(ttl))
+        .execute(&state.pool)
+        .await?;
+
+    let link = format!("{}/reset-password?token={}", state.config.server.public_url.trim_end_matches('/'), token);
+    let email = Email {
+        to: user.email,
+        subject: "Reset your Venus password".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Someone asked to reset the password of your Venus account.\n\
+             To choose a new password, open this link within {}:\n\n\
+             {}\n\n\
+             If this wasn't you, ignore this email; your password stays the same.\n",
+            user.username,
+            format_duration(ttl),
+            link,
+        ),
+    };
+    let mailer = state.mailer.clone();
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&email).await {
+            tracing::error!("Failed to send password reset email to user {}: {:#}", user.id, e);
+        }
+    });
+
+    Ok(())
+}
+
+// Set a new password with a reset token. The token is used up, every session of the user is
+// signed out and the email address counts as verified. Returns false when the token is unknown, used or expired.
+pub async fn reset_password(pool: &SqlitePool, token: &str, new_password: &str) -> Result<bool> {
+    let token_hash = token_hash(token);
+
+    // 先确认链接有效再做耗时的 bcrypt，无效的令牌不消耗 CPU
+    let valid: Option<i64> = sqlx::query_scalar(
+        "SELECT uid FROM password_resets WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?"
+    )
+    .bind(&token_hash)
+    .bind(Utc::now())
+    .fetch_optional(pool)
+    .await?;
+    if valid.is_none() {
+        return Ok(false);
+    }
+
+    let password_hash = hash_password(new_password)?;
+    let now = Utc::now();
+    let mut tx = pool.begin().await?;
+
+    // 条件更新保证重置链接只能使用一次
+    let uid: Option<i64> = sqlx::query_scalar(
+        r#"
+        UPDATE password_resets SET used_at = ?
+        WHERE token_hash = ? AND used_at IS NULL AND expires_at > ?
+        RETURNING uid
+        "#
+    )
+    .bind(now)
+    .bind(&token_hash)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(uid) = uid else {
+        return Ok(false);
+    };
+
+    // 能收到重置邮件也就证明了邮箱属于该用户
+    let updated = sqlx::query(
+        "UPDATE users SET password_hash = ?, updated_at = ?, verified_at = COALESCE(verified_at, ?), legacy_verified = 0 WHERE id = ?"
+    )
+    .bind(&password_hash)
+    .bind(now)
+    .bind(now)
+    .bind(uid)
+    .execute(&mut *tx)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Ok(false);
+    }
+    revoke_all_sessions(&mut *tx, uid).await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{auth::verify_password, database::test_pool};
+    use chrono::DateTime;
+
+    async fn add_user(pool: &SqlitePool) -> i64 {
+        let now = Utc::now();
+        sqlx::query_scalar(
+            "INSERT INTO users (username, email, password_hash, created_at, updated_at) VALUES ('alice', 'alice@example.com', ?, ?, ?) RETURNING id"
+        )
+        .bind(hash_password("old password").unwrap())
+        .bind(now)
+        .bind(now)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    async fn add_reset(pool: &SqlitePool, uid: i64, token: &str, expires_at: DateTime<Utc>) {
+        sqlx::query("INSERT INTO password_resets (id, uid, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(uid)
+            .bind(token_hash(token))
+            .bind(Utc::now())
+            .bind(expires_at)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn password_is(pool: &SqlitePool, uid: i64, password: &str) -> bool {
+        let hash: String = sqlx::query_scalar("SELECT password_hash FROM users WHERE id = ?")
+            .bind(uid)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        verify_password(password, &hash).unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_works_once() {
+        let pool = test_pool().await;
+        let uid = add_user(&pool).await;
+        add_reset(&pool, uid, "reset-token", Utc::now() + Duration::hours(1)).await;
+
+        assert!(reset_password(&pool, "reset-token", "new password").await.unwrap());
+        assert!(password_is(&pool, uid, "new password").await);
+
+        assert!(!reset_password(&pool, "reset-token", "another password").await.unwrap());
+        assert!(password_is(&pool, uid, "new password").await);
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_or_unknown_token() {
+        let pool = test_pool().await;
+        let uid = add_user(&pool).await;
+        add_reset(&pool, uid, "expired-token", Utc::now() - Duration::seconds(1)).await;
+
+        assert!(!reset_password(&pool, "expired-token", "new password").await.unwrap());
+        assert!(!reset_password(&pool, "unknown-token", "new password").await.unwrap());
+        assert!(password_is(&pool, uid, "old password").await);
+    }
+}
@@ -0,0 +1,369 @@
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use reqwest::Client;
+use sqlx::SqlitePool;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{token_hash, Claims, NO_SESSION_ID, TOKEN_COOKIE},
+    config::{Config, ForwardAuthConfig, ForwardAuthMode},
+    identities::{find_or_create_user, ExternalIdentity, IdentityError},
+    oidc_handlers::STATE_COOKIE,
+    state::AppState,
+};
+
+// Identities vouched for by a proxy in "headers" mode are recorded under this issuer
+const HEADERS_ISSUER: &str = "proxy";
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+// Forget all cached answers of the auth service rather than grow without bound
+const MAX_CACHE_ENTRIES: usize = 10_000;
+// venus's own cookies carry its tokens and are none of the auth service's business
+const VENUS_COOKIES: &[&str] = &[TOKEN_COOKIE, STATE_COOKIE];
+
+// An address range like 10.0.0.0/8 or fd00::/8; a bare address is a range of one
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.trim().parse().context("invalid IP address")?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().context("invalid prefix length")?,
+            None => max,
+        };
+        if prefix > max {
+            bail!("prefix length {} is longer than the address", prefix);
+        }
+        Ok(Self { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // 监听 IPv6 时 IPv4 客户端的地址形如 ::ffff:10.0.0.1
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ProxyUser {
+    uid: i64,
+    username: String,
+}
+
+// What venus believes about requests coming through reverse proxies: which peers may forward the
+// client address, and who the user is when authentication happens in front of venus
+pub struct ReverseProxy {
+    trusted: Vec<Cidr>,
+    config: ForwardAuthConfig,
+    user_header: HeaderName,
+    email_header: HeaderName,
+    http: Client,
+    token_ttl_secs: i64,
+    // 按 Cookie 的哈希缓存认证服务的结果，None 表示未登录
+    cache: Mutex<HashMap<String, (Instant, Option<ProxyUser>)>>,
+}
+
+impl ReverseProxy {
+    pub fn new(config: &Config) -> Result<Self> {
+        let trusted = config
+            .server
+            .trusted_proxies
+            .iter()
+            .map(|proxy| proxy.parse())
+            .collect::<Result<Vec<Cidr>>>()?;
+        let forward = &config.auth.forward;
+        Ok(Self {
+            trusted,
+            config: forward.clone(),
+            user_header: HeaderName::from_bytes(forward.user_header.as_bytes())?,
+            email_header: HeaderName::from_bytes(forward.email_header.as_bytes())?,
+            http: Client::builder().timeout(HTTP_TIMEOUT).build()?,
+            token_ttl_secs: config.auth.token_ttl_secs,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    // The client a trusted proxy forwarded the request for: the last X-Forwarded-For entry that
+    // is not one of our proxies. Entries further left were added by the client itself.
+    fn forwarded_client(&self, headers: &HeaderMap) -> Option<IpAddr> {
+        let entries: Vec<&str> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect();
+
+        let mut client = None;
+        for entry in entries.iter().rev() {
+            let ip = entry
+                .parse::<IpAddr>()
+                .or_else(|_| entry.parse::<SocketAddr>().map(|addr| addr.ip()))
+                .ok()?;
+            client = Some(ip);
+            if !self.is_trusted(ip) {
+                break;
+            }
+        }
+        client
+    }
+
+    // The user authenticated in front of venus, or None to leave the request to venus's own tokens
+    pub async fn authenticate(&self, pool: &SqlitePool, headers: &HeaderMap) -> Result<Option<Claims>, StatusCode> {
+        let user = match self.config.mode {
+            ForwardAuthMode::Off => return Ok(None),
+            ForwardAuthMode::Headers => self.user_from_headers(pool, headers).await,
+            ForwardAuthMode::Url => self.user_from_auth_url(pool, headers).await,
+        };
+
+        match user {
+            Ok(user) => {
+                Ok(user.map(|user| Claims::new(user.uid, user.username, NO_SESSION_ID, self.token_ttl_secs)))
+            }
+            Err(IdentityError::Other(e)) => {
+                tracing::error!("Forward authentication failed: {:#}", e);
+                Err(StatusCode::BAD_GATEWAY)
+            }
+            Err(e) => {
+                tracing::info!("Forward authentication refused: {}", e);
+                Err(StatusCode::FORBIDDEN)
+            }
+        }
+    }
+
+    // The middleware has already removed these headers unless a trusted proxy sent the request
+    async fn user_from_headers(
+        &self,
+        pool: &SqlitePool,
+        headers: &HeaderMap,
+    ) -> Result<Option<ProxyUser>, IdentityError> {
+        let Some(subject) = header_value(headers, &self.user_header) else {
+            return Ok(None);
+        };
+        let email = header_value(headers, &self.email_header);
+
+        // 代理只给出用户名时也为其创建账号
+        let identity = ExternalIdentity {
+            issuer: HEADERS_ISSUER,
+            subject,
+            verified_email: email,
+            preferred_username: Some(subject),
+            email_optional: true,
+        };
+        let user = find_or_create_user(pool, &identity, self.config.allow_signup).await?;
+        Ok(Some(ProxyUser {
+            uid: user.id,
+            username: user.username,
+        }))
+    }
+
+    async fn user_from_auth_url(
+        &self,
+        pool: &SqlitePool,
+        headers: &HeaderMap,
+    ) -> Result<Option<ProxyUser>, IdentityError> {
+        let Some(cookie) = forwarded_cookies(headers) else {
+            return Ok(None);
+        };
+        let key = token_hash(&cookie);
+        let ttl = Duration::from_secs(self.config.cache_secs);
+
+        if let Some((checked_at, user)) = self.cache.lock().unwrap().get(&key) {
+            if checked_at.elapsed() < ttl {
+                return Ok(user.clone());
+            }
+        }
+
+        let response = self
+            .http
+            .get(&self.config.url)
+            .header(header::COOKIE, cookie)
+            .send()
+            .await
+            .context("auth service request failed")?;
+
+        let status = response.status();
+        let user = if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            None
+        } else if status.is_success() {
+            let headers = response.headers();
+            let subject = header_value(headers, &self.user_header)
+                .with_context(|| format!("auth service did not send {}", self.user_header))?;
+            let identity = ExternalIdentity {
+                issuer: &self.config.url,
+                subject,
+                verified_email: header_value(headers, &self.email_header),
+                preferred_username: Some(subject),
+                email_optional: true,
+            };
+            let user = find_or_create_user(pool, &identity, self.config.allow_signup).await?;
+            Some(ProxyUser {
+                uid: user.id,
+                username: user.username,
+            })
+        } else {
+            return Err(anyhow::anyhow!("auth service returned {}", status).into());
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= MAX_CACHE_ENTRIES {
+            cache.clear();
+        }
+        cache.insert(key, (Instant::now(), user.clone()));
+        Ok(user)
+    }
+}
+
+// The request's cookies without venus's own, or None when no others are left
+fn forwarded_cookies(headers: &HeaderMap) -> Option<String> {
+    let cookies: Vec<&str> = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .map(str::trim)
+        .filter(|pair| {
+            let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+            !pair.is_empty() && !VENUS_COOKIES.contains(&name.trim())
+        })
+        .collect();
+    (!cookies.is_empty()).then(|| cookies.join("; "))
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+}
+
+// Runs before every handler. Behind a trusted proxy the client address handlers see is the one the
+// proxy forwarded; from anywhere else, identity headers are dropped so that nobody can set them
+// themselves.
+pub async fn trust_proxy(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let proxy = &state.proxy;
+    if proxy.is_trusted(peer.ip()) {
+        if let Some(client) = proxy.forwarded_client(request.headers()) {
+            request
+                .extensions_mut()
+                .insert(ConnectInfo(SocketAddr::new(client, peer.port())));
+        }
+    } else {
+        let headers = request.headers_mut();
+        headers.remove(&proxy.user_header);
+        headers.remove(&proxy.email_header);
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_matching() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains("10.20.30.40".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("11.0.0.1".parse().unwrap()));
+        assert!(!cidr.contains("fd00::1".parse().unwrap()));
+
+        let single: Cidr = "127.0.0.1".parse().unwrap();
+        assert!(single.contains("127.0.0.1".parse().unwrap()));
+        assert!(!single.contains("127.0.0.2".parse().unwrap()));
+
+        let any: Cidr = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    fn proxy(trusted: &[&str]) -> ReverseProxy {
+        let mut config = Config::default();
+        config.server.trusted_proxies = trusted.iter().map(|proxy| proxy.to_string()).collect();
+        ReverseProxy::new(&config).unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn forwarded_client_skips_trusted_proxies() {
+        let proxy = proxy(&["10.0.0.0/8"]);
+        let client = |values: &[&str]| proxy.forwarded_client(&forwarded_for(values));
+
+        assert_eq!(client(&["203.0.113.7"]), Some("203.0.113.7".parse().unwrap()));
+        // 客户端自己写的条目在左边，不可信
+        assert_eq!(client(&["1.1.1.1, 203.0.113.7, 10.0.0.2"]), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(client(&["1.1.1.1", "203.0.113.7", "10.0.0.2"]), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(client(&["203.0.113.7:5555"]), Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(client(&["[2001:db8::1]:443"]), Some("2001:db8::1".parse().unwrap()));
+        // 全部是受信任的代理时取最左边的一个
+        assert_eq!(client(&["10.0.0.3, 10.0.0.2"]), Some("10.0.0.3".parse().unwrap()));
+        assert_eq!(client(&[]), None);
+        // 无法解析的条目之后的内容不可信
+        assert_eq!(client(&["1.1.1.1, unknown, 10.0.0.2"]), None);
+    }
+
+    #[test]
+    fn venus_cookies_are_not_forwarded() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, "token=jwt; _oauth2_proxy=abc".parse().unwrap());
+        headers.append(header::COOKIE, "venus_oidc_state=xyz;theme=dark".parse().unwrap());
+        assert_eq!(forwarded_cookies(&headers).as_deref(), Some("_oauth2_proxy=abc; theme=dark"));
+
+        let mut headers = HeaderMap::new();
+        headers.append(header::COOKIE, "token=jwt".parse().unwrap());
+        assert_eq!(forwarded_cookies(&headers), None);
+    }
+}
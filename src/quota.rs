@@ -0,0 +1,151 @@
+use axum::http::StatusCode;
+use sqlx::{Executor, Sqlite};
+use std::fmt;
+
+use crate::state::AppState;
+
+// Bytes counted against a user's quota and how many images they make up. Every images row the
+// user uploaded counts in full, even when its file is shared with identical uploads.
+pub async fn storage_used<'e, E>(executor: E, uid: i64) -> Result<(u64, i64), sqlx::Error>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    let (bytes, images): (i64, i64) =
+        sqlx::query_as("SELECT COALESCE(SUM(size), 0), COUNT(*) FROM images WHERE uploaded_by = ?")
+            .bind(uid)
+            .fetch_one(executor)
+            .await?;
+    Ok((bytes.max(0) as u64, images))
+}
+
+// Returned when recording an image would take its uploader past their quota. UploadLimit is
+// checked before an upload is read; this is the final check in the transaction that inserts the
+// images row, which concurrent uploads cannot both pass.
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("storage quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+// How large the next upload of a user may be
+#[derive(Debug, Clone, Copy)]
+pub struct UploadLimit {
+    pub max_bytes: u64,
+    // True when the remaining quota rather than the per-file maximum sets the limit
+    pub quota_bound: bool,
+}
+
+impl UploadLimit {
+    pub async fn for_user(state: &AppState, uid: i64) -> Result<Self, sqlx::Error> {
+        let max_upload = state.config.storage.max_upload_bytes;
+        let quota = state.config.storage.user_quota_bytes;
+        if quota == 0 {
+            return Ok(Self { max_bytes: max_upload, quota_bound: false });
+        }
+
+        // 这里不加锁，并发上传由插入记录时的检查兜底
+        let (used, _) = storage_used(&state.pool, uid).await?;
+        let remaining = quota.saturating_sub(used);
+        Ok(Self {
+            max_bytes: max_upload.min(remaining),
+            quota_bound: remaining < max_upload,
+        })
+    }
+
+    pub fn allows(&self, size: u64) -> bool {
+        size <= self.max_bytes
+    }
+
+    // 单个文件过大返回 413，配额用完返回 507
+    pub fn exceeded_status(&self) -> StatusCode {
+        if self.quota_bound {
+            StatusCode::INSUFFICIENT_STORAGE
+        } else {
+            StatusCode::PAYLOAD_TOO_LARGE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{image_handlers::create_image, image_probe::probe, state::test_state};
+    use image::{DynamicImage, ImageFormat};
+    use std::sync::Arc;
+
+    async fn state_with(max_upload_bytes: u64, user_quota_bytes: u64) -> AppState {
+        let state = test_state().await;
+        let mut config = (*state.config).clone();
+        config.storage.max_upload_bytes = max_upload_bytes;
+        config.storage.user_quota_bytes = user_quota_bytes;
+        AppState { config: Arc::new(config), ..state }
+    }
+
+    async fn add_image(state: &AppState, uid: i64, size: i64) {
+        sqlx::query(
+            "INSERT INTO images (id, filename, original_name, mime_type, size, uploaded_by) VALUES (?, 'a.png', 'a.png', 'image/png', ?, ?)"
+        )
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(size)
+        .bind(uid)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn per_file_maximum_without_quota() {
+        let state = state_with(1000, 0).await;
+        add_image(&state, 1, 5000).await;
+
+        let limit = UploadLimit::for_user(&state, 1).await.unwrap();
+        assert!(limit.allows(1000));
+        assert!(!limit.allows(1001));
+        assert_eq!(limit.exceeded_status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn remaining_quota_lowers_the_limit() {
+        let state = state_with(1000, 3000).await;
+        add_image(&state, 1, 1500).await;
+        add_image(&state, 2, 3000).await;
+
+        // 别人的上传不计入配额
+        let limit = UploadLimit::for_user(&state, 1).await.unwrap();
+        assert_eq!((limit.max_bytes, limit.quota_bound), (1000, false));
+
+        add_image(&state, 1, 1000).await;
+        let limit = UploadLimit::for_user(&state, 1).await.unwrap();
+        assert_eq!((limit.max_bytes, limit.quota_bound), (500, true));
+        assert!(!limit.allows(501));
+        assert_eq!(limit.exceeded_status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        // 用量超过配额时不再允许任何上传
+        add_image(&state, 1, 1000).await;
+        let limit = UploadLimit::for_user(&state, 1).await.unwrap();
+        assert_eq!(limit.max_bytes, 0);
+        assert!(!limit.allows(1));
+    }
+
+    #[tokio::test]
+    async fn recording_past_the_quota_fails() {
+        let png = |width| {
+            let mut png = std::io::Cursor::new(Vec::new());
+            DynamicImage::new_rgb8(width, 10).write_to(&mut png, ImageFormat::Png).unwrap();
+            png.into_inner()
+        };
+        let (first, second) = (png(10), png(20));
+        let state = state_with(1000, (first.len() + second.len() - 1) as u64).await;
+
+        create_image(&state, &first, probe(&first).unwrap(), "a.png", None, 1).await.unwrap();
+        // 开始时的检查通过之后，插入记录时仍然按实际用量拒绝
+        let error = create_image(&state, &second, probe(&second).unwrap(), "b.png", None, 1).await.unwrap_err();
+        assert!(error.is::<QuotaExceeded>());
+        assert_eq!(storage_used(&state.pool, 1).await.unwrap(), (first.len() as u64, 1));
+    }
+}
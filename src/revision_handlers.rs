@@ -0,0 +1,104 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use chrono::Utc;
+
+use crate::{
+    access::require_role,
+    auth::extract_uid_from_headers,
+    handlers::scene_thumbnail,
+    models::{Project, ProjectRow, Revision, RevisionRow, RevisionSummary, Role},
+    revisions::{prune_revisions, record_revision},
+    state::AppState,
+};
+
+pub async fn list_revisions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<RevisionSummary>>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
+    let revisions = sqlx::query_as::<_, RevisionSummary>(
+        "SELECT rev, content_hash, size, author_uid, created_at FROM project_revisions WHERE project_id = ? ORDER BY rev DESC"
+    )
+    .bind(&id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(revisions))
+}
+
+pub async fn get_revision(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((id, rev)): Path<(String, i64)>,
+) -> Result<Json<Revision>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Viewer).await?;
+
+    let row = sqlx::query_as::<_, RevisionRow>(
+        "SELECT rev, content, content_hash, size, author_uid, created_at FROM project_revisions WHERE project_id = ? AND rev = ?"
+    )
+    .bind(&id)
+    .bind(rev)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(Revision::from(row)))
+}
+
+pub async fn restore_revision(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((id, rev)): Path<(String, i64)>,
+) -> Result<Json<Project>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+    let now = Utc::now();
+
+    let mut tx = state.pool.begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let content: String = sqlx::query_scalar(
+        "SELECT content FROM project_revisions WHERE project_id = ? AND rev = ?"
+    )
+    .bind(&id)
+    .bind(rev)
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    // 恢复本身也是一次保存，会追加新的版本，原有历史保持不变
+    let project_row = sqlx::query_as::<_, ProjectRow>(
+        r#"
+        UPDATE projects SET content = ?, thumbnail = ?, updated_at = ?, version = version + 1 WHERE id = ?
+        RETURNING id, name, content, uid, version, created_at, updated_at
+        "#
+    )
+    .bind(&content)
+    .bind(scene_thumbnail(&content))
+    .bind(now)
+    .bind(&id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    record_revision(&mut tx, &id, uid, &content, now)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if let Err(e) = prune_revisions(&state.pool, &state.config.history, &id).await {
+        tracing::warn!("Failed to prune revisions for project {}: {}", id, e);
+    }
+
+    Ok(Json(Project::from(project_row)))
+}
@@ -0,0 +1,187 @@
+use chrono::{DateTime, Duration, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::config::HistoryConfig;
+
+pub fn content_hash(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+// Append a revision for a project save. Must run in the same transaction as the content update
+// so the history never disagrees with the stored project.
+pub async fn record_revision(
+    conn: &mut SqliteConnection,
+    project_id: &str,
+    author_uid: i64,
+    content: &str,
+    now: DateTime<Utc>,
+) -> Result<i64, sqlx::Error> {
+    let rev: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO project_revisions (project_id, rev, content, content_hash, size, author_uid, created_at)
+        VALUES (
+            ?,
+            (SELECT COALESCE(MAX(rev), 0) + 1 FROM project_revisions WHERE project_id = ?),
+            ?, ?, ?, ?, ?
+        )
+        RETURNING rev
+        "#
+    )
+    .bind(project_id)
+    .bind(project_id)
+    .bind(content)
+    .bind(content_hash(content))
+    .bind(content.len() as i64)
+    .bind(author_uid)
+    .bind(now)
+    .fetch_one(conn)
+    .await?;
+
+    Ok(rev)
+}
+
+// Projects saved before history was kept have no revision of their earlier content. Record the
+// stored content as their first revision before it is overwritten, so it can still be restored.
+pub async fn record_baseline(conn: &mut SqliteConnection, project_id: &str) -> Result<(), sqlx::Error> {
+    let stored: Option<(String, i64, DateTime<Utc>)> = sqlx::query_as(
+        r#"
+        SELECT content, uid, updated_at FROM projects
+        WHERE id = ? AND NOT EXISTS (SELECT 1 FROM project_revisions WHERE project_id = ?)
+        "#
+    )
+    .bind(project_id)
+    .bind(project_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    if let Some((content, owner, saved_at)) = stored {
+        record_revision(conn, project_id, owner, &content, saved_at).await?;
+    }
+    Ok(())
+}
+
+// Apply the retention policy: the newest `keep_last` revisions are always kept, older ones are
+// thinned to the last revision of each day, and those are dropped once past `daily_retention_days`.
+pub async fn prune_revisions(
+    pool: &SqlitePool,
+    config: &HistoryConfig,
+    project_id: &str,
+) -> Result<u64, sqlx::Error> {
+    let cutoff = (config.daily_retention_days > 0)
+        .then(|| Utc::now() - Duration::days(config.daily_retention_days as i64));
+
+    let result = sqlx::query(
+        r#"
+        DELETE FROM project_revisions
+        WHERE project_id = ?1
+          AND rev NOT IN (
+              SELECT rev FROM project_revisions WHERE project_id = ?1 ORDER BY rev DESC LIMIT ?2
+          )
+          AND (
+              rev NOT IN (
+                  SELECT MAX(rev) FROM project_revisions WHERE project_id = ?1
+                  GROUP BY substr(created_at, 1, 10)
+              )
+              OR (?3 IS NOT NULL AND created_at < ?3)
+          )
+        "#
+    )
+    .bind(project_id)
+    .bind(config.keep_last as i64)
+    .bind(cutoff)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+
+    async fn add_revision(pool: &SqlitePool, project_id: &str, created_at: DateTime<Utc>) {
+        let mut conn = pool.acquire().await.unwrap();
+        record_revision(&mut conn, project_id, 1, "{}", created_at).await.unwrap();
+    }
+
+    async fn revs(pool: &SqlitePool, project_id: &str) -> Vec<i64> {
+        sqlx::query_scalar("SELECT rev FROM project_revisions WHERE project_id = ? ORDER BY rev")
+            .bind(project_id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    fn days_ago(days: i64, hour: u32) -> DateTime<Utc> {
+        let day = (Utc::now() - Duration::days(days)).date_naive();
+        day.and_hms_opt(hour, 0, 0).unwrap().and_utc()
+    }
+
+    #[tokio::test]
+    async fn keeps_newest_and_last_of_each_day() {
+        let pool = test_pool().await;
+        // 第 1-3 个版本在 10 天前，第 4-5 个在 5 天前，第 6-7 个在今天之前一小时内
+        for hour in [8, 9, 10] {
+            add_revision(&pool, "p", days_ago(10, hour)).await;
+        }
+        for hour in [8, 9] {
+            add_revision(&pool, "p", days_ago(5, hour)).await;
+        }
+        for minutes in [30, 20] {
+            add_revision(&pool, "p", Utc::now() - Duration::minutes(minutes)).await;
+        }
+        add_revision(&pool, "other", days_ago(10, 8)).await;
+        add_revision(&pool, "other", days_ago(10, 9)).await;
+
+        let config = HistoryConfig { keep_last: 2, daily_retention_days: 0 };
+        assert_eq!(prune_revisions(&pool, &config, "p").await.unwrap(), 3);
+        assert_eq!(revs(&pool, "p").await, [3, 5, 6, 7]);
+        // 其他项目不受影响
+        assert_eq!(revs(&pool, "other").await, [1, 2]);
+
+        // 再次执行不会删除更多
+        assert_eq!(prune_revisions(&pool, &config, "p").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_daily_revisions_past_retention() {
+        let pool = test_pool().await;
+        add_revision(&pool, "p", days_ago(40, 12)).await;
+        add_revision(&pool, "p", days_ago(20, 12)).await;
+        add_revision(&pool, "p", days_ago(35, 12)).await;
+
+        let config = HistoryConfig { keep_last: 1, daily_retention_days: 30 };
+        prune_revisions(&pool, &config, "p").await.unwrap();
+        assert_eq!(revs(&pool, "p").await, [2, 3]);
+
+        // keep_last 以内的版本再旧也保留
+        let config = HistoryConfig { keep_last: 2, daily_retention_days: 1 };
+        prune_revisions(&pool, &config, "p").await.unwrap();
+        assert_eq!(revs(&pool, "p").await, [2, 3]);
+    }
+
+    #[tokio::test]
+    async fn baseline_is_recorded_once() {
+        let pool = test_pool().await;
+        let saved_at = days_ago(3, 12);
+        sqlx::query("INSERT INTO projects (id, name, content, uid, updated_at) VALUES ('p', 'p', '{\"elements\":[]}', 7, ?)")
+            .bind(saved_at)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut conn = pool.acquire().await.unwrap();
+        record_baseline(&mut conn, "p").await.unwrap();
+        record_baseline(&mut conn, "p").await.unwrap();
+        drop(conn);
+
+        let baseline: (i64, String, i64, DateTime<Utc>) =
+            sqlx::query_as("SELECT rev, content, author_uid, created_at FROM project_revisions WHERE project_id = 'p'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(baseline, (1, "{\"elements\":[]}".to_string(), 7, saved_at));
+    }
+}
@@ -0,0 +1,238 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+pub const IMAGE_URL_PREFIX: &str = "/api/images/";
+
+// The frontend stores the scene as a JSON encoded string; accept both forms.
+pub fn normalize_content(content: &Value) -> Value {
+    match content {
+        Value::String(raw) => serde_json::from_str(raw).unwrap_or(Value::Object(Map::new())),
+        other => other.clone(),
+    }
+}
+
+// Reconcile an incoming Excalidraw scene against the stored one, element by element.
+//
+// For elements present on both sides the higher `version` wins, ties are broken by the lower
+// `versionNonce` (the same rule Excalidraw uses for collaboration). Deleted elements are kept as
+// tombstones so a stale client cannot resurrect them. The `files` maps are unioned, keeping an
+// offloaded `/api/images/` entry over an inline `data:` URL for the same file, and all other
+// top-level keys (e.g. `appState`) are taken from the incoming scene.
+pub fn merge_scenes(stored: &Value, incoming: &Value) -> Value {
+    let stored = normalize_content(stored);
+    let incoming = normalize_content(incoming);
+
+    let mut merged = match &incoming {
+        Value::Object(map) => map.clone(),
+        _ => Map::new(),
+    };
+
+    merged.insert(
+        "elements".to_string(),
+        Value::Array(merge_elements(elements(&stored), elements(&incoming))),
+    );
+
+    let mut files = stored
+        .get("files")
+        .and_then(Value::as_object)
+        .cloned()
+        .unwrap_or_default();
+    if let Some(incoming_files) = incoming.get("files").and_then(Value::as_object) {
+        for (id, file) in incoming_files {
+            // 服务端已经转存的图片不要被客户端手里的内嵌 dataURL 覆盖回去
+            if is_inline_file(file) && files.get(id).is_some_and(|stored| !is_inline_file(stored)) {
+                continue;
+            }
+            files.insert(id.clone(), file.clone());
+        }
+    }
+    merged.insert("files".to_string(), Value::Object(files));
+
+    Value::Object(merged)
+}
+
+// Image ids of uploaded files referenced from the scene's `files` map
+pub fn referenced_image_ids(scene: &Value) -> Vec<String> {
+    let scene = normalize_content(scene);
+    let Some(files) = scene.get("files").and_then(Value::as_object) else {
+        return Vec::new();
+    };
+
+    files
+        .values()
+        .filter_map(|file| file.get("dataURL").and_then(Value::as_str))
+        .filter_map(image_id_from_url)
+        .collect()
+}
+
+// Files still carrying their bytes inline as a `data:` URL rather than pointing at the image store
+pub fn is_inline_file(file: &Value) -> bool {
+    file.get("dataURL")
+        .and_then(Value::as_str)
+        .is_some_and(|url| url.starts_with("data:"))
+}
+
+pub fn image_id_from_url(url: &str) -> Option<String> {
+    let (_, rest) = url.rsplit_once(IMAGE_URL_PREFIX)?;
+    let id = rest.split(['?', '#', '/']).next()?;
+    (!id.is_empty()).then(|| id.to_string())
+}
+
+fn elements(scene: &Value) -> &[Value] {
+    scene
+        .get("elements")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or(&[])
+}
+
+fn element_id(element: &Value) -> Option<&str> {
+    element.get("id").and_then(Value::as_str)
+}
+
+fn incoming_wins(stored: &Value, incoming: &Value) -> bool {
+    let version = |e: &Value| e.get("version").and_then(Value::as_i64).unwrap_or(0);
+    let nonce = |e: &Value| e.get("versionNonce").and_then(Value::as_i64).unwrap_or(0);
+
+    match version(incoming).cmp(&version(stored)) {
+        std::cmp::Ordering::Greater => true,
+        std::cmp::Ordering::Less => false,
+        std::cmp::Ordering::Equal => nonce(incoming) <= nonce(stored),
+    }
+}
+
+fn merge_elements(stored: &[Value], incoming: &[Value]) -> Vec<Value> {
+    let stored_by_id: HashMap<&str, &Value> = stored
+        .iter()
+        .filter_map(|e| element_id(e).map(|id| (id, e)))
+        .collect();
+
+    // Incoming order defines the z-order; resolve conflicts per element
+    let mut result: Vec<Value> = incoming
+        .iter()
+        .map(|element| match element_id(element).and_then(|id| stored_by_id.get(id)) {
+            Some(existing) if !incoming_wins(existing, element) => (*existing).clone(),
+            _ => element.clone(),
+        })
+        .collect();
+
+    // Elements only the server knows about are placed right after their stored predecessor
+    let mut previous: Option<&str> = None;
+    for element in stored {
+        let Some(id) = element_id(element) else {
+            continue;
+        };
+        let position = |id: &str, result: &[Value]| result.iter().position(|e| element_id(e) == Some(id));
+
+        if position(id, &result).is_none() {
+            let index = previous
+                .and_then(|prev| position(prev, &result))
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            result.insert(index, element.clone());
+        }
+        previous = Some(id);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn el(id: &str, version: i64, nonce: i64) -> Value {
+        json!({"id": id, "version": version, "versionNonce": nonce, "isDeleted": false})
+    }
+
+    fn ids(scene: &Value) -> Vec<&str> {
+        elements(scene).iter().filter_map(element_id).collect()
+    }
+
+    #[test]
+    fn keeps_concurrent_edits_to_different_elements() {
+        let stored = json!({"elements": [el("a", 2, 1), el("b", 1, 1)]});
+        let incoming = json!({"elements": [el("a", 1, 1), el("b", 3, 1)]});
+
+        let merged = merge_scenes(&stored, &incoming);
+        let merged_elements = elements(&merged);
+
+        assert_eq!(merged_elements[0]["version"], 2);
+        assert_eq!(merged_elements[1]["version"], 3);
+    }
+
+    #[test]
+    fn equal_versions_resolve_by_lower_nonce() {
+        let stored = json!({"elements": [el("a", 2, 5)]});
+        let incoming = json!({"elements": [el("a", 2, 9)]});
+        assert_eq!(elements(&merge_scenes(&stored, &incoming))[0]["versionNonce"], 5);
+
+        let incoming = json!({"elements": [el("a", 2, 1)]});
+        assert_eq!(elements(&merge_scenes(&stored, &incoming))[0]["versionNonce"], 1);
+    }
+
+    #[test]
+    fn newer_tombstone_is_not_resurrected() {
+        let mut deleted = el("a", 5, 1);
+        deleted["isDeleted"] = json!(true);
+        let stored = json!({"elements": [deleted]});
+        let incoming = json!({"elements": [el("a", 4, 1)]});
+
+        let merged = merge_scenes(&stored, &incoming);
+        assert_eq!(elements(&merged).len(), 1);
+        assert_eq!(elements(&merged)[0]["isDeleted"], true);
+    }
+
+    #[test]
+    fn server_only_elements_keep_their_position() {
+        let stored = json!({"elements": [el("a", 1, 1), el("x", 1, 1), el("b", 1, 1)]});
+        let incoming = json!({"elements": [el("a", 1, 1), el("b", 1, 1), el("c", 1, 1)]});
+
+        assert_eq!(ids(&merge_scenes(&stored, &incoming)), ["a", "x", "b", "c"]);
+
+        let stored = json!({"elements": [el("x", 1, 1), el("a", 1, 1)]});
+        let incoming = json!({"elements": [el("a", 1, 1)]});
+        assert_eq!(ids(&merge_scenes(&stored, &incoming)), ["x", "a"]);
+    }
+
+    #[test]
+    fn unions_files_and_takes_incoming_app_state() {
+        let stored = json!({
+            "elements": [],
+            "appState": {"viewBackgroundColor": "#fff"},
+            "files": {"f1": {"id": "f1"}}
+        });
+        let incoming = json!({
+            "elements": [],
+            "appState": {"viewBackgroundColor": "#000"},
+            "files": {"f2": {"id": "f2"}}
+        });
+
+        let merged = merge_scenes(&stored, &incoming);
+        assert_eq!(merged["appState"]["viewBackgroundColor"], "#000");
+        assert!(merged["files"].get("f1").is_some());
+        assert!(merged["files"].get("f2").is_some());
+    }
+
+    #[test]
+    fn keeps_offloaded_files_over_inline_copies() {
+        let stored = json!({"files": {"f1": {"dataURL": "/api/images/abc", "imageId": "abc"}}});
+        let incoming = json!({"files": {
+            "f1": {"dataURL": "data:image/png;base64,AAAA"},
+            "f2": {"dataURL": "data:image/png;base64,BBBB"}
+        }});
+
+        let merged = merge_scenes(&stored, &incoming);
+        assert_eq!(merged["files"]["f1"]["dataURL"], "/api/images/abc");
+        assert_eq!(merged["files"]["f2"]["dataURL"], "data:image/png;base64,BBBB");
+    }
+
+    #[test]
+    fn accepts_string_encoded_scenes() {
+        let stored = Value::String(json!({"elements": [el("a", 1, 1)]}).to_string());
+        let incoming = Value::String(json!({"elements": [el("b", 1, 1)]}).to_string());
+
+        assert_eq!(ids(&merge_scenes(&stored, &incoming)), ["a", "b"]);
+    }
+}
@@ -0,0 +1,71 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::Json,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    auth::authenticate,
+    models::SessionResponse,
+    sessions,
+    state::AppState,
+};
+
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SessionResponse>>, StatusCode> {
+    let claims = authenticate(&state, &headers).await?;
+
+    let rows = sessions::list_sessions(&state.pool, claims.uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let sessions = rows
+        .into_iter()
+        .map(|row| SessionResponse {
+            current: row.id == claims.sid,
+            id: row.id,
+            created_at: row.created_at,
+            last_seen_at: row.last_seen_at,
+            user_agent: row.user_agent,
+            ip: row.ip,
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+// 注销指定会话，例如丢失的设备；它持有的 access token 随即失效
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = authenticate(&state, &headers).await?;
+
+    // 只能注销自己的会话，其他用户的会话一律视为不存在
+    let revoked = sessions::revoke_session(&state.pool, &id, claims.uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 注销除当前会话以外的所有会话
+pub async fn revoke_other_sessions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = authenticate(&state, &headers).await?;
+
+    let revoked = sessions::revoke_other_sessions(&state.pool, claims.uid, &claims.sid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "revoked": revoked })))
+}
@@ -0,0 +1,312 @@
+use anyhow::Result;
+use axum::http::{header, HeaderMap};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{SqliteExecutor, SqlitePool};
+use std::net::SocketAddr;
+use uuid::Uuid;
+
+use crate::{
+    auth::{generate_jwt_token, random_token, token_hash},
+    models::{SessionRow, User},
+    state::AppState,
+};
+
+// Presenting a refresh token that was already rotated away means it was copied, so the whole
+// session is revoked. Right after a rotation this is tolerated: another tab of the same browser
+// may have refreshed at the same moment.
+const REUSE_GRACE_SECS: i64 = 30;
+
+// Don't write last_seen_at on every request; this is precise enough for the session list
+const LAST_SEEN_RESOLUTION_SECS: i64 = 60;
+const MAX_USER_AGENT_LEN: usize = 512;
+
+// Where a login came from, recorded for the session list
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl ClientInfo {
+    pub fn new(headers: &HeaderMap, peer: SocketAddr) -> Self {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok())
+            .map(|ua| ua.chars().take(MAX_USER_AGENT_LEN).collect());
+        Self {
+            user_agent,
+            ip: Some(peer.ip().to_string()),
+        }
+    }
+}
+
+pub struct IssuedTokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+fn issue(state: &AppState, user: &User, session_id: &str, refresh_token: String) -> Result<IssuedTokens> {
+    let access_token = generate_jwt_token(state, user.id, user.username.clone(), session_id)?;
+    Ok(IssuedTokens {
+        access_token,
+        refresh_token,
+        expires_in: state.config.auth.token_ttl_secs,
+    })
+}
+
+// Record a new login and issue its first pair of tokens
+pub async fn create_session(state: &AppState, user: &User, client: &ClientInfo) -> Result<IssuedTokens> {
+    let now = Utc::now();
+
+    // 顺便清理该用户已过期或已注销的会话
+    sqlx::query("DELETE FROM sessions WHERE uid = ? AND (expires_at <= ? OR revoked_at IS NOT NULL)")
+        .bind(user.id)
+        .bind(now)
+        .execute(&state.pool)
+        .await?;
+
+    let id = Uuid::new_v4().to_string();
+    let refresh_token = random_token(48);
+    sqlx::query(
+        r#"
+        INSERT INTO sessions (id, uid, refresh_token_hash, created_at, last_seen_at, expires_at, user_agent, ip)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(&id)
+    .bind(user.id)
+    .bind(token_hash(&refresh_token))
+    .bind(now)
+    .bind(now)
+    .bind(now + Duration::seconds(state.config.auth.refresh_token_ttl_secs))
+    .bind(&client.user_agent)
+    .bind(&client.ip)
+    .execute(&state.pool)
+    .await?;
+
+    issue(state, user, &id, refresh_token)
+}
+
+// Exchange a refresh token for a new access token and a new refresh token. The old refresh
+// token stops working and the session's expiry moves forward. Returns None when the token is
+// unknown, expired or revoked.
+pub async fn refresh_session(state: &AppState, refresh_token: &str) -> Result<Option<(User, IssuedTokens)>> {
+    let now = Utc::now();
+    let hash = token_hash(refresh_token);
+    let new_token = random_token(48);
+
+    // 条件更新保证同一个 refresh token 只能成功使用一次
+    let rotated: Option<(String, i64)> = sqlx::query_as(
+        r#"
+        UPDATE sessions
+        SET previous_token_hash = refresh_token_hash, refresh_token_hash = ?, rotated_at = ?, last_seen_at = ?,
+            expires_at = ?
+        WHERE refresh_token_hash = ? AND revoked_at IS NULL AND expires_at > ?
+        RETURNING id, uid
+        "#
+    )
+    .bind(token_hash(&new_token))
+    .bind(now)
+    .bind(now)
+    .bind(now + Duration::seconds(state.config.auth.refresh_token_ttl_secs))
+    .bind(&hash)
+    .bind(now)
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let Some((session_id, uid)) = rotated else {
+        let reused: Option<String> = sqlx::query_scalar(
+            r#"
+            UPDATE sessions SET revoked_at = ?
+            WHERE previous_token_hash = ? AND revoked_at IS NULL AND rotated_at < ?
+            RETURNING id
+            "#
+        )
+        .bind(now)
+        .bind(&hash)
+        .bind(now - Duration::seconds(REUSE_GRACE_SECS))
+        .fetch_optional(&state.pool)
+        .await?;
+        if let Some(session_id) = reused {
+            tracing::warn!("Refresh token of session {} was used again after rotation; session revoked", session_id);
+        }
+        return Ok(None);
+    };
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+    )
+    .bind(uid)
+    .fetch_optional(&state.pool)
+    .await?;
+    let Some(user) = user else {
+        revoke_session(&state.pool, &session_id, uid).await?;
+        return Ok(None);
+    };
+
+    let tokens = issue(state, &user, &session_id, new_token)?;
+    Ok(Some((user, tokens)))
+}
+
+// Whether the session is still valid, noting that it was just used
+pub async fn touch_session(pool: &SqlitePool, session_id: &str, uid: i64) -> Result<bool, sqlx::Error> {
+    let now = Utc::now();
+    let last_seen: Option<Option<DateTime<Utc>>> = sqlx::query_scalar(
+        "SELECT last_seen_at FROM sessions WHERE id = ? AND uid = ? AND revoked_at IS NULL AND expires_at > ?"
+    )
+    .bind(session_id)
+    .bind(uid)
+    .bind(now)
+    .fetch_optional(pool)
+    .await?;
+
+    let Some(last_seen) = last_seen else {
+        return Ok(false);
+    };
+    if last_seen.is_none_or(|seen| now - seen >= Duration::seconds(LAST_SEEN_RESOLUTION_SECS)) {
+        sqlx::query("UPDATE sessions SET last_seen_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(session_id)
+            .execute(pool)
+            .await?;
+    }
+    Ok(true)
+}
+
+// When an active session was started, i.e. when the user last signed in on it
+pub async fn session_started_at(pool: &SqlitePool, session_id: &str, uid: i64) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT created_at FROM sessions WHERE id = ? AND uid = ? AND revoked_at IS NULL AND expires_at > ?"
+    )
+    .bind(session_id)
+    .bind(uid)
+    .bind(Utc::now())
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn list_sessions(pool: &SqlitePool, uid: i64) -> Result<Vec<SessionRow>, sqlx::Error> {
+    sqlx::query_as::<_, SessionRow>(
+        r#"
+        SELECT id, created_at, last_seen_at, user_agent, ip FROM sessions
+        WHERE uid = ? AND revoked_at IS NULL AND expires_at > ?
+        ORDER BY COALESCE(last_seen_at, created_at) DESC
+        "#
+    )
+    .bind(uid)
+    .bind(Utc::now())
+    .fetch_all(pool)
+    .await
+}
+
+// Revoke every session of the user except `keep`; returns how many were revoked
+pub async fn revoke_other_sessions(pool: &SqlitePool, uid: i64, keep: &str) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE uid = ? AND id != ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(uid)
+        .bind(keep)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+pub async fn revoke_all_sessions<'e>(executor: impl SqliteExecutor<'e>, uid: i64) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE uid = ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(uid)
+        .execute(executor)
+        .await?;
+    Ok(result.rows_affected())
+}
+
+// Returns false when the session does not exist or was already revoked
+pub async fn revoke_session(pool: &SqlitePool, session_id: &str, uid: i64) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE id = ? AND uid = ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(session_id)
+        .bind(uid)
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub async fn revoke_refresh_token(pool: &SqlitePool, refresh_token: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE refresh_token_hash = ? AND revoked_at IS NULL")
+        .bind(Utc::now())
+        .bind(token_hash(refresh_token))
+        .execute(pool)
+        .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test_state;
+
+    async fn login(state: &AppState) -> (User, IssuedTokens) {
+        let now = Utc::now();
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (username, email, password_hash, created_at, updated_at) VALUES ('alice', 'alice@example.com', 'x', ?, ?)
+            RETURNING id, username, email, password_hash, verified_at, created_at, updated_at
+            "#
+        )
+        .bind(now)
+        .bind(now)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap();
+        let client = ClientInfo { user_agent: None, ip: None };
+        let tokens = create_session(state, &user, &client).await.unwrap();
+        (user, tokens)
+    }
+
+    // 模拟轮换发生在宽限期之前
+    async fn age_rotation(state: &AppState) {
+        sqlx::query("UPDATE sessions SET rotated_at = ?")
+            .bind(Utc::now() - Duration::seconds(REUSE_GRACE_SECS + 1))
+            .execute(&state.pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refresh_rotates_token() {
+        let state = test_state().await;
+        let (user, first) = login(&state).await;
+
+        let (refreshed_user, second) = refresh_session(&state, &first.refresh_token).await.unwrap().unwrap();
+        assert_eq!(refreshed_user.id, user.id);
+        assert_ne!(second.refresh_token, first.refresh_token);
+
+        // 同时刷新的另一个标签页拿不到新令牌，但不会导致会话被注销
+        assert!(refresh_session(&state, &first.refresh_token).await.unwrap().is_none());
+        let (_, third) = refresh_session(&state, &second.refresh_token).await.unwrap().unwrap();
+        assert!(refresh_session(&state, &third.refresh_token).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn reused_token_revokes_session() {
+        let state = test_state().await;
+        let (user, first) = login(&state).await;
+        let (_, second) = refresh_session(&state, &first.refresh_token).await.unwrap().unwrap();
+        let claims: crate::auth::Claims = state.jwt_keys.verify(&second.access_token).unwrap();
+        age_rotation(&state).await;
+
+        assert!(refresh_session(&state, &first.refresh_token).await.unwrap().is_none());
+        // 复用被发现后，合法持有者的令牌也一起失效
+        assert!(refresh_session(&state, &second.refresh_token).await.unwrap().is_none());
+        assert!(!touch_session(&state.pool, &claims.sid, user.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn revoked_or_unknown_token_is_refused() {
+        let state = test_state().await;
+        let (_, tokens) = login(&state).await;
+
+        assert!(refresh_session(&state, "unknown").await.unwrap().is_none());
+        assert!(revoke_refresh_token(&state.pool, &tokens.refresh_token).await.unwrap());
+        assert!(refresh_session(&state, &tokens.refresh_token).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,437 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    access::{can_view_image, require_role},
+    auth::{extract_uid_from_headers, hash_password, random_token, sign_url, token_hash, verify_password, verify_url_signature},
+    image_handlers::image_file_response,
+    models::{
+        CreateShareRequest, Image, ProjectRow, ProjectShare, Role, ShareAccess, ShareResponse, SharedProject,
+        UnlockShareRequest,
+    },
+    scene::{image_id_from_url, normalize_content, referenced_image_ids},
+    state::AppState,
+};
+
+const SHARE_TOKEN_LEN: usize = 40;
+const MAX_SHARE_TTL_SECS: i64 = 5 * 365 * 24 * 60 * 60;
+// How long the access grant for a password protected share stays valid
+const SHARE_ACCESS_TTL_SECS: i64 = 12 * 60 * 60;
+const SHARE_ACCESS_HEADER: &str = "x-share-access";
+
+#[derive(Debug, Deserialize)]
+pub struct ShareAccessQuery {
+    // 图片通过 <img> 加载时无法设置请求头
+    pub access: Option<String>,
+}
+
+pub async fn create_share(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(req): Json<CreateShareRequest>,
+) -> Result<Json<ShareResponse>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+
+    let now = Utc::now();
+    let expires_at = match req.expires_in_secs {
+        Some(secs) if !(1..=MAX_SHARE_TTL_SECS).contains(&secs) => return Err(StatusCode::BAD_REQUEST),
+        Some(secs) => Some(
+            TimeDelta::try_seconds(secs)
+                .and_then(|ttl| now.checked_add_signed(ttl))
+                .ok_or(StatusCode::BAD_REQUEST)?,
+        ),
+        None => None,
+    };
+
+    let password_hash = match req.password.as_deref().filter(|p| !p.is_empty()) {
+        Some(password) => Some(hash_password(password).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?),
+        None => None,
+    };
+
+    // 只保存 token 的哈希，链接只在这里返回一次
+    let token = random_token(SHARE_TOKEN_LEN);
+    let share = sqlx::query_as::<_, ProjectShare>(
+        r#"
+        INSERT INTO project_shares (id, project_id, token_hash, password_hash, expires_at, created_by, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?)
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(&id)
+    .bind(token_hash(&token))
+    .bind(password_hash)
+    .bind(expires_at)
+    .bind(uid)
+    .bind(now)
+    .fetch_one(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut response = share.to_response();
+    response.url = Some(format!("/shared/{}", token));
+    response.token = Some(token);
+    Ok(Json(response))
+}
+
+pub async fn list_shares(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<ShareResponse>>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+
+    let shares = sqlx::query_as::<_, ProjectShare>(
+        "SELECT * FROM project_shares WHERE project_id = ? ORDER BY created_at DESC"
+    )
+    .bind(&id)
+    .fetch_all(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(shares.iter().map(ProjectShare::to_response).collect()))
+}
+
+pub async fn revoke_share(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((id, share_id)): Path<(String, String)>,
+) -> Result<StatusCode, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    require_role(&state.pool, &id, uid, Role::Editor).await?;
+
+    let result = sqlx::query("DELETE FROM project_shares WHERE id = ? AND project_id = ?")
+        .bind(&share_id)
+        .bind(&id)
+        .execute(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 无需登录：通过分享 token 读取项目
+pub async fn get_shared(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+    Query(query): Query<ShareAccessQuery>,
+) -> Result<Json<SharedProject>, Response> {
+    let share = resolve_share(&state, &token, &headers, query.access.as_deref()).await?;
+    let project = load_project(&state, &share.project_id).await.map_err(IntoResponse::into_response)?;
+
+    let mut content = normalize_content(&serde_json::from_str(&project.content).unwrap_or(json!({})));
+
+    let mut images = Vec::new();
+    for image_id in referenced_image_ids(&content) {
+        if let Some(image) = shareable_image(&state, &share, &image_id).await.map_err(IntoResponse::into_response)? {
+            images.push(image);
+        }
+    }
+
+    // 场景中的图片地址改为分享链接下的地址，查看者无需登录即可加载
+    if let Some(files) = content.get_mut("files").and_then(Value::as_object_mut) {
+        for file in files.values_mut() {
+            let Some(image_id) = file.get("dataURL").and_then(Value::as_str).and_then(image_id_from_url) else {
+                continue;
+            };
+            if images.iter().any(|image| image.id == image_id) {
+                file["dataURL"] = json!(format!("/api/shared/{}/images/{}", token, image_id));
+            }
+        }
+    }
+
+    let images = images
+        .iter()
+        .map(|image| {
+            let mut response = image.to_response();
+            response.url = format!("/api/shared/{}/images/{}", token, image.id);
+            response
+        })
+        .collect();
+
+    Ok(Json(SharedProject {
+        name: project.name,
+        content,
+        images,
+        updated_at: project.updated_at,
+    }))
+}
+
+pub async fn get_shared_image(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((token, image_id)): Path<(String, String)>,
+    Query(query): Query<ShareAccessQuery>,
+) -> Result<Response, Response> {
+    let share = resolve_share(&state, &token, &headers, query.access.as_deref()).await?;
+    let project = load_project(&state, &share.project_id).await.map_err(IntoResponse::into_response)?;
+
+    // 只允许访问该项目场景中引用的图片
+    let content: Value = serde_json::from_str(&project.content).unwrap_or(json!({}));
+    if !referenced_image_ids(&content).contains(&image_id) {
+        return Err(StatusCode::NOT_FOUND.into_response());
+    }
+
+    let image = shareable_image(&state, &share, &image_id)
+        .await
+        .map_err(IntoResponse::into_response)?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    image_file_response(&state, &image, &headers, "private, max-age=300")
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
+// Exchange the password of a protected share for a short-lived access grant, so the password is
+// checked once instead of with every image request
+pub async fn unlock_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(req): Json<UnlockShareRequest>,
+) -> Result<Json<ShareAccess>, Response> {
+    let share = find_share(&state, &token).await?;
+    let Some(password_hash) = &share.password_hash else {
+        return Err(StatusCode::BAD_REQUEST.into_response());
+    };
+
+    let valid = verify_password(&req.password, password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    if !valid {
+        return Err(password_required());
+    }
+
+    let mut expires_at = Utc::now() + TimeDelta::seconds(SHARE_ACCESS_TTL_SECS);
+    if let Some(share_expires_at) = share.expires_at {
+        expires_at = expires_at.min(share_expires_at);
+    }
+    Ok(Json(ShareAccess {
+        access: share_access(&state, &share, expires_at),
+        expires_at,
+    }))
+}
+
+// 授权里签名的是分享记录和密码哈希，撤销分享或修改密码后旧授权随之失效
+fn share_access(state: &AppState, share: &ProjectShare, expires_at: DateTime<Utc>) -> String {
+    let expires = expires_at.timestamp();
+    let subject = format!("share:{}:{}", share.id, share.password_hash.as_deref().unwrap_or_default());
+    format!("{}.{}", expires, sign_url(&state.config.auth, &subject, expires))
+}
+
+fn verify_share_access(state: &AppState, share: &ProjectShare, access: &str) -> bool {
+    let Some((expires, signature)) = access.split_once('.') else {
+        return false;
+    };
+    let Ok(expires) = expires.parse::<i64>() else {
+        return false;
+    };
+    let subject = format!("share:{}:{}", share.id, share.password_hash.as_deref().unwrap_or_default());
+    verify_url_signature(&state.config.auth, &subject, expires, signature)
+}
+
+async fn find_share(state: &AppState, token: &str) -> Result<ProjectShare, Response> {
+    let share = sqlx::query_as::<_, ProjectShare>("SELECT * FROM project_shares WHERE token_hash = ?")
+        .bind(token_hash(token))
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
+
+    if share.expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(StatusCode::GONE.into_response());
+    }
+
+    Ok(share)
+}
+
+async fn resolve_share(
+    state: &AppState,
+    token: &str,
+    headers: &HeaderMap,
+    query_access: Option<&str>,
+) -> Result<ProjectShare, Response> {
+    let share = find_share(state, token).await?;
+
+    if share.password_hash.is_some() {
+        let access = headers
+            .get(SHARE_ACCESS_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .or(query_access)
+            .ok_or_else(password_required)?;
+        if !verify_share_access(state, &share, access) {
+            return Err(password_required());
+        }
+    }
+
+    Ok(share)
+}
+
+// A scene can reference any image id, so an image is only served through a share when it belongs
+// to the shared project or its creator could see it anyway
+async fn shareable_image(state: &AppState, share: &ProjectShare, image_id: &str) -> Result<Option<Image>, StatusCode> {
+    let image = sqlx::query_as::<_, Image>("SELECT * FROM images WHERE id = ?")
+        .bind(image_id)
+        .fetch_optional(&state.pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(image) = image else {
+        return Ok(None);
+    };
+
+    if image.project_id.as_deref() == Some(share.project_id.as_str()) {
+        return Ok(Some(image));
+    }
+    let allowed = can_view_image(&state.pool, &image, share.created_by)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(allowed.then_some(image))
+}
+
+async fn load_project(state: &AppState, id: &str) -> Result<ProjectRow, StatusCode> {
+    sqlx::query_as::<_, ProjectRow>(
+        "SELECT id, name, content, uid, version, created_at, updated_at FROM projects WHERE id = ?"
+    )
+    .bind(id)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+fn password_required() -> Response {
+    (StatusCode::UNAUTHORIZED, Json(json!({"error": "password_required"}))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{test_image, test_project, test_state, test_user};
+
+    async fn share(state: &AppState, headers: &HeaderMap, project_id: &str, password: Option<&str>) -> ShareResponse {
+        let req = CreateShareRequest { expires_in_secs: Some(3600), password: password.map(str::to_string) };
+        let Json(share) = create_share(State(state.clone()), headers.clone(), Path(project_id.to_string()), Json(req))
+            .await
+            .unwrap();
+        share
+    }
+
+    async fn open(state: &AppState, token: &str, access: Option<String>) -> Result<SharedProject, StatusCode> {
+        let query = Query(ShareAccessQuery { access });
+        get_shared(State(state.clone()), HeaderMap::new(), Path(token.to_string()), query)
+            .await
+            .map(|Json(project)| project)
+            .map_err(|response| response.status())
+    }
+
+    async fn open_image(state: &AppState, token: &str, image_id: &str) -> StatusCode {
+        let query = Query(ShareAccessQuery { access: None });
+        let path = Path((token.to_string(), image_id.to_string()));
+        match get_shared_image(State(state.clone()), HeaderMap::new(), path, query).await {
+            Ok(response) => response.status(),
+            Err(response) => response.status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_only_images_the_share_may_show() {
+        let state = test_state().await;
+        let (alice, alice_headers) = test_user(&state, "alice").await;
+        let (bob, _) = test_user(&state, "bob").await;
+
+        let bobs_project = test_project(&state, bob, &json!({})).await;
+        let private = test_image(&state, bob, Some(&bobs_project), 30).await;
+        let personal = test_image(&state, alice, None, 20).await;
+
+        let project = test_project(&state, alice, &json!({})).await;
+        let own = test_image(&state, alice, Some(&project), 10).await;
+        let scene = json!({
+            "elements": [],
+            "files": {
+                "own": { "dataURL": format!("/api/images/{}", own.id) },
+                "personal": { "dataURL": format!("/api/images/{}", personal.id) },
+                "private": { "dataURL": format!("/api/images/{}", private.id) },
+            },
+        });
+        sqlx::query("UPDATE projects SET content = ? WHERE id = ?")
+            .bind(scene.to_string())
+            .bind(&project)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+
+        let token = share(&state, &alice_headers, &project, None).await.token.unwrap();
+        let shared = open(&state, &token, None).await.unwrap();
+
+        let mut ids: Vec<_> = shared.images.iter().map(|image| image.id.clone()).collect();
+        ids.sort();
+        let mut expected = vec![own.id.clone(), personal.id.clone()];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert_eq!(shared.content["files"]["own"]["dataURL"], format!("/api/shared/{}/images/{}", token, own.id));
+        assert_eq!(shared.content["files"]["private"]["dataURL"], format!("/api/images/{}", private.id));
+
+        assert_eq!(open_image(&state, &token, &own.id).await, StatusCode::OK);
+        assert_eq!(open_image(&state, &token, &personal.id).await, StatusCode::OK);
+        // 其他项目的图片即使写进场景也不能通过分享下载
+        assert_eq!(open_image(&state, &token, &private.id).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn password_protected_shares_need_access() {
+        let state = test_state().await;
+        let (alice, headers) = test_user(&state, "alice").await;
+        let project = test_project(&state, alice, &json!({})).await;
+        let token = share(&state, &headers, &project, Some("secret")).await.token.unwrap();
+
+        assert_eq!(open(&state, &token, None).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(open(&state, &token, Some("1.forged".to_string())).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let unlock = |password: &str| {
+            let req = UnlockShareRequest { password: password.to_string() };
+            unlock_share(State(state.clone()), Path(token.clone()), Json(req))
+        };
+        assert_eq!(unlock("wrong").await.unwrap_err().status(), StatusCode::UNAUTHORIZED);
+        let Json(access) = unlock("secret").await.unwrap();
+
+        assert!(open(&state, &token, Some(access.access)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_or_revoked_shares_are_refused() {
+        let state = test_state().await;
+        let (alice, headers) = test_user(&state, "alice").await;
+        let project = test_project(&state, alice, &json!({})).await;
+
+        let expired = share(&state, &headers, &project, None).await;
+        sqlx::query("UPDATE project_shares SET expires_at = ? WHERE id = ?")
+            .bind(Utc::now() - TimeDelta::seconds(1))
+            .bind(&expired.id)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+        assert_eq!(open(&state, &expired.token.unwrap(), None).await.unwrap_err(), StatusCode::GONE);
+
+        let revoked = share(&state, &headers, &project, None).await;
+        let token = revoked.token.unwrap();
+        assert!(open(&state, &token, None).await.is_ok());
+        let status = revoke_share(State(state.clone()), headers.clone(), Path((project.clone(), revoked.id)))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(open(&state, &token, None).await.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,130 @@
+use axum::extract::multipart::{Field, MultipartError};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+// Enough of the file to detect its type and, for nearly every image, its dimensions
+const HEAD_BYTES: usize = 256 * 1024;
+
+// An upload written to a temporary file while it arrives, so it never has to fit in memory.
+// The file is removed when this is dropped.
+pub struct SpooledFile {
+    path: PathBuf,
+    pub size: u64,
+    pub sha256: String,
+    pub head: Vec<u8>,
+}
+
+impl SpooledFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+pub enum SpoolError {
+    // The field is larger than the limit; reading stopped at the first chunk past it
+    TooLarge,
+    Read(MultipartError),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for SpoolError {
+    fn from(e: std::io::Error) -> Self {
+        SpoolError::Io(e)
+    }
+}
+
+pub async fn spool_field(field: &mut Field<'_>, limit: u64) -> Result<SpooledFile, SpoolError> {
+    let path = std::env::temp_dir().join(format!("venus-upload-{}", Uuid::new_v4()));
+    let mut file = tokio::fs::File::create(&path).await?;
+    // 从这里开始由 SpooledFile 负责删除临时文件，包括出错提前返回的情况
+    let mut spooled = SpooledFile { path, size: 0, sha256: String::new(), head: Vec::new() };
+    let mut hasher = Sha256::new();
+
+    while let Some(chunk) = field.chunk().await.map_err(SpoolError::Read)? {
+        spooled.size += chunk.len() as u64;
+        if spooled.size > limit {
+            return Err(SpoolError::TooLarge);
+        }
+
+        if spooled.head.len() < HEAD_BYTES {
+            let take = chunk.len().min(HEAD_BYTES - spooled.head.len());
+            spooled.head.extend_from_slice(&chunk[..take]);
+        }
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+    }
+
+    file.flush().await?;
+    spooled.sha256 = hex::encode(hasher.finalize());
+    Ok(spooled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        extract::{FromRequest, Multipart},
+        http::{header, Request},
+    };
+
+    const BOUNDARY: &str = "venus-test-boundary";
+
+    async fn multipart(data: &[u8]) -> Multipart {
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"a.bin\"\r\n\r\n",
+            BOUNDARY
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn spools_fields_to_disk() {
+        let data = sample(HEAD_BYTES + 1000);
+        let mut multipart = multipart(&data).await;
+        let mut field = multipart.next_field().await.unwrap().unwrap();
+
+        let Ok(spooled) = spool_field(&mut field, data.len() as u64).await else {
+            panic!("upload within the limit was refused");
+        };
+        assert_eq!(spooled.size, data.len() as u64);
+        assert_eq!(spooled.sha256, hex::encode(Sha256::digest(&data)));
+        assert_eq!(spooled.head, data[..HEAD_BYTES]);
+        assert_eq!(std::fs::read(spooled.path()).unwrap(), data);
+
+        // 释放后临时文件被删除
+        let path = spooled.path().to_path_buf();
+        drop(spooled);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_fields_over_the_limit() {
+        let data = sample(1000);
+        let mut multipart = multipart(&data).await;
+        let mut field = multipart.next_field().await.unwrap().unwrap();
+
+        let result = spool_field(&mut field, data.len() as u64 - 1).await;
+        assert!(matches!(result, Err(SpoolError::TooLarge)));
+    }
+}
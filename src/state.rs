@@ -0,0 +1,106 @@
+use sqlx::SqlitePool;
+use std::sync::Arc;
+
+use crate::{
+    collab::Rooms, config::Config, jwt_keys::JwtKeys, mailer::Mailer, oidc::OidcClient, proxy::ReverseProxy,
+    storage::BlobStore,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: SqlitePool,
+    pub config: Arc<Config>,
+    pub jwt_keys: Arc<JwtKeys>,
+    pub rooms: Rooms,
+    pub blobs: Arc<dyn BlobStore>,
+    pub mailer: Arc<dyn Mailer>,
+    // Set when single sign-on is configured
+    pub oidc: Option<Arc<OidcClient>>,
+    pub proxy: Arc<ReverseProxy>,
+}
+
+// An AppState over database::test_pool that signs tokens with HS256, stores files in a fresh
+// temporary directory and logs emails
+#[cfg(test)]
+pub async fn test_state() -> AppState {
+    use crate::{auth::random_token, config::JwtAlgorithm, database::test_pool, mailer, oidc, storage};
+
+    let mut config = Config::default();
+    config.auth.jwt_algorithm = JwtAlgorithm::Hs256;
+    config.auth.jwt_secret = random_token(64);
+    config.auth.url_signing_secret = random_token(64);
+    config.storage.upload_dir = std::env::temp_dir().join(format!("venus-test-{}", uuid::Uuid::new_v4()));
+
+    AppState {
+        pool: test_pool().await,
+        jwt_keys: Arc::new(JwtKeys::load(&config).unwrap()),
+        rooms: Rooms::default(),
+        blobs: storage::build(&config.storage).unwrap(),
+        mailer: mailer::build(&config.mail).unwrap(),
+        oidc: oidc::build(&config.oidc).unwrap(),
+        proxy: Arc::new(ReverseProxy::new(&config).unwrap()),
+        config: Arc::new(config),
+    }
+}
+
+// Register a user and sign them in; returns their id and request headers carrying the session
+#[cfg(test)]
+pub async fn test_user(state: &AppState, username: &str) -> (i64, axum::http::HeaderMap) {
+    use crate::{
+        models::User,
+        sessions::{create_session, ClientInfo},
+    };
+
+    let now = chrono::Utc::now();
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (username, email, password_hash, created_at, updated_at) VALUES (?, ?, 'x', ?, ?)
+        RETURNING id, username, email, password_hash, verified_at, created_at, updated_at
+        "#
+    )
+    .bind(username)
+    .bind(format!("{}@example.com", username))
+    .bind(now)
+    .bind(now)
+    .fetch_one(&state.pool)
+    .await
+    .unwrap();
+
+    let client = ClientInfo { user_agent: None, ip: None };
+    let token = create_session(state, &user, &client).await.unwrap().access_token;
+    let mut headers = axum::http::HeaderMap::new();
+    headers.insert(axum::http::header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+    (user.id, headers)
+}
+
+// Create a project with the given scene, owned by `uid`
+#[cfg(test)]
+pub async fn test_project(state: &AppState, uid: i64, content: &serde_json::Value) -> String {
+    let id = uuid::Uuid::new_v4().to_string();
+    sqlx::query("INSERT INTO projects (id, name, content, uid) VALUES (?, 'test', ?, ?)")
+        .bind(&id)
+        .bind(content.to_string())
+        .bind(uid)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO project_members (project_id, uid, role) VALUES (?, ?, 'owner')")
+        .bind(&id)
+        .bind(uid)
+        .execute(&state.pool)
+        .await
+        .unwrap();
+    id
+}
+
+// Upload a blank PNG `width` pixels wide; images of the same width share one blob
+#[cfg(test)]
+pub async fn test_image(state: &AppState, uid: i64, project_id: Option<&str>, width: u32) -> crate::models::Image {
+    use crate::{image_handlers::create_image, image_probe::probe};
+    use image::{DynamicImage, ImageFormat};
+
+    let mut png = std::io::Cursor::new(Vec::new());
+    DynamicImage::new_rgb8(width, 10).write_to(&mut png, ImageFormat::Png).unwrap();
+    let data = png.into_inner();
+    create_image(state, &data, probe(&data).unwrap(), "a.png", project_id, uid).await.unwrap()
+}
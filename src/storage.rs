@@ -0,0 +1,171 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    config::{StorageBackend, StorageConfig},
+    http_cache::ByteRange,
+    s3::S3BlobStore,
+};
+
+// Storage for uploaded image bytes. Keys are flat file names such as `<uuid>.png`; metadata
+// lives in the `images` table.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn put(&self, key: &str, data: Vec<u8>, content_type: &str) -> Result<()>;
+
+    // Store the contents of a local file without reading it into memory
+    async fn put_file(&self, key: &str, path: &Path, content_type: &str) -> Result<()>;
+
+    // Returns None when the blob does not exist
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    // Stream a blob, or the part of it selected by `range`, without buffering it. Returns None
+    // when the blob does not exist.
+    async fn read(&self, key: &str, range: Option<ByteRange>) -> Result<Option<BlobRead>>;
+
+    // Deleting a missing blob is not an error
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    // Every stored blob, used by the garbage collector to find files nothing points at
+    async fn list(&self) -> Result<Vec<BlobEntry>>;
+}
+
+#[derive(Debug, Clone)]
+pub struct BlobEntry {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+}
+
+pub type BlobBody = BoxStream<'static, std::io::Result<Bytes>>;
+
+pub enum BlobRead {
+    Found {
+        // Size of the whole blob
+        total: u64,
+        // Inclusive span being returned, None for the whole blob
+        range: Option<(u64, u64)>,
+        body: BlobBody,
+    },
+    // The requested range lies outside the blob
+    Unsatisfiable { total: u64 },
+}
+
+pub fn build(config: &StorageConfig) -> Result<Arc<dyn BlobStore>> {
+    let store: Arc<dyn BlobStore> = match config.backend {
+        StorageBackend::Filesystem => Arc::new(FsBlobStore::new(config.upload_dir.clone())?),
+        StorageBackend::S3 => Arc::new(S3BlobStore::new(&config.s3)?),
+    };
+    Ok(store)
+}
+
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(root: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("failed to create upload directory {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        // 键由服务端生成，这里仍然拒绝任何可能逃出根目录的路径
+        if key.is_empty() || key.contains(['/', '\\']) || key.starts_with('.') {
+            bail!("invalid blob key {:?}", key);
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, key: &str, data: Vec<u8>, _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        tokio::fs::write(&path, data)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    async fn put_file(&self, key: &str, source: &Path, _content_type: &str) -> Result<()> {
+        let path = self.path(key)?;
+        tokio::fs::copy(source, &path)
+            .await
+            .with_context(|| format!("failed to write {}", path.display()))
+            .map(drop)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    async fn read(&self, key: &str, range: Option<ByteRange>) -> Result<Option<BlobRead>> {
+        let path = self.path(key)?;
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("failed to open {}", path.display())),
+        };
+        let total = file.metadata().await?.len();
+
+        let Some(range) = range else {
+            return Ok(Some(BlobRead::Found { total, range: None, body: ReaderStream::new(file).boxed() }));
+        };
+        let Some((start, end)) = range.resolve(total) else {
+            return Ok(Some(BlobRead::Unsatisfiable { total }));
+        };
+
+        file.seek(SeekFrom::Start(start)).await?;
+        let body = ReaderStream::new(file.take(end - start + 1)).boxed();
+        Ok(Some(BlobRead::Found { total, range: Some((start, end)), body }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).with_context(|| format!("failed to delete {}", path.display())),
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<BlobEntry>> {
+        let mut entries = Vec::new();
+        let mut dir = tokio::fs::read_dir(&self.root)
+            .await
+            .with_context(|| format!("failed to list {}", self.root.display()))?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let Some(key) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if !metadata.is_file() || key.starts_with('.') {
+                continue;
+            }
+            entries.push(BlobEntry {
+                key,
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+            });
+        }
+
+        Ok(entries)
+    }
+}
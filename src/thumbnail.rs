@@ -0,0 +1,521 @@
+use anyhow::Result;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageFormat, ImageReader};
+use serde_json::Value;
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{Cursor, Read},
+    path::PathBuf,
+};
+
+use crate::{
+    image_probe::{sniff, ImageKind},
+    models::{Image, ImageVariant, ImageVariantRow},
+    scene::normalize_content,
+    state::AppState,
+};
+
+const JPEG_QUALITY: u8 = 85;
+const SCENE_THUMBNAIL_SIZE: f64 = 256.0;
+const SCENE_PADDING: f64 = 10.0;
+// Freehand strokes can have thousands of points; a preview does not need them all
+const MAX_PATH_POINTS: usize = 64;
+
+pub struct EncodedVariant {
+    pub variant: ImageVariant,
+    pub kind: ImageKind,
+    pub data: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+// The bytes of an image to resize: already in memory, or a local file (such as a spooled
+// upload) that is decoded without being read into memory first
+pub enum ImageSource {
+    Bytes(Vec<u8>),
+    File(PathBuf),
+}
+
+impl ImageSource {
+    fn kind(&self) -> Result<Option<ImageKind>> {
+        match self {
+            ImageSource::Bytes(data) => Ok(sniff(data)),
+            ImageSource::File(path) => {
+                // 位图的文件头很短，SVG 认不出来也无妨，反正不生成变体
+                let mut head = Vec::new();
+                File::open(path)?.take(64).read_to_end(&mut head)?;
+                Ok(sniff(&head))
+            }
+        }
+    }
+
+    fn decode(&self) -> Result<DynamicImage> {
+        let image = match self {
+            ImageSource::Bytes(data) => image::load_from_memory(data)?,
+            ImageSource::File(path) => ImageReader::open(path)?.with_guessed_format()?.decode()?,
+        };
+        Ok(image)
+    }
+}
+
+// Downscale a raster image to every variant smaller than the original. SVGs scale natively and
+// images already within a variant's bounds are served as-is, so both produce no variants.
+pub fn resize_variants(source: &ImageSource, kind: ImageKind) -> Result<Vec<EncodedVariant>> {
+    if kind == ImageKind::Svg {
+        return Ok(Vec::new());
+    }
+
+    let original = source.decode()?;
+    let mut variants = Vec::new();
+
+    for variant in ImageVariant::ALL {
+        let max = variant.max_dimension();
+        if original.width().max(original.height()) <= max {
+            continue;
+        }
+
+        let resized = original.resize(max, max, FilterType::Triangle);
+        // 照片保持 JPEG，其余格式（可能带透明通道）统一输出 PNG
+        let kind = if kind == ImageKind::Jpeg { ImageKind::Jpeg } else { ImageKind::Png };
+        variants.push(EncodedVariant {
+            variant,
+            kind,
+            data: encode(&resized, kind)?,
+            width: resized.width(),
+            height: resized.height(),
+        });
+    }
+
+    Ok(variants)
+}
+
+fn encode(image: &DynamicImage, kind: ImageKind) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    match kind {
+        ImageKind::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY))?,
+        _ => image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?,
+    }
+    Ok(data)
+}
+
+// Generate and store the resized variants of an uploaded image; returns how many were written
+pub async fn store_variants(state: &AppState, image: &Image, source: ImageSource) -> Result<usize> {
+    let variants = tokio::task::spawn_blocking(move || match source.kind()? {
+        Some(kind) => resize_variants(&source, kind),
+        None => Ok(Vec::new()),
+    })
+    .await??;
+
+    // 变体与原图共用文件名前缀，内容相同的图片也就共用同一组变体文件
+    let stem = image.filename.rsplit_once('.').map_or(image.filename.as_str(), |(stem, _)| stem);
+
+    for encoded in &variants {
+        let filename = format!("{}_{}.{}", stem, encoded.variant.as_str(), encoded.kind.extension());
+        state.blobs.put(&filename, encoded.data.clone(), encoded.kind.mime_type()).await?;
+
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO image_variants (image_id, size, filename, mime_type, width, height)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#
+        )
+        .bind(&image.id)
+        .bind(encoded.variant)
+        .bind(&filename)
+        .bind(encoded.kind.mime_type())
+        .bind(encoded.width as i32)
+        .bind(encoded.height as i32)
+        .execute(&state.pool)
+        .await?;
+    }
+
+    Ok(variants.len())
+}
+
+// Point a deduplicated image at the variants already generated for the same blob, generating
+// them when no other image has any (e.g. the blob predates variants).
+pub async fn share_variants(state: &AppState, image: &Image, source: ImageSource) -> Result<usize> {
+    let copied = sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO image_variants (image_id, size, filename, mime_type, width, height)
+        SELECT ?, v.size, v.filename, v.mime_type, v.width, v.height
+        FROM image_variants v JOIN images i ON i.id = v.image_id
+        WHERE i.blob_hash = ? AND i.id != ?
+        "#
+    )
+    .bind(&image.id)
+    .bind(&image.blob_hash)
+    .bind(&image.id)
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+
+    if copied > 0 {
+        return Ok(copied as usize);
+    }
+    store_variants(state, image, source).await
+}
+
+// Forget an image's variants; their files are only removed together with the original blob
+pub async fn delete_variants(state: &AppState, image_id: &str, remove_files: bool) -> Result<()> {
+    let variants = sqlx::query_as::<_, ImageVariantRow>(
+        "SELECT filename, mime_type, width, height FROM image_variants WHERE image_id = ?"
+    )
+    .bind(image_id)
+    .fetch_all(&state.pool)
+    .await?;
+
+    sqlx::query("DELETE FROM image_variants WHERE image_id = ?")
+        .bind(image_id)
+        .execute(&state.pool)
+        .await?;
+
+    if !remove_files {
+        return Ok(());
+    }
+    for variant in variants {
+        if let Err(e) = state.blobs.delete(&variant.filename).await {
+            tracing::warn!("Failed to delete image variant {}: {}", variant.filename, e);
+        }
+    }
+
+    Ok(())
+}
+
+// Render a simplified SVG preview of an Excalidraw scene: basic shapes, lines and text in their
+// own colours, images and frames as placeholders. Returns None for an empty scene.
+pub fn render_scene_svg(scene: &Value) -> Option<String> {
+    let scene = normalize_content(scene);
+    let elements: Vec<&Value> = scene
+        .get("elements")
+        .and_then(Value::as_array)
+        .map(|elements| {
+            elements
+                .iter()
+                .filter(|e| !e.get("isDeleted").and_then(Value::as_bool).unwrap_or(false))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let bounds = elements
+        .iter()
+        .filter_map(|e| element_bounds(e))
+        .reduce(Bounds::union)?;
+
+    let min_x = bounds.min_x - SCENE_PADDING;
+    let min_y = bounds.min_y - SCENE_PADDING;
+    let width = bounds.max_x - bounds.min_x + 2.0 * SCENE_PADDING;
+    let height = bounds.max_y - bounds.min_y + 2.0 * SCENE_PADDING;
+    let scale = SCENE_THUMBNAIL_SIZE / width.max(height);
+
+    let background = scene
+        .pointer("/appState/viewBackgroundColor")
+        .and_then(Value::as_str)
+        .unwrap_or("#ffffff");
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="{} {} {} {}">"#,
+        (width * scale).round().max(1.0),
+        (height * scale).round().max(1.0),
+        fmt(min_x),
+        fmt(min_y),
+        fmt(width),
+        fmt(height),
+    );
+    let _ = write!(
+        svg,
+        r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/>"#,
+        fmt(min_x),
+        fmt(min_y),
+        fmt(width),
+        fmt(height),
+        escape(background),
+    );
+
+    for element in elements {
+        render_element(&mut svg, element);
+    }
+
+    svg.push_str("</svg>");
+    Some(svg)
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl Bounds {
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            min_x: self.min_x.min(other.min_x),
+            min_y: self.min_y.min(other.min_y),
+            max_x: self.max_x.max(other.max_x),
+            max_y: self.max_y.max(other.max_y),
+        }
+    }
+}
+
+fn number(element: &Value, key: &str) -> Option<f64> {
+    element.get(key).and_then(Value::as_f64).filter(|n| n.is_finite())
+}
+
+fn points(element: &Value) -> Vec<(f64, f64)> {
+    let Some(points) = element.get("points").and_then(Value::as_array) else {
+        return Vec::new();
+    };
+    let step = points.len().div_ceil(MAX_PATH_POINTS).max(1);
+    let last = points.len().saturating_sub(1);
+
+    points
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| i % step == 0 || *i == last)
+        .filter_map(|(_, p)| Some((p.get(0)?.as_f64()?, p.get(1)?.as_f64()?)))
+        .filter(|(x, y)| x.is_finite() && y.is_finite())
+        .collect()
+}
+
+fn element_bounds(element: &Value) -> Option<Bounds> {
+    let x = number(element, "x")?;
+    let y = number(element, "y")?;
+    let width = number(element, "width").unwrap_or(0.0);
+    let height = number(element, "height").unwrap_or(0.0);
+
+    let mut bounds = Bounds { min_x: x, min_y: y, max_x: x + width, max_y: y + height };
+    for (px, py) in points(element) {
+        bounds = bounds.union(Bounds { min_x: x + px, min_y: y + py, max_x: x + px, max_y: y + py });
+    }
+
+    // 旋转后的图形用外接圆近似
+    if number(element, "angle").unwrap_or(0.0) != 0.0 {
+        let (cx, cy) = ((bounds.min_x + bounds.max_x) / 2.0, (bounds.min_y + bounds.max_y) / 2.0);
+        let r = (bounds.max_x - bounds.min_x).hypot(bounds.max_y - bounds.min_y) / 2.0;
+        bounds = Bounds { min_x: cx - r, min_y: cy - r, max_x: cx + r, max_y: cy + r };
+    }
+
+    Some(bounds)
+}
+
+fn render_element(svg: &mut String, element: &Value) {
+    let kind = element.get("type").and_then(Value::as_str).unwrap_or_default();
+    let (Some(x), Some(y)) = (number(element, "x"), number(element, "y")) else {
+        return;
+    };
+    let width = number(element, "width").unwrap_or(0.0);
+    let height = number(element, "height").unwrap_or(0.0);
+
+    let color = |key: &str, default: &'static str| {
+        let value = element.get(key).and_then(Value::as_str).unwrap_or(default);
+        if value == "transparent" { "none".to_string() } else { escape(value) }
+    };
+    let stroke = color("strokeColor", "#1e1e1e");
+    let fill = color("backgroundColor", "transparent");
+    let stroke_width = number(element, "strokeWidth").unwrap_or(1.0);
+    let opacity = number(element, "opacity").unwrap_or(100.0).clamp(0.0, 100.0) / 100.0;
+
+    let mut style = format!(r#" opacity="{}""#, fmt(opacity));
+    let angle = number(element, "angle").unwrap_or(0.0);
+    if angle != 0.0 {
+        let _ = write!(
+            style,
+            r#" transform="rotate({} {} {})""#,
+            fmt(angle.to_degrees()),
+            fmt(x + width / 2.0),
+            fmt(y + height / 2.0)
+        );
+    }
+    let paint = |fill: &str| format!(r#" stroke="{}" stroke-width="{}" fill="{}""#, stroke, fmt(stroke_width), fill);
+
+    let _ = match kind {
+        "rectangle" => {
+            let radius = if element.get("roundness").is_some_and(|r| !r.is_null()) {
+                (width.min(height) * 0.25).min(32.0)
+            } else {
+                0.0
+            };
+            write!(
+                svg,
+                r#"<rect x="{}" y="{}" width="{}" height="{}" rx="{}"{}{}/>"#,
+                fmt(x), fmt(y), fmt(width), fmt(height), fmt(radius), paint(&fill), style
+            )
+        }
+        "ellipse" => write!(
+            svg,
+            r#"<ellipse cx="{}" cy="{}" rx="{}" ry="{}"{}{}/>"#,
+            fmt(x + width / 2.0), fmt(y + height / 2.0), fmt(width / 2.0), fmt(height / 2.0), paint(&fill), style
+        ),
+        "diamond" => write!(
+            svg,
+            r#"<polygon points="{},{} {},{} {},{} {},{}"{}{}/>"#,
+            fmt(x + width / 2.0), fmt(y),
+            fmt(x + width), fmt(y + height / 2.0),
+            fmt(x + width / 2.0), fmt(y + height),
+            fmt(x), fmt(y + height / 2.0),
+            paint(&fill), style
+        ),
+        "line" | "arrow" | "freedraw" => {
+            let points = points(element);
+            // 只有闭合的 line 才有填充
+            let closed = points.len() > 2 && points.first() == points.last();
+            let fill = if kind == "line" && closed { fill.as_str() } else { "none" };
+            let points: Vec<String> = points
+                .into_iter()
+                .map(|(px, py)| format!("{},{}", fmt(x + px), fmt(y + py)))
+                .collect();
+            write!(
+                svg,
+                r#"<polyline points="{}" stroke-linecap="round" stroke-linejoin="round"{}{}/>"#,
+                points.join(" "), paint(fill), style
+            )
+        }
+        "text" => render_text(svg, element, x, y, width, &stroke, &style),
+        "image" => write!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="#e9ecef" stroke="#ced4da"{}/>"##,
+            fmt(x), fmt(y), fmt(width), fmt(height), style
+        ),
+        "frame" | "magicframe" => write!(
+            svg,
+            r##"<rect x="{}" y="{}" width="{}" height="{}" fill="none" stroke="#bbbbbb"{}/>"##,
+            fmt(x), fmt(y), fmt(width), fmt(height), style
+        ),
+        _ => Ok(()),
+    };
+}
+
+fn render_text(
+    svg: &mut String,
+    element: &Value,
+    x: f64,
+    y: f64,
+    width: f64,
+    color: &str,
+    style: &str,
+) -> std::fmt::Result {
+    let text = element.get("text").and_then(Value::as_str).unwrap_or_default();
+    let font_size = number(element, "fontSize").unwrap_or(20.0);
+    let line_height = number(element, "lineHeight").unwrap_or(1.25);
+
+    let (anchor, anchor_x) = match element.get("textAlign").and_then(Value::as_str) {
+        Some("center") => ("middle", x + width / 2.0),
+        Some("right") => ("end", x + width),
+        _ => ("start", x),
+    };
+
+    write!(
+        svg,
+        r#"<text font-family="sans-serif" font-size="{}" fill="{}" text-anchor="{}"{}>"#,
+        fmt(font_size), color, anchor, style
+    )?;
+    for (i, line) in text.lines().enumerate() {
+        let baseline = y + font_size * (0.9 + line_height * i as f64);
+        write!(svg, r#"<tspan x="{}" y="{}">{}</tspan>"#, fmt(anchor_x), fmt(baseline), escape(line))?;
+    }
+    svg.push_str("</text>");
+    Ok(())
+}
+
+fn fmt(n: f64) -> String {
+    let rounded = (n * 100.0).round() / 100.0;
+    format!("{}", rounded)
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::image_probe::probe;
+    use serde_json::json;
+
+    #[test]
+    fn renders_scene_preview() {
+        let scene = json!({
+            "elements": [
+                { "type": "rectangle", "x": 0, "y": 0, "width": 200, "height": 100, "backgroundColor": "#ffc9c9" },
+                { "type": "line", "x": 300, "y": 0, "width": 100, "height": 100, "backgroundColor": "#a5d8ff",
+                  "points": [[0, 0], [100, 0], [100, 100], [0, 0]] },
+                { "type": "arrow", "x": 0, "y": 150, "width": 400, "height": 0, "backgroundColor": "#b2f2bb",
+                  "points": [[0, 0], [400, 0]] },
+                { "type": "text", "x": 10, "y": 10, "width": 80, "height": 25, "text": "a < b" },
+                { "type": "ellipse", "x": 5000, "y": 5000, "width": 10, "height": 10, "isDeleted": true },
+            ],
+            "appState": { "viewBackgroundColor": "#fafafa" },
+        });
+        let svg = render_scene_svg(&scene).unwrap();
+
+        // 420 x 170 的场景按长边缩放到 256
+        let info = probe(svg.as_bytes()).unwrap();
+        assert_eq!((info.kind, info.width, info.height), (ImageKind::Svg, Some(256), Some(104)));
+        assert!(svg.contains(r##"fill="#fafafa""##));
+        assert!(svg.contains(r##"fill="#a5d8ff""##));
+        assert!(!svg.contains(r##"fill="#b2f2bb""##));
+        assert!(svg.contains("a &lt; b"));
+        assert!(!svg.contains("<ellipse"));
+
+        assert_eq!(render_scene_svg(&json!({ "elements": [] })), None);
+    }
+
+    #[test]
+    fn resizes_bitmaps_to_variants() {
+        let mut png = Cursor::new(Vec::new());
+        DynamicImage::new_rgba8(2000, 1000).write_to(&mut png, ImageFormat::Png).unwrap();
+        let variants = resize_variants(&ImageSource::Bytes(png.into_inner()), ImageKind::Png).unwrap();
+
+        let sizes: Vec<_> = variants
+            .iter()
+            .map(|v| {
+                let info = probe(&v.data).unwrap();
+                (v.variant, info.kind, info.width, info.height)
+            })
+            .collect();
+        assert_eq!(
+            sizes,
+            [
+                (ImageVariant::Thumb, ImageKind::Png, Some(256), Some(128)),
+                (ImageVariant::Medium, ImageKind::Png, Some(1024), Some(512)),
+            ]
+        );
+
+        // 比所有变体都小的图片不需要缩放
+        let mut small = Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(100, 50).write_to(&mut small, ImageFormat::Jpeg).unwrap();
+        assert!(resize_variants(&ImageSource::Bytes(small.into_inner()), ImageKind::Jpeg).unwrap().is_empty());
+    }
+
+    #[test]
+    fn resizes_bitmaps_from_files() {
+        let path = std::env::temp_dir().join(format!("venus-test-{}.jpg", uuid::Uuid::new_v4()));
+        DynamicImage::new_rgb8(400, 800).save_with_format(&path, ImageFormat::Jpeg).unwrap();
+        let source = ImageSource::File(path.clone());
+
+        let kind = source.kind().unwrap();
+        let variants = resize_variants(&source, kind.unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(kind, Some(ImageKind::Jpeg));
+        let sizes: Vec<_> = variants.unwrap().iter().map(|v| (v.variant, v.kind, v.width, v.height)).collect();
+        assert_eq!(sizes, [(ImageVariant::Thumb, ImageKind::Jpeg, 128, 256)]);
+    }
+}
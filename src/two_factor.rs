@@ -0,0 +1,385 @@
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use rand::seq::SliceRandom;
+use sqlx::{FromRow, SqlitePool};
+use uuid::Uuid;
+
+use crate::{
+    auth::{random_token, token_hash},
+    models::{TotpSetup, User},
+    totp,
+};
+
+// How long the step between password and code may take, and how many codes may be tried in it
+pub const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+const MAX_CHALLENGE_ATTEMPTS: i64 = 5;
+const CHALLENGE_TOKEN_LEN: usize = 32;
+
+// Wrong codes in a row, over any number of challenges, after which codes are refused for a while
+const MAX_FAILED_CODES: i64 = 10;
+const LOCKOUT_SECS: i64 = 15 * 60;
+
+// Accept codes from the previous and next 30 second window to allow for clock drift
+const TOTP_SKEW_STEPS: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+// No 0/o, 1/l/i, so codes can be read off paper
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+#[derive(Debug, FromRow)]
+struct TotpRow {
+    secret: String,
+    enabled_at: Option<DateTime<Utc>>,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeCheck {
+    Valid,
+    Wrong,
+    // Too many wrong codes; nothing is accepted until the lockout ends
+    LockedOut,
+}
+
+// Outcome of the second step of a login
+#[derive(Debug)]
+pub enum ChallengeOutcome {
+    SignedIn(User),
+    // Unknown, expired or exhausted challenge, or a wrong code
+    Refused,
+    LockedOut,
+}
+
+async fn totp_row(pool: &SqlitePool, uid: i64) -> Result<Option<TotpRow>, sqlx::Error> {
+    sqlx::query_as::<_, TotpRow>("SELECT secret, enabled_at, locked_until FROM user_totp WHERE uid = ?")
+        .bind(uid)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn is_enabled(pool: &SqlitePool, uid: i64) -> Result<bool, sqlx::Error> {
+    Ok(totp_row(pool, uid).await?.is_some_and(|row| row.enabled_at.is_some()))
+}
+
+pub async fn recovery_codes_left(pool: &SqlitePool, uid: i64) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar("SELECT COUNT(*) FROM recovery_codes WHERE uid = ? AND used_at IS NULL")
+        .bind(uid)
+        .fetch_one(pool)
+        .await
+}
+
+// Start enrolling an authenticator with a fresh secret. The second factor is not required until
+// `confirm_setup` has seen a first code from it. Returns None when 2FA is already enabled.
+pub async fn begin_setup(pool: &SqlitePool, user: &User, issuer: &str) -> Result<Option<TotpSetup>> {
+    if is_enabled(pool, user.id).await? {
+        return Ok(None);
+    }
+
+    let secret = totp::generate_secret();
+    sqlx::query(
+        r#"
+        INSERT INTO user_totp (uid, secret, created_at) VALUES (?, ?, ?)
+        ON CONFLICT(uid) DO UPDATE SET secret = excluded.secret, created_at = excluded.created_at, last_used_step = NULL
+        "#
+    )
+    .bind(user.id)
+    .bind(&secret)
+    .bind(Utc::now())
+    .execute(pool)
+    .await?;
+
+    Ok(Some(TotpSetup {
+        otpauth_uri: totp::otpauth_uri(&secret, issuer, &user.username),
+        secret,
+    }))
+}
+
+// Enable 2FA once the user proves their authenticator works. Returns the new recovery codes, or
+// None when there is no pending setup or the code is wrong.
+pub async fn confirm_setup(pool: &SqlitePool, uid: i64, code: &str) -> Result<Option<Vec<String>>> {
+    let Some(row) = totp_row(pool, uid).await? else {
+        return Ok(None);
+    };
+    if row.enabled_at.is_some() {
+        return Ok(None);
+    }
+    let Some(step) = totp::verify(&row.secret, code, Utc::now().timestamp(), TOTP_SKEW_STEPS) else {
+        return Ok(None);
+    };
+
+    sqlx::query("UPDATE user_totp SET enabled_at = ?, last_used_step = ? WHERE uid = ?")
+        .bind(Utc::now())
+        .bind(step)
+        .bind(uid)
+        .execute(pool)
+        .await?;
+
+    regenerate_recovery_codes(pool, uid).await.map(Some)
+}
+
+pub async fn disable(pool: &SqlitePool, uid: i64) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM user_totp WHERE uid = ?")
+        .bind(uid)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE uid = ?")
+        .bind(uid)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+// Replace all recovery codes of the user. The codes are only ever shown this once.
+pub async fn regenerate_recovery_codes(pool: &SqlitePool, uid: i64) -> Result<Vec<String>> {
+    let codes: Vec<String> = {
+        let mut rng = rand::thread_rng();
+        (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code: String = (0..RECOVERY_CODE_LEN)
+                    .map(|_| *RECOVERY_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                    .collect();
+                format!("{}-{}", &code[..RECOVERY_CODE_LEN / 2], &code[RECOVERY_CODE_LEN / 2..])
+            })
+            .collect()
+    };
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE uid = ?")
+        .bind(uid)
+        .execute(&mut *tx)
+        .await?;
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_codes (id, uid, code_hash) VALUES (?, ?, ?)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(uid)
+            .bind(token_hash(&normalize_recovery_code(code)))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(codes)
+}
+
+// Check a second factor: a code from the user's authenticator, or one of their recovery codes.
+// Either is used up by a successful check. Wrong codes count towards a lockout of the user.
+pub async fn check_code(pool: &SqlitePool, uid: i64, code: &str) -> Result<CodeCheck> {
+    let now = Utc::now();
+    let Some(row) = totp_row(pool, uid).await? else {
+        return Ok(CodeCheck::Wrong);
+    };
+    if row.enabled_at.is_none() {
+        return Ok(CodeCheck::Wrong);
+    }
+    if row.locked_until.is_some_and(|until| until > now) {
+        return Ok(CodeCheck::LockedOut);
+    }
+
+    if !accept_code(pool, uid, &row, code.trim(), now).await? {
+        record_failure(pool, uid, now).await?;
+        return Ok(CodeCheck::Wrong);
+    }
+    sqlx::query("UPDATE user_totp SET failed_attempts = 0, locked_until = NULL WHERE uid = ?")
+        .bind(uid)
+        .execute(pool)
+        .await?;
+    Ok(CodeCheck::Valid)
+}
+
+async fn accept_code(pool: &SqlitePool, uid: i64, row: &TotpRow, code: &str, now: DateTime<Utc>) -> Result<bool> {
+    if code.len() == 6 && code.bytes().all(|b| b.is_ascii_digit()) {
+        let Some(step) = totp::verify(&row.secret, code, now.timestamp(), TOTP_SKEW_STEPS) else {
+            return Ok(false);
+        };
+
+        // 同一个时间窗口的验证码只能使用一次
+        let result = sqlx::query(
+            "UPDATE user_totp SET last_used_step = ? WHERE uid = ? AND (last_used_step IS NULL OR last_used_step < ?)"
+        )
+        .bind(step)
+        .bind(uid)
+        .bind(step)
+        .execute(pool)
+        .await?;
+        return Ok(result.rows_affected() > 0);
+    }
+
+    let result = sqlx::query("UPDATE recovery_codes SET used_at = ? WHERE uid = ? AND code_hash = ? AND used_at IS NULL")
+        .bind(now)
+        .bind(uid)
+        .bind(token_hash(&normalize_recovery_code(code)))
+        .execute(pool)
+        .await?;
+    if result.rows_affected() > 0 {
+        tracing::info!("User {} used a recovery code", uid);
+    }
+    Ok(result.rows_affected() > 0)
+}
+
+// Count a wrong code; the one that reaches the limit starts the lockout and resets the count
+async fn record_failure(pool: &SqlitePool, uid: i64, now: DateTime<Utc>) -> Result<()> {
+    let locked: Option<bool> = sqlx::query_scalar(
+        r#"
+        UPDATE user_totp
+        SET locked_until = CASE WHEN failed_attempts + 1 >= ? THEN ? ELSE locked_until END,
+            failed_attempts = CASE WHEN failed_attempts + 1 >= ? THEN 0 ELSE failed_attempts + 1 END
+        WHERE uid = ?
+        RETURNING failed_attempts = 0
+        "#
+    )
+    .bind(MAX_FAILED_CODES)
+    .bind(now + Duration::seconds(LOCKOUT_SECS))
+    .bind(MAX_FAILED_CODES)
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    if locked == Some(true) {
+        tracing::warn!("Too many wrong two-factor codes for user {}; locked for {} seconds", uid, LOCKOUT_SECS);
+    }
+    Ok(())
+}
+
+// Remember that the user got the password right; the returned token stands in for it in the
+// second step of the login
+pub async fn create_challenge(pool: &SqlitePool, uid: i64) -> Result<String> {
+    let now = Utc::now();
+    let token = random_token(CHALLENGE_TOKEN_LEN);
+
+    sqlx::query("DELETE FROM login_challenges WHERE expires_at <= ?")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    sqlx::query("INSERT INTO login_challenges (id, uid, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(Uuid::new_v4().to_string())
+        .bind(uid)
+        .bind(token_hash(&token))
+        .bind(now)
+        .bind(now + Duration::seconds(CHALLENGE_TTL_SECS))
+        .execute(pool)
+        .await?;
+
+    Ok(token)
+}
+
+// Finish a two-step login. The user signs in when the challenge is valid and the code correct;
+// the challenge is then used up. Each challenge allows only a few wrong codes.
+pub async fn complete_challenge(pool: &SqlitePool, token: &str, code: &str) -> Result<ChallengeOutcome> {
+    let challenge: Option<(String, i64)> = sqlx::query_as(
+        r#"
+        UPDATE login_challenges SET attempts = attempts + 1
+        WHERE token_hash = ? AND expires_at > ? AND attempts < ?
+        RETURNING id, uid
+        "#
+    )
+    .bind(token_hash(token))
+    .bind(Utc::now())
+    .bind(MAX_CHALLENGE_ATTEMPTS)
+    .fetch_optional(pool)
+    .await?;
+    let Some((challenge_id, uid)) = challenge else {
+        return Ok(ChallengeOutcome::Refused);
+    };
+
+    match check_code(pool, uid, code).await? {
+        CodeCheck::Valid => {}
+        CodeCheck::Wrong => return Ok(ChallengeOutcome::Refused),
+        CodeCheck::LockedOut => return Ok(ChallengeOutcome::LockedOut),
+    }
+
+    // 并发请求中只有删除成功的一方可以登录
+    let deleted = sqlx::query("DELETE FROM login_challenges WHERE id = ?")
+        .bind(&challenge_id)
+        .execute(pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Ok(ChallengeOutcome::Refused);
+    }
+
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+    )
+    .bind(uid)
+    .fetch_optional(pool)
+    .await?;
+    Ok(user.map_or(ChallengeOutcome::Refused, ChallengeOutcome::SignedIn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_pool;
+
+    // 直接启用两步验证，用恢复码作为正确的验证码
+    async fn enabled_user(pool: &SqlitePool) -> (i64, Vec<String>) {
+        let now = Utc::now();
+        let uid: i64 = sqlx::query_scalar(
+            "INSERT INTO users (username, email, password_hash, created_at, updated_at) VALUES ('alice', 'alice@example.com', 'x', ?, ?) RETURNING id"
+        )
+        .bind(now)
+        .bind(now)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO user_totp (uid, secret, enabled_at) VALUES (?, ?, ?)")
+            .bind(uid)
+            .bind(totp::generate_secret())
+            .bind(now)
+            .execute(pool)
+            .await
+            .unwrap();
+        (uid, regenerate_recovery_codes(pool, uid).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_lock_out_until_expiry() {
+        let pool = test_pool().await;
+        let (uid, codes) = enabled_user(&pool).await;
+
+        // 正确的验证码清零计数
+        for _ in 1..MAX_FAILED_CODES {
+            assert_eq!(check_code(&pool, uid, "wrong-code").await.unwrap(), CodeCheck::Wrong);
+        }
+        assert_eq!(check_code(&pool, uid, &codes[0]).await.unwrap(), CodeCheck::Valid);
+
+        for _ in 0..MAX_FAILED_CODES {
+            assert_eq!(check_code(&pool, uid, "wrong-code").await.unwrap(), CodeCheck::Wrong);
+        }
+        assert_eq!(check_code(&pool, uid, &codes[1]).await.unwrap(), CodeCheck::LockedOut);
+
+        sqlx::query("UPDATE user_totp SET locked_until = ? WHERE uid = ?")
+            .bind(Utc::now() - Duration::seconds(1))
+            .bind(uid)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(check_code(&pool, uid, &codes[1]).await.unwrap(), CodeCheck::Valid);
+    }
+
+    #[tokio::test]
+    async fn new_challenges_do_not_reset_the_count() {
+        let pool = test_pool().await;
+        let (uid, codes) = enabled_user(&pool).await;
+
+        for _ in 0..MAX_FAILED_CODES / MAX_CHALLENGE_ATTEMPTS {
+            let token = create_challenge(&pool, uid).await.unwrap();
+            for _ in 0..MAX_CHALLENGE_ATTEMPTS {
+                let outcome = complete_challenge(&pool, &token, "wrong-code").await.unwrap();
+                assert!(matches!(outcome, ChallengeOutcome::Refused));
+            }
+        }
+
+        let token = create_challenge(&pool, uid).await.unwrap();
+        let outcome = complete_challenge(&pool, &token, &codes[0]).await.unwrap();
+        assert!(matches!(outcome, ChallengeOutcome::LockedOut));
+    }
+}
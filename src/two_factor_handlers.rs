@@ -0,0 +1,162 @@
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+use chrono::{Duration, Utc};
+use serde_json::json;
+
+use crate::{
+    auth::{authenticate, extract_uid_from_headers, verify_password},
+    models::{
+        DisableTwoFactorRequest, RecoveryCodes, TotpSetup, TwoFactorCodeRequest, TwoFactorStatus, User,
+    },
+    sessions::session_started_at,
+    state::AppState,
+    two_factor::{self, CodeCheck},
+};
+
+// Accounts without a password confirm turning two-factor off by having signed in with the identity
+// provider at most this long ago
+const REAUTH_MAX_AGE_SECS: i64 = 5 * 60;
+
+async fn load_user(state: &AppState, uid: i64) -> Result<User, StatusCode> {
+    sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+    )
+    .bind(uid)
+    .fetch_optional(&state.pool)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)
+}
+
+// 验证码错误返回 403，连续错误太多次后返回 429
+fn code_status(checked: CodeCheck) -> Result<(), StatusCode> {
+    match checked {
+        CodeCheck::Valid => Ok(()),
+        CodeCheck::Wrong => Err(StatusCode::FORBIDDEN),
+        CodeCheck::LockedOut => Err(StatusCode::TOO_MANY_REQUESTS),
+    }
+}
+
+pub async fn get_two_factor_status(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TwoFactorStatus>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    let user = load_user(&state, uid).await?;
+
+    let enabled = two_factor::is_enabled(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let recovery_codes_left = two_factor::recovery_codes_left(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(TwoFactorStatus {
+        enabled,
+        recovery_codes_left,
+        password_required: !user.password_hash.is_empty(),
+    }))
+}
+
+// 生成新的 TOTP 密钥；需要用第一个验证码确认后才会启用
+pub async fn setup_two_factor(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<TotpSetup>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+    let user = load_user(&state, uid).await?;
+
+    let setup = two_factor::begin_setup(&state.pool, &user, &state.config.auth.totp_issuer)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::CONFLICT)?; // 已经启用
+
+    Ok(Json(setup))
+}
+
+pub async fn enable_two_factor(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<TwoFactorCodeRequest>,
+) -> Result<Json<RecoveryCodes>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    // 验证码错误返回 400，401 会让前端误以为 token 过期
+    let recovery_codes = two_factor::confirm_setup(&state.pool, uid, &req.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    tracing::info!("User {} enabled two-factor authentication", uid);
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+// 关闭两步验证需要同时提供密码和验证码（或恢复码）。通过单点登录创建的账号没有密码，
+// 改为要求当前会话是刚在身份提供方重新登录的
+pub async fn disable_two_factor(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<DisableTwoFactorRequest>,
+) -> Result<StatusCode, Response> {
+    let claims = authenticate(&state, &headers).await.map_err(IntoResponse::into_response)?;
+    let uid = claims.uid;
+    let user = load_user(&state, uid).await.map_err(IntoResponse::into_response)?;
+
+    if user.password_hash.is_empty() {
+        // API 令牌和反向代理的身份没有对应的会话，只能从浏览器操作
+        let started_at = session_started_at(&state.pool, &claims.sid, uid)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        let recent = started_at.is_some_and(|t| Utc::now() - t <= Duration::seconds(REAUTH_MAX_AGE_SECS));
+        if !recent {
+            let body = Json(json!({"error": "reauthentication_required"}));
+            return Err((StatusCode::FORBIDDEN, body).into_response());
+        }
+    } else {
+        let password_valid = verify_password(&req.password, &user.password_hash)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+        if !password_valid {
+            return Err(StatusCode::FORBIDDEN.into_response());
+        }
+    }
+    let checked = two_factor::check_code(&state.pool, uid, &req.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+    code_status(checked).map_err(IntoResponse::into_response)?;
+
+    two_factor::disable(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
+
+    tracing::info!("User {} disabled two-factor authentication", uid);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// 重新生成恢复码，旧的恢复码全部失效
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<TwoFactorCodeRequest>,
+) -> Result<Json<RecoveryCodes>, StatusCode> {
+    let uid = extract_uid_from_headers(&state, &headers).await?;
+
+    let enabled = two_factor::is_enabled(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !enabled {
+        return Err(StatusCode::CONFLICT);
+    }
+    let checked = two_factor::check_code(&state.pool, uid, &req.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    code_status(checked)?;
+
+    let recovery_codes = two_factor::regenerate_recovery_codes(&state.pool, uid)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
@@ -0,0 +1,259 @@
+use anyhow::Result;
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use lettre::Address;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{
+    auth::{authenticate, random_token, token_hash},
+    config::UnverifiedAccess,
+    mailer::{format_duration, Email},
+    models::User,
+    state::AppState,
+};
+
+const VERIFY_TOKEN_LEN: usize = 32;
+const MAX_EMAIL_LEN: usize = 254;
+
+// The address in the form it is stored (lowercase), or None when it is not a valid email address
+pub fn normalize_email(email: &str) -> Option<String> {
+    let email = email.trim();
+    if email.len() > MAX_EMAIL_LEN {
+        return None;
+    }
+    let address: Address = email.parse().ok()?;
+    // 不接受 user@localhost 这类没有顶级域名的地址
+    if !address.domain().contains('.') {
+        return None;
+    }
+    Some(address.to_string().to_lowercase())
+}
+
+pub async fn is_verified(pool: &SqlitePool, uid: i64) -> Result<bool, sqlx::Error> {
+    let verified: Option<Option<DateTime<Utc>>> = sqlx::query_scalar("SELECT verified_at FROM users WHERE id = ?")
+        .bind(uid)
+        .fetch_optional(pool)
+        .await?;
+    Ok(matches!(verified, Some(Some(_))))
+}
+
+// Email the user a link that proves they own their address. Any earlier link stops working. The
+// email is sent in the background; failures are only logged.
+pub async fn send_verification(state: &AppState, user: &User) -> Result<()> {
+    let now = Utc::now();
+    let ttl = state.config.auth.email_verification_ttl_secs;
+    let token = random_token(VERIFY_TOKEN_LEN);
+
+    sqlx::query("DELETE FROM email_verifications WHERE uid = ?")
+        .bind(user.id)
+        .execute(&state.pool)
+        .await?;
+    sqlx::query("INSERT INTO email_verifications (id, uid, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(Uuid::new_v4().to_string())
+        .bind(user.id)
+        .bind(token_hash(&token))
+        .bind(now)
+        .bind(now + Duration::seconds(ttl))
+        .execute(&state.pool)
+        .await?;
+
+    let link = format!("{}/verify-email?token={}", state.config.server.public_url.trim_end_matches('/'), token);
+    let email = Email {
+        to: user.email.clone(),
+        subject: "Verify your Venus email address".to_string(),
+        body: format!(
+            "Hi {},\n\n\
+             Please confirm that this is your email address by opening this link\n\
+             within {}:\n\n\
+             {}\n\n\
+             If you didn't create a Venus account, ignore this email.\n",
+            user.username,
+            format_duration(ttl),
+            link,
+        ),
+    };
+    let mailer = state.mailer.clone();
+    let uid = user.id;
+    tokio::spawn(async move {
+        if let Err(e) = mailer.send(&email).await {
+            tracing::error!("Failed to send verification email to user {}: {:#}", uid, e);
+        }
+    });
+
+    Ok(())
+}
+
+// Resend the link to the unverified account registered with `email`. Like password resets, this
+// does nothing (and says nothing) for unknown or already verified addresses.
+pub async fn resend_verification(state: &AppState, email: &str) -> Result<()> {
+    let user = sqlx::query_as::<_, User>(
+        "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE email = ? COLLATE NOCASE AND verified_at IS NULL"
+    )
+    .bind(email.trim())
+    .fetch_optional(&state.pool)
+    .await?;
+
+    match user {
+        Some(user) => send_verification(state, &user).await,
+        None => Ok(()),
+    }
+}
+
+// Mark the address behind a verification link as verified. Returns false when the token is
+// unknown or expired.
+pub async fn verify_email(state: &AppState, token: &str) -> Result<bool> {
+    let now = Utc::now();
+    let mut tx = state.pool.begin().await?;
+
+    let uid: Option<i64> = sqlx::query_scalar(
+        "DELETE FROM email_verifications WHERE token_hash = ? AND expires_at > ? RETURNING uid"
+    )
+    .bind(token_hash(token))
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(uid) = uid else {
+        return Ok(false);
+    };
+
+    sqlx::query("UPDATE users SET verified_at = COALESCE(verified_at, ?), legacy_verified = 0 WHERE id = ?")
+        .bind(now)
+        .bind(uid)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(true)
+}
+
+// With `unverified_access = "read-only"`, reject every request that would change something when it
+// comes from an account whose email is not verified yet. Requests that fail to authenticate are
+// left to the handler.
+pub async fn restrict_unverified(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let read_only = state.config.auth.unverified_access == UnverifiedAccess::ReadOnly;
+    let method = request.method();
+    if !read_only || method == Method::GET || method == Method::HEAD || method == Method::OPTIONS {
+        return next.run(request).await;
+    }
+
+    if let Ok(claims) = authenticate(&state, request.headers()).await {
+        match is_verified(&state.pool, claims.uid).await {
+            Ok(true) => {}
+            Ok(false) => return StatusCode::FORBIDDEN.into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth_handlers::finish_login,
+        sessions::ClientInfo,
+        state::{test_state, test_user},
+    };
+    use axum::{body::Body, middleware, routing::get, Router};
+    use std::sync::Arc;
+    use tower::Service;
+
+    async fn state_with(unverified_access: UnverifiedAccess) -> AppState {
+        let state = test_state().await;
+        let mut config = (*state.config).clone();
+        config.auth.unverified_access = unverified_access;
+        AppState { config: Arc::new(config), ..state }
+    }
+
+    async fn add_verification(state: &AppState, uid: i64, token: &str, expires_at: DateTime<Utc>) {
+        sqlx::query("INSERT INTO email_verifications (id, uid, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(uid)
+            .bind(token_hash(token))
+            .bind(Utc::now())
+            .bind(expires_at)
+            .execute(&state.pool)
+            .await
+            .unwrap();
+    }
+
+    async fn user(state: &AppState, uid: i64) -> User {
+        sqlx::query_as::<_, User>(
+            "SELECT id, username, email, password_hash, verified_at, created_at, updated_at FROM users WHERE id = ?"
+        )
+        .bind(uid)
+        .fetch_one(&state.pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn token_works_once() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+        add_verification(&state, uid, "verify-token", Utc::now() + Duration::hours(1)).await;
+
+        assert!(!is_verified(&state.pool, uid).await.unwrap());
+        assert!(verify_email(&state, "verify-token").await.unwrap());
+        assert!(is_verified(&state.pool, uid).await.unwrap());
+        assert!(!verify_email(&state, "verify-token").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_unknown_or_replaced_token() {
+        let state = test_state().await;
+        let (uid, _) = test_user(&state, "alice").await;
+        add_verification(&state, uid, "expired-token", Utc::now() - Duration::seconds(1)).await;
+
+        assert!(!verify_email(&state, "expired-token").await.unwrap());
+        assert!(!verify_email(&state, "unknown-token").await.unwrap());
+
+        // 重新发送后旧链接失效
+        add_verification(&state, uid, "old-token", Utc::now() + Duration::hours(1)).await;
+        send_verification(&state, &user(&state, uid).await).await.unwrap();
+        assert!(!verify_email(&state, "old-token").await.unwrap());
+        assert!(!is_verified(&state.pool, uid).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn unverified_accounts_cannot_sign_in_when_access_is_none() {
+        let state = state_with(UnverifiedAccess::None).await;
+        let (uid, _) = test_user(&state, "alice").await;
+        let client = ClientInfo { user_agent: None, ip: None };
+
+        let refused = finish_login(&state, user(&state, uid).await, &client).await;
+        assert_eq!(refused.unwrap_err(), StatusCode::FORBIDDEN);
+
+        add_verification(&state, uid, "verify-token", Utc::now() + Duration::hours(1)).await;
+        assert!(verify_email(&state, "verify-token").await.unwrap());
+        assert!(finish_login(&state, user(&state, uid).await, &client).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn read_only_accounts_cannot_change_anything() {
+        let state = state_with(UnverifiedAccess::ReadOnly).await;
+        let (uid, headers) = test_user(&state, "alice").await;
+        let app = Router::new()
+            .route("/", get(|| async { "read" }).post(|| async { "written" }))
+            .route_layer(middleware::from_fn_with_state(state.clone(), restrict_unverified));
+
+        let call = |method: Method| {
+            let mut request = Request::builder().method(method).uri("/").body(Body::empty()).unwrap();
+            *request.headers_mut() = headers.clone();
+            app.clone().call(request)
+        };
+        assert_eq!(call(Method::GET).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(call(Method::POST).await.unwrap().status(), StatusCode::FORBIDDEN);
+
+        add_verification(&state, uid, "verify-token", Utc::now() + Duration::hours(1)).await;
+        assert!(verify_email(&state, "verify-token").await.unwrap());
+        assert_eq!(call(Method::POST).await.unwrap().status(), StatusCode::OK);
+    }
+}